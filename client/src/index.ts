@@ -3,7 +3,12 @@ import type { paths } from "./api/v1";
 
 // A very simple example showing how to call the server using code-generated type-safe code.
 const main = async () => {
-    const client = createClient<paths>({ baseUrl: "http://127.0.0.1:8080" });
+    // In a browser, call the API through a same-origin proxy, as it sends no CORS headers, and
+    // include the Kratos session cookie.
+    const client = createClient<paths>({
+        baseUrl: "http://127.0.0.1:8080",
+        credentials: "include",
+    });
 
     const {
         data: data0, // only present if 2XX response
@@ -0,0 +1,150 @@
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::{DeriveInput, Fields, GenericArgument, PathArguments, Type};
+
+use super::EntityField;
+
+/// How a field is converted to and from its column, from `#[entity(json)]` or
+/// `#[entity(enum_text)]`. Other fields are stored with their own `ToSql` and `FromSql`.
+#[derive(Clone, Copy)]
+pub(crate) enum Conversion {
+    Json,
+    EnumText,
+}
+
+impl EntityField {
+    /// The adapter storing the field, if it is converted.
+    fn adapter(&self) -> Option<TokenStream> {
+        self.conversion.map(|conversion| match conversion {
+            Conversion::Json => quote!(crate::database::Json),
+            Conversion::EnumText => quote!(crate::database::EnumText),
+        })
+    }
+
+    /// A reference to the value to bind for the field of `owner`, e.g. `&self.field`.
+    pub(crate) fn param(&self, owner: TokenStream) -> TokenStream {
+        let ident = &self.ident;
+        match self.adapter() {
+            Some(adapter) => quote!(&#adapter(&#owner.#ident)),
+            None => quote!(&#owner.#ident),
+        }
+    }
+
+    /// The `ToSqlOutput` to bind for the field of `owner`, borrowing only from `owner`.
+    pub(crate) fn output(&self, owner: TokenStream) -> TokenStream {
+        let ident = &self.ident;
+        match self.adapter() {
+            Some(adapter) => quote!(#adapter::to_sql_output(&#owner.#ident)?),
+            None => quote!(rusqlite::ToSql::to_sql(&#owner.#ident)?),
+        }
+    }
+
+    /// Reads the field from column `index` of `row`.
+    pub(crate) fn read(&self, index: usize) -> TokenStream {
+        let ty = &self.ty;
+        match self.adapter() {
+            Some(adapter) => quote!(row.get::<_, #adapter<#ty>>(#index)?.0),
+            None => quote!(row.get(#index)?),
+        }
+    }
+
+    /// The `affinity`, `nullable` and `check` of the field's `Column`.
+    pub(crate) fn column_type(&self) -> (TokenStream, TokenStream, TokenStream) {
+        let ty = &self.ty;
+        let nullable = option_inner(ty).is_some();
+        match self.conversion {
+            None => (
+                quote!(<#ty as crate::database::ColumnType>::AFFINITY),
+                quote!(<#ty as crate::database::ColumnType>::NULLABLE),
+                quote!(None),
+            ),
+            Some(Conversion::Json) => (
+                quote!(crate::database::Affinity::Text),
+                quote!(#nullable),
+                quote!(Some(crate::database::Check::Json)),
+            ),
+            Some(Conversion::EnumText) => {
+                let variants = option_inner(ty).unwrap_or(ty);
+                (
+                    quote!(crate::database::Affinity::Text),
+                    quote!(#nullable),
+                    quote!(Some(crate::database::Check::OneOf(
+                        crate::database::enum_values::<#variants>
+                    ))),
+                )
+            }
+        }
+    }
+
+    /// The expression of the field's `QueryColumn` constant.
+    pub(crate) fn query_column(&self) -> TokenStream {
+        let column = &self.column;
+        let ty = &self.ty;
+        match self.adapter() {
+            Some(adapter) => quote!(crate::database::QueryColumn::with(#column, |value: #ty| {
+                Box::new(#adapter(value))
+            })),
+            None => quote!(crate::database::QueryColumn::new(#column)),
+        }
+    }
+}
+
+/// The `T` of a type written as `Option<T>`.
+pub(crate) fn option_inner(ty: &Type) -> Option<&Type> {
+    let Type::Path(path) = ty else {
+        return None;
+    };
+    let segment = path.path.segments.last()?;
+    if segment.ident != "Option" {
+        return None;
+    }
+    let PathArguments::AngleBracketed(arguments) = &segment.arguments else {
+        return None;
+    };
+    match arguments.args.first()? {
+        GenericArgument::Type(inner) => Some(inner),
+        _ => None,
+    }
+}
+
+/// Implements `ToSql`, `FromSql` and `ColumnType` for a `#[entity(transparent)]` newtype with
+/// those of the type it wraps, so that it can be used as a field, including as a key.
+pub(crate) fn transparent(input: &DeriveInput) -> syn::Result<TokenStream> {
+    let error = || {
+        syn::Error::new(
+            input.ident.span(),
+            "`#[entity(transparent)]` needs a struct with exactly one unnamed field",
+        )
+    };
+    let syn::Data::Struct(ref data) = input.data else {
+        return Err(error());
+    };
+    let Fields::Unnamed(ref fields) = data.fields else {
+        return Err(error());
+    };
+    if fields.unnamed.len() != 1 {
+        return Err(error());
+    }
+
+    let name = &input.ident;
+    let inner = &fields.unnamed[0].ty;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote!(
+        impl #impl_generics rusqlite::ToSql for #name #ty_generics #where_clause {
+            fn to_sql(&self) -> rusqlite::Result<rusqlite::types::ToSqlOutput<'_>> {
+                rusqlite::ToSql::to_sql(&self.0)
+            }
+        }
+
+        impl #impl_generics rusqlite::types::FromSql for #name #ty_generics #where_clause {
+            fn column_result(value: rusqlite::types::ValueRef<'_>) -> rusqlite::types::FromSqlResult<Self> {
+                <#inner as rusqlite::types::FromSql>::column_result(value).map(Self)
+            }
+        }
+
+        impl #impl_generics crate::database::ColumnType for #name #ty_generics #where_clause {
+            const AFFINITY: crate::database::Affinity = <#inner as crate::database::ColumnType>::AFFINITY;
+            const NULLABLE: bool = <#inner as crate::database::ColumnType>::NULLABLE;
+        }
+    ))
+}
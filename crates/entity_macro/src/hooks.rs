@@ -0,0 +1,129 @@
+use proc_macro2::TokenStream;
+use quote::{format_ident, quote};
+
+use super::Entity;
+
+/// The calls of the generated writes to the `EntityHooks` of a struct with `#[entity(hooks)]`,
+/// which are all empty for any other struct.
+#[derive(Default)]
+pub(crate) struct Hooks {
+    pub(crate) validate: TokenStream,
+    pub(crate) before_insert: TokenStream,
+    pub(crate) after_insert: TokenStream,
+    pub(crate) before_insert_many: TokenStream,
+    pub(crate) after_insert_many: TokenStream,
+    pub(crate) before_update: TokenStream,
+    pub(crate) after_update: TokenStream,
+    pub(crate) before_upsert: TokenStream,
+    pub(crate) after_upsert: TokenStream,
+    /// The implementation of `EntityHooks` with the defaults for a struct without
+    /// `#[entity(hooks)]`.
+    pub(crate) hooks_impl: TokenStream,
+}
+
+impl Hooks {
+    pub(crate) fn new(entity: &Entity) -> Self {
+        let name = &entity.name;
+        if !entity.hooks {
+            return Self {
+                hooks_impl: quote!(impl crate::database::EntityHooks for #name {}),
+                ..Self::default()
+            };
+        }
+
+        let name_string = name.to_string();
+        let primary_key = entity.primary_key();
+        let primary_key_ident = &primary_key.ident;
+        // Whether the row exists. An upsert of a soft deleted row fails before calling any
+        // callbacks, rather than calling those of an update that then changes nothing.
+        let exists = match entity.deleted_at() {
+            Some(deleted_at) => {
+                let live_statement = format!(
+                    "SELECT {} IS NULL FROM {} WHERE {} = ?;",
+                    deleted_at.column, entity.table, primary_key.column
+                );
+                quote!(
+                    let exists = match rusqlite::OptionalExtension::optional(
+                        txn.prepare_cached(#live_statement)?
+                            .query_row([&self.#primary_key_ident], |row| row.get::<_, bool>(0)),
+                    )? {
+                        Some(true) => true,
+                        Some(false) => {
+                            return Err(crate::database::EntityError::not_found(
+                                #name_string,
+                                &self.#primary_key_ident,
+                            ))
+                        }
+                        None => false,
+                    };
+                )
+            }
+            None => {
+                let exists_statement = format!(
+                    "SELECT EXISTS (SELECT 1 FROM {} WHERE {} = ?);",
+                    entity.table, primary_key.column
+                );
+                quote!(
+                    let exists: bool = txn
+                        .prepare_cached(#exists_statement)?
+                        .query_row([&self.#primary_key_ident], |row| row.get(0))?;
+                )
+            }
+        };
+        let validate = |entity: TokenStream| {
+            quote!(
+                crate::database::EntityHooks::validate(#entity)
+                    .map_err(|errors| crate::database::EntityError::invalid(#name_string, errors))?;
+            )
+        };
+        let call = |method: &str, entity: TokenStream| {
+            let method = format_ident!("{method}");
+            quote!(crate::database::EntityHooks::#method(#entity, txn)?;)
+        };
+
+        let before_insert = call("before_insert", quote!(&*self));
+        let after_insert = call("after_insert", quote!(&*self));
+        let before_update = call("before_update", quote!(&*self));
+        let after_update = call("after_update", quote!(&*self));
+        let validate_entity = validate(quote!(entity));
+        let before_insert_entity = call("before_insert", quote!(entity));
+        let after_insert_entity = call("after_insert", quote!(entity));
+
+        Self {
+            validate: validate(quote!(&*self)),
+            before_insert_many: quote!(
+                for entity in entities.iter() {
+                    #validate_entity
+                    #before_insert_entity
+                }
+            ),
+            after_insert_many: quote!(
+                for entity in entities.iter() {
+                    #after_insert_entity
+                }
+            ),
+            // An upsert calls the callbacks of an update if the entity exists, otherwise those
+            // of an insert, and fails if it has been soft deleted.
+            before_upsert: quote!(
+                #exists
+                if exists {
+                    #before_update
+                } else {
+                    #before_insert
+                }
+            ),
+            after_upsert: quote!(
+                if exists {
+                    #after_update
+                } else {
+                    #after_insert
+                }
+            ),
+            before_insert,
+            after_insert,
+            before_update,
+            after_update,
+            hooks_impl: TokenStream::new(),
+        }
+    }
+}
@@ -1,128 +1,941 @@
 use convert_case::{Case, Casing};
 use proc_macro::TokenStream;
-use quote::quote;
-use syn::{parse_macro_input, DeriveInput, Fields, Ident};
+use quote::{format_ident, quote};
+use syn::{
+    parse_macro_input, Attribute, DeriveInput, Field, Fields, Ident, LitStr, Type, Visibility,
+};
+
+mod convert;
+mod hooks;
+mod relation;
+mod tracking;
+
+use convert::Conversion;
+use hooks::Hooks;
+use relation::Relation;
+
+/// The options of a struct deriving `ToSql`, parsed from its `#[entity(...)]` attributes.
+struct Entity {
+    name: Ident,
+    vis: Visibility,
+    table: String,
+    fields: Vec<EntityField>,
+    relations: Vec<Relation>,
+    timestamps: bool,
+    soft_delete: bool,
+    hooks: bool,
+}
+
+/// The options of a field, parsed from its `#[entity(...)]` attributes.
+struct EntityField {
+    ident: Ident,
+    ty: Type,
+    column: String,
+    skip: bool,
+    primary_key: bool,
+    unique: bool,
+    index: bool,
+    version: bool,
+    conversion: Option<Conversion>,
+}
+
+impl Entity {
+    fn parse(input: &DeriveInput) -> syn::Result<Self> {
+        let syn::Data::Struct(ref data) = input.data else {
+            return Err(syn::Error::new(
+                input.ident.span(),
+                "Only structs with named fields can derive `ToSql`",
+            ));
+        };
+        let Fields::Named(ref fields) = data.fields else {
+            return Err(syn::Error::new(
+                input.ident.span(),
+                "Only structs with named fields can derive `ToSql`",
+            ));
+        };
+
+        // Without `#[entity(table = "...")]` the table is the pluralised snake case name.
+        let mut table = input
+            .ident
+            .to_string()
+            .to_case(Case::Snake)
+            .replace("_", "s_");
+        table.push('s');
+        let mut relations = Vec::<Relation>::new();
+        let mut timestamps = false;
+        let mut soft_delete = false;
+        let mut hooks = false;
+        parse_attributes(&input.attrs, |meta| {
+            if meta.path.is_ident("table") {
+                table = meta.value()?.parse::<LitStr>()?.value();
+            } else if meta.path.is_ident("belongs_to") {
+                relations.push(Relation::new(meta.value()?.parse()?));
+            } else if meta.path.is_ident("fk") || meta.path.is_ident("has_many") {
+                let value = meta.value()?.parse::<LitStr>()?;
+                let Some(relation) = relations.last_mut() else {
+                    return Err(meta.error("expected `belongs_to = ...` first"));
+                };
+                if meta.path.is_ident("fk") {
+                    relation.fk = Some(value);
+                } else {
+                    relation.has_many = Some(value.value());
+                }
+            } else if meta.path.is_ident("timestamps") {
+                timestamps = true;
+            } else if meta.path.is_ident("soft_delete") {
+                soft_delete = true;
+            } else if meta.path.is_ident("hooks") {
+                hooks = true;
+            } else {
+                return Err(meta.error(
+                    "expected `table = \"...\"`, `belongs_to = ...`, `fk = \"...\"`, `has_many = \"...\"`, `timestamps`, `soft_delete` or `hooks`",
+                ));
+            }
+            Ok(())
+        })?;
+
+        let mut fields = fields
+            .named
+            .iter()
+            .map(EntityField::parse)
+            .collect::<syn::Result<Vec<_>>>()?;
+
+        // Without `#[entity(primary_key)]` the primary key is the field named `id`.
+        match fields.iter().filter(|field| field.primary_key).count() {
+            0 => {
+                match fields.iter_mut().find(|field| field.ident == "id") {
+                    Some(field) => field.primary_key = true,
+                    None => return Err(syn::Error::new(
+                        input.ident.span(),
+                        "no field named `id`, mark the primary key with `#[entity(primary_key)]`",
+                    )),
+                }
+            }
+            1 => {}
+            _ => {
+                return Err(syn::Error::new(
+                    input.ident.span(),
+                    "only one field can be `#[entity(primary_key)]`",
+                ))
+            }
+        }
+
+        let entity = Self {
+            name: input.ident.clone(),
+            vis: input.vis.clone(),
+            table,
+            fields,
+            relations,
+            timestamps,
+            soft_delete,
+            hooks,
+        };
+        for relation in &entity.relations {
+            relation.fk_field(&entity)?;
+        }
+        entity.check_tracking()?;
+        Ok(entity)
+    }
+
+    /// The fields stored in the table, in field order.
+    fn columns(&self) -> impl Iterator<Item = &EntityField> {
+        self.fields.iter().filter(|field| !field.skip)
+    }
+
+    fn primary_key(&self) -> &EntityField {
+        self.fields.iter().find(|field| field.primary_key).unwrap()
+    }
+}
+
+impl EntityField {
+    fn parse(field: &Field) -> syn::Result<Self> {
+        let ident = field.ident.clone().unwrap();
+        let mut column = ident.to_string();
+        let mut skip = false;
+        let mut primary_key = false;
+        let mut unique = false;
+        let mut index = false;
+        let mut version = false;
+        let mut conversion = None;
+        parse_attributes(&field.attrs, |meta| {
+            if meta.path.is_ident("column") {
+                column = meta.value()?.parse::<LitStr>()?.value();
+            } else if meta.path.is_ident("skip") {
+                skip = true;
+            } else if meta.path.is_ident("primary_key") {
+                primary_key = true;
+            } else if meta.path.is_ident("unique") {
+                unique = true;
+            } else if meta.path.is_ident("index") {
+                index = true;
+            } else if meta.path.is_ident("version") {
+                version = true;
+            } else if meta.path.is_ident("json") || meta.path.is_ident("enum_text") {
+                if conversion.is_some() {
+                    return Err(meta.error("only one of `json` and `enum_text` can be given"));
+                }
+                conversion = Some(match meta.path.is_ident("json") {
+                    true => Conversion::Json,
+                    false => Conversion::EnumText,
+                });
+            } else {
+                return Err(meta.error(
+                    "expected `column = \"...\"`, `skip`, `primary_key`, `unique`, `index`, `version`, `json` or `enum_text`",
+                ));
+            }
+            Ok(())
+        })?;
+
+        if skip && (primary_key || unique || index) {
+            return Err(syn::Error::new(
+                ident.span(),
+                "a field that is `#[entity(skip)]` is not stored and cannot be a key or indexed",
+            ));
+        }
+        if version && (skip || primary_key) {
+            return Err(syn::Error::new(
+                ident.span(),
+                "the `#[entity(version)]` field must be stored and cannot be the primary key",
+            ));
+        }
+        if conversion.is_some() && (skip || primary_key || version) {
+            return Err(syn::Error::new(
+                ident.span(),
+                "a `#[entity(json)]` or `#[entity(enum_text)]` field must be stored and cannot be \
+                 a key or the version, use a `#[entity(transparent)]` newtype for keys",
+            ));
+        }
+
+        Ok(Self {
+            ident,
+            ty: field.ty.clone(),
+            column,
+            skip,
+            primary_key,
+            unique,
+            index,
+            version,
+            conversion,
+        })
+    }
+}
+
+/// Call `parse` for each option in the `#[entity(...)]` attributes.
+fn parse_attributes(
+    attrs: &[Attribute],
+    mut parse: impl FnMut(syn::meta::ParseNestedMeta) -> syn::Result<()>,
+) -> syn::Result<()> {
+    for attr in attrs.iter().filter(|attr| attr.path().is_ident("entity")) {
+        attr.parse_nested_meta(&mut parse)?;
+    }
+    Ok(())
+}
 
 /// This derives the `FromRow` trait for structs
-/// Requires that the query is in field order, as it just uses row indices
-#[proc_macro_derive(ToSql)]
+///
+/// The generated queries name their columns explicitly, so the order of the columns in the table
+/// does not need to match the order of the fields. The derive is configured with:
+///
+/// - `#[entity(table = "...")]` on the struct to name the table, which otherwise is the
+///   pluralised snake case name of the struct.
+/// - `#[entity(column = "...")]` on a field to name its column, which otherwise is the field
+///   name.
+/// - `#[entity(skip)]` on a field that is not stored. It is set to its `Default` when read.
+/// - `#[entity(primary_key)]` on the primary key field, which otherwise is the field named `id`.
+/// - `#[entity(unique)]` or `#[entity(index)]` on a field whose column has a unique or plain
+///   index. Like the primary key and foreign keys these only describe the table for the
+///   migration generator, the table itself is created by the migrations.
+/// - `#[entity(belongs_to = Parent, fk = "...", has_many = "...")]` on the struct for each
+///   foreign key. This generates an accessor for the parent on the struct, named after the
+///   parent, and an accessor for the children on the parent, named `has_many`. The foreign key
+///   field otherwise is `parent_id` and the children accessor is the pluralised snake case name
+///   of the struct.
+/// - `#[entity(timestamps)]` on the struct to set the `created_at` and `updated_at` fields, which
+///   must be `DateTime<Utc>`, when it is created, inserted and updated. `insert`, `update` and
+///   `upsert` then take the struct mutably, and `update` and `upsert` read back the stored
+///   `created_at` rather than overwriting it. `insert_many` keeps the timestamps of the structs,
+///   so that imported records keep theirs.
+/// - `#[entity(soft_delete)]` on the struct to make `delete` and `delete_many` set the
+///   `deleted_at` field, which must be `Option<DateTime<Utc>>`, instead of deleting the row. The
+///   generated retrievals and relations skip deleted rows, as do queries unless they are built
+///   `with_deleted()`, and `update` and `upsert` fail with `EntityError::NotFound` on them.
+///   `restore` clears `deleted_at` and `purge` deletes the row.
+/// - `#[entity(version)]` on an integer field for optimistic concurrency. `new` sets it to 1, and
+///   `update` and `upsert` only change the row while it is still at the version of the struct,
+///   incrementing the version in both, and otherwise fail with `EntityError::VersionConflict`.
+///   Soft deleting and restoring increment it too.
+/// - `#[entity(json)]` on a field stored as JSON text, for any type implementing `Serialize` and
+///   `Deserialize` such as a struct, a `Vec` or a `serde_json::Value`.
+/// - `#[entity(enum_text)]` on a field whose type is an enum of unit variants, or an `Option` of
+///   one, stored as the name serde gives the variant. The enum must derive `JsonSchema`, which
+///   lists the variants for the `CHECK` constraint of the migration generator, so the column
+///   holds the same values as the API.
+/// - `#[entity(transparent)]` on a tuple struct with one field, rather than on a field, to store
+///   the newtype as the type it wraps. Such a newtype can then be any field, including a key.
+/// - `#[entity(hooks)]` on the struct to have `insert`, `insert_many`, `update` and `upsert`
+///   call its implementation of `EntityHooks`, to validate it and run callbacks before and after
+///   it is written, but not deletes. Without it the derive implements `EntityHooks` with the
+///   defaults.
+///
+/// Alongside the struct it generates `{Struct}Column`, with a typed constant for each stored
+/// field named after the field in Pascal case, to build queries with `{Struct}::query()`, e.g.
+/// `User::query().filter(UserColumn::Id.eq(id)).limit(10).page(txn)`.
+#[proc_macro_derive(ToSql, attributes(entity))]
 pub fn derive_to_sql(input: TokenStream) -> TokenStream {
     // Parse it as a proc macro
     let input = parse_macro_input!(input as DeriveInput);
 
-    if let syn::Data::Struct(ref data) = input.data {
-        if let Fields::Named(ref fields) = data.fields {
-            let name = input.ident.clone();
-
-            let mut name_snake = input
-                .ident
-                .to_string()
-                .to_case(Case::Snake)
-                .replace("_", "s_");
-            name_snake.push('s');
-            let name_snake = Ident::new(&name_snake, name.span());
-
-            // An iterator for `{field}`
-            let field_names = fields
-                .named
+    // A tuple struct is a `#[entity(transparent)]` newtype rather than an entity.
+    if let syn::Data::Struct(syn::DataStruct {
+        fields: Fields::Unnamed(_),
+        ..
+    }) = input.data
+    {
+        let mut transparent = false;
+        let parsed = parse_attributes(&input.attrs, |meta| {
+            if meta.path.is_ident("transparent") {
+                transparent = true;
+                Ok(())
+            } else {
+                Err(meta.error("expected `transparent`"))
+            }
+        })
+        .and_then(|()| match transparent {
+            true => convert::transparent(&input),
+            false => Err(syn::Error::new(
+                input.ident.span(),
+                "a tuple struct deriving `ToSql` needs `#[entity(transparent)]`",
+            )),
+        });
+        return match parsed {
+            Ok(expanded) => expanded.into(),
+            Err(err) => err.to_compile_error().into(),
+        };
+    }
+
+    match Entity::parse(&input) {
+        Ok(entity) => expand(&entity),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+fn expand(entity: &Entity) -> TokenStream {
+    let name = &entity.name;
+    let table = &entity.table;
+
+    let column_names = entity
+        .columns()
+        .map(|field| field.column.as_str())
+        .collect::<Vec<_>>();
+    let columns = column_names.join(",");
+    let placeholders = "?,".repeat(column_names.len());
+    let placeholders = placeholders.trim_end_matches(",");
+
+    let primary_key = entity.primary_key();
+    let primary_key_column = &primary_key.column;
+    let primary_key_type = &primary_key.ty;
+
+    // An iterator for `{field}: {type}` of the stored fields that are not set by the generated
+    // methods
+    let field_name_type = entity
+        .columns()
+        .filter(|field| !entity.is_tracking(field))
+        .map(|field| {
+            let name = &field.ident;
+            let ty = &field.ty;
+            quote!(#name: #ty)
+        });
+
+    // An iterator for `{field}`, `{field}: Default::default()` or the initial value of a tracked
+    // field of every field
+    let field_new = entity.fields.iter().map(|field| {
+        let name = &field.ident;
+        if field.skip {
+            quote!(#name: Default::default())
+        } else if field.version {
+            quote!(#name: 1)
+        } else if entity.is_tracking(field) && field.ident == "deleted_at" {
+            quote!(#name: None)
+        } else if entity.is_tracking(field) {
+            quote!(#name: now)
+        } else {
+            quote!(#name)
+        }
+    });
+
+    // An iterator for `&self.{field}`, or its converted value, of the stored fields
+    let field_self_name = entity.columns().map(|field| field.param(quote!(self)));
+
+    // An iterator for the `Column` of each stored field
+    let field_columns = entity.columns().map(|field| {
+        let column = &field.column;
+        let (affinity, nullable, check) = field.column_type();
+        let primary_key = field.primary_key;
+        let unique = field.unique;
+        let index = field.index;
+        let references = match entity
+            .relations
+            .iter()
+            .find(|relation| relation.fk_field(entity).unwrap().ident == field.ident)
+        {
+            Some(relation) => relation.reference(),
+            None => quote!(None),
+        };
+        quote!(crate::database::Column {
+            name: #column,
+            affinity: #affinity,
+            nullable: #nullable,
+            primary_key: #primary_key,
+            unique: #unique,
+            index: #index,
+            references: #references,
+            check: #check,
+        })
+    });
+
+    // An iterator for `{field}: row.get(0usize)?` in the order of the selected columns, or
+    // `{field}: Default::default()` for skipped fields
+    let mut index = 0usize;
+    let field_gets = entity
+        .fields
+        .iter()
+        .map(|field| {
+            let name = &field.ident;
+            if field.skip {
+                quote!(#name: Default::default())
+            } else {
+                index += 1;
+                let read = field.read(index - 1);
+                quote!(#name: #read)
+            }
+        })
+        .collect::<Vec<_>>();
+
+    // The columns an update sets, which are all but the primary key, `created_at` and the
+    // version
+    let created_at = entity.timestamps().map(|(created_at, _)| created_at);
+    let version = entity.version();
+    let updated_fields = entity
+        .columns()
+        .filter(|field| {
+            !field.primary_key
+                && !field.version
+                && created_at.is_none_or(|created_at| field.ident != created_at.ident)
+        })
+        .collect::<Vec<_>>();
+    let mut assignments = updated_fields
+        .iter()
+        .map(|field| format!("{} = ?", field.column))
+        .collect::<Vec<_>>();
+    let mut excluded = updated_fields
+        .iter()
+        .map(|field| format!("{0} = excluded.{0}", field.column))
+        .collect::<Vec<_>>();
+    // With `#[entity(version)]` an update increments the version, but only of the row at the
+    // version of the entity.
+    let mut upsert_conditions = Vec::new();
+    let and_version = match version {
+        Some(version) => {
+            let column = &version.column;
+            assignments.push(format!("{column} = {column} + 1"));
+            excluded.push(format!("{column} = {column} + 1"));
+            upsert_conditions.push(format!("{column} = excluded.{column}"));
+            format!(" AND {column} = ?")
+        }
+        None => String::new(),
+    };
+    // The parameters of the update, the columns it sets, the primary key and then the version
+    let update_params = updated_fields
+        .iter()
+        .chain([&primary_key])
+        .chain(version.as_ref())
+        .map(|field| field.param(quote!(self)))
+        .collect::<Vec<_>>();
+
+    // With `#[entity(timestamps)]` the update and upsert return the stored `created_at`, and
+    // with `#[entity(version)]` the stored version.
+    let returned = created_at.into_iter().chain(version).collect::<Vec<_>>();
+    let returning = match returned.is_empty() {
+        true => String::new(),
+        false => format!(
+            " RETURNING {}",
+            returned
                 .iter()
-                .map(|field| field.ident.clone().unwrap().to_string())
-                .collect::<Vec<_>>();
-
-            // An iterator for `{field}`
-            let field_name = fields.named.iter().map(|field| {
-                let name = &field.ident;
-                quote!(#name)
-            });
-
-            // An iterator for `{field}: {type}`
-            let field_name_type = fields.named.iter().map(|field| {
-                let name = &field.ident;
-                let ty = &field.ty;
-                quote!(#name: #ty)
-            });
-
-            // An iterator for `&self.{field}`
-            let field_self_name = fields.named.iter().map(|field| {
-                let name = &field.ident;
-                quote!(&self.#name)
-            });
-
-            // An iterator for `{field}: row.get(0usize)?`
-            let field_gets = fields.named.iter().enumerate().map(|(i, field)| {
-                let name = &field.ident;
-                quote!(#name: row.get(#i)?)
-            });
-
-            let new_docstring = format!("Creates a new `{name}` instance.");
-
-            let upsert_docstring = format!("Inserts or updates a `{name}` in the database.");
-            let upsert_statement = format!(
-                "INSERT OR REPLACE INTO {name_snake} ({}) VALUES ({});",
-                field_names.join(","),
-                "?,".repeat(field_names.len()).trim_end_matches(",")
-            );
-
-            let retrieve_docstring = format!("Retrieves a `{name}` by its identifier.");
-            let retrieve_many_docstring =
-                format!("Retrieves `{name}` records by their identifiers.");
-            let retrieve_many_statement = format!("SELECT * FROM {name_snake} WHERE id IN ({{}});");
-            let retrieve_all_docstring = format!("Retrieves all `{name}` records.");
-            let retrieve_all_statement = format!("SELECT * FROM {name_snake};");
-
-            return TokenStream::from(quote!(
-                impl #name {
-                    #[doc = #new_docstring]
-                    pub fn new(#(#field_name_type),*) -> Self {
-                        Self { #(#field_name),* }
-                    }
+                .map(|field| field.column.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    };
+    let live = entity.live();
+    let and_live = live
+        .as_ref()
+        .map(|live| format!(" AND {live}"))
+        .unwrap_or_default();
+    let where_live = live
+        .as_ref()
+        .map(|live| format!(" WHERE {live}"))
+        .unwrap_or_default();
+    // Like an update, an upsert leaves a soft deleted row alone rather than restoring it.
+    upsert_conditions.extend(live.clone());
+    let upsert_where = match upsert_conditions.is_empty() {
+        true => String::new(),
+        false => format!(" WHERE {}", upsert_conditions.join(" AND ")),
+    };
 
-                    #[doc = #upsert_docstring]
-                    pub fn upsert(&self, txn: &Transaction) -> Result<&Self> {
-                        let mut stmt = txn.prepare_cached(#upsert_statement)?;
-                        stmt.execute(params![#(#field_self_name),*])?;
-                        Ok(self)
-                    }
+    let schema_docstring =
+        format!("The table `{name}` is stored in and its columns in field order.");
+    let name_string = name.to_string();
 
-                    #[doc = #retrieve_docstring]
-                    pub fn retrieve(txn: &Transaction, id: &Uuid) -> Result<Option<Self>> {
-                        Ok(Self::retrieve_many(txn, &[*id])?.pop())
-                    }
+    let new_docstring = format!("Creates a new `{name}` instance.");
 
-                    #[doc = #retrieve_many_docstring]
-                    pub fn retrieve_many(txn: &Transaction, ids: &[Uuid]) -> Result<Vec<Self>> {
-                        let mut stmt = txn.prepare_cached(&format!(
-                            #retrieve_many_statement,
-                            "?,".repeat(ids.len()).trim_end_matches(",")
-                        ))?;
-                        let mapped = stmt.query_map(params_from_iter(ids), |row| row.try_into())?;
-                        Ok(mapped.collect::<rusqlite::Result<Vec<_>>>()?)
-                    }
+    let insert_docstring = format!(
+        "Inserts a new `{name}` into the database, failing with `EntityError::Conflict` if it \
+         already exists."
+    );
+    let insert_statement = format!("INSERT INTO {table} ({columns}) VALUES ({placeholders});");
+    let insert_many_docstring = format!(
+        "Inserts `{name}` records into the database with multi-row inserts, as many rows per \
+         statement as the parameter limit allows, returning the number inserted. Fails with \
+         `EntityError::Conflict` if any already exists."
+    );
+    let insert_many_statement = format!("INSERT INTO {table} ({columns}) VALUES {{}};");
+    let insert_many_row = format!("({placeholders})");
+    let column_count = column_names.len();
 
-                    #[doc = #retrieve_all_docstring]
-                    pub fn retrieve_all(txn: &Transaction) -> Result<Vec<Self>> {
-                        let mut stmt = txn.prepare_cached(#retrieve_all_statement)?;
-                        let mapped = stmt.query_map([], |row| row.try_into())?;
-                        Ok(mapped.collect::<rusqlite::Result<Vec<_>>>()?)
-                    }
-                }
+    let update_docstring = match version {
+        Some(version) => format!(
+            "Updates an existing `{name}` in the database and increments its `{}`, failing with \
+             `EntityError::NotFound` if it does not exist or `EntityError::VersionConflict` if it \
+             has been updated since.",
+            version.ident
+        ),
+        None => format!(
+            "Updates an existing `{name}` in the database, failing with `EntityError::NotFound` \
+             if it does not exist."
+        ),
+    };
+    // Without any other columns there is nothing to set, but the row must still exist.
+    let update_statement = if assignments.is_empty() {
+        format!(
+            "UPDATE {table} SET {primary_key_column} = {primary_key_column} \
+             WHERE {primary_key_column} = ?{and_live}{returning};"
+        )
+    } else {
+        format!(
+            "UPDATE {table} SET {} \
+             WHERE {primary_key_column} = ?{and_version}{and_live}{returning};",
+            assignments.join(", ")
+        )
+    };
+
+    let upsert_docstring = match version {
+        Some(version) => format!(
+            "Inserts a `{name}` into the database, or updates it in place and increments its \
+             `{}` if it already exists, failing with `EntityError::VersionConflict` if it has \
+             been updated since.",
+            version.ident
+        ),
+        None => format!(
+            "Inserts a `{name}` into the database, or updates it in place if it already exists."
+        ),
+    };
+    let upsert_docstring = match entity.soft_delete {
+        true => format!(
+            "{upsert_docstring} Fails with `EntityError::NotFound` if it has been soft deleted."
+        ),
+        false => upsert_docstring,
+    };
+    let upsert_statement = if excluded.is_empty() {
+        format!(
+            "INSERT INTO {table} ({columns}) VALUES ({placeholders}) \
+             ON CONFLICT ({primary_key_column}) DO NOTHING;"
+        )
+    } else {
+        format!(
+            "INSERT INTO {table} ({columns}) VALUES ({placeholders}) \
+             ON CONFLICT ({primary_key_column}) DO UPDATE SET {}{upsert_where}{returning};",
+            excluded.join(", ")
+        )
+    };
+
+    let delete_docstring = format!(
+        "Deletes a `{name}` by its identifier, failing with `EntityError::NotFound` if it does \
+         not exist."
+    );
+    let delete_statement = format!("DELETE FROM {table} WHERE {primary_key_column} = ?;");
+    let delete_many_docstring =
+        format!("Deletes `{name}` records by their identifiers, returning the number deleted.");
+    let delete_many_statement =
+        format!("DELETE FROM {table} WHERE {primary_key_column} IN ({{}});");
+
+    let exists_docstring = format!("Returns whether a `{name}` with the identifier exists.");
+    let exists_statement =
+        format!("SELECT EXISTS (SELECT 1 FROM {table} WHERE {primary_key_column} = ?{and_live});");
 
-                impl TryFrom<&rusqlite::Row<'_>> for #name {
-                    type Error = rusqlite::Error;
+    let retrieve_docstring = format!("Retrieves a `{name}` by its identifier.");
+    let retrieve_many_docstring = format!("Retrieves `{name}` records by their identifiers.");
+    let retrieve_many_statement =
+        format!("SELECT {columns} FROM {table} WHERE {primary_key_column} IN ({{}}){and_live};");
+    let retrieve_all_docstring =
+        format!("Retrieves all `{name}` records, see `stream_all` and `query` for large tables.");
+    let retrieve_all_statement = format!("SELECT {columns} FROM {table}{where_live};");
+    let stream_all_docstring = format!(
+        "Calls `f` with each `{name}` record in turn, reading one row at a time, and stops at the \
+         first error."
+    );
 
-                    fn try_from(row: &rusqlite::Row<'_>) -> Result<Self, Self::Error> {
-                        Ok(Self { #(#field_gets),* })
+    let field_self_name = field_self_name.collect::<Vec<_>>();
+    let primary_key_ident = &primary_key.ident;
+
+    // With `#[entity(version)]` the update and upsert take the entity mutably to set its version.
+    let update_self = if entity.timestamps || version.is_some() {
+        quote!(&mut self)
+    } else {
+        quote!(&self)
+    };
+
+    // With `#[entity(timestamps)]` the writes take the entity mutably to set its timestamps.
+    // `insert_many` keeps the timestamps of the structs, as set by `new` or imported.
+    let (new_now, write_self, stamp_insert, stamp_update) = match entity.timestamps() {
+        Some((created_at, updated_at)) => {
+            let (created_at, updated_at) = (&created_at.ident, &updated_at.ident);
+            (
+                quote!(let now = chrono::Utc::now();),
+                quote!(&mut self),
+                quote!(
+                    let now = chrono::Utc::now();
+                    self.#created_at = now;
+                    self.#updated_at = now;
+                ),
+                quote!(self.#updated_at = chrono::Utc::now();),
+            )
+        }
+        None => (quote!(), quote!(&self), quote!(), quote!()),
+    };
+
+    // The update and upsert either count the rows or read back the returned columns. A
+    // versioned update or upsert that changes no row found the row at another version.
+    let not_updated = match version {
+        Some(version) => {
+            let version = &version.ident;
+            quote!(
+                if Self::exists(txn, &self.#primary_key_ident)? {
+                    crate::database::EntityError::version_conflict(#name_string, &self.#primary_key_ident, &self.#version)
+                } else {
+                    crate::database::EntityError::not_found(#name_string, &self.#primary_key_ident)
+                }
+            )
+        }
+        None => {
+            quote!(crate::database::EntityError::not_found(#name_string, &self.#primary_key_ident))
+        }
+    };
+    let (update_execute, upsert_execute) = if returned.is_empty() {
+        (
+            quote!(
+                let updated = txn
+                    .prepare_cached(#update_statement)
+                    .and_then(|mut stmt| stmt.execute(params![#(#update_params),*]))
+                    .map_err(|err| crate::database::EntityError::from_sqlite(#name_string, err))?;
+                if updated == 0 {
+                    return Err(#not_updated);
+                }
+            ),
+            match entity.soft_delete {
+                // A soft deleted row is left alone, so the upsert changes no row.
+                true => quote!(
+                    let upserted = txn
+                        .prepare_cached(#upsert_statement)
+                        .and_then(|mut stmt| stmt.execute(params![#(#field_self_name),*]))
+                        .map_err(|err| crate::database::EntityError::from_sqlite(#name_string, err))?;
+                    if upserted == 0 {
+                        return Err(#not_updated);
                     }
+                ),
+                false => quote!(
+                    txn.prepare_cached(#upsert_statement)
+                        .and_then(|mut stmt| stmt.execute(params![#(#field_self_name),*]))
+                        .map_err(|err| crate::database::EntityError::from_sqlite(#name_string, err))?;
+                ),
+            },
+        )
+    } else {
+        let returned_idents = returned
+            .iter()
+            .map(|field| &field.ident)
+            .collect::<Vec<_>>();
+        let returned_indexes = 0..returned.len();
+        let read_returned =
+            quote!(|row: &rusqlite::Row<'_>| Ok((#(row.get(#returned_indexes)?,)*)));
+        let returned_values = quote!((#(#returned_idents,)*));
+        (
+            quote!(
+                let #returned_values = match txn
+                    .prepare_cached(#update_statement)
+                    .and_then(|mut stmt| stmt.query_row(params![#(#update_params),*], #read_returned))
+                {
+                    Ok(returned) => returned,
+                    Err(rusqlite::Error::QueryReturnedNoRows) => return Err(#not_updated),
+                    Err(err) => return Err(crate::database::EntityError::from_sqlite(#name_string, err)),
+                };
+                #(self.#returned_idents = #returned_idents;)*
+            ),
+            quote!(
+                let #returned_values = match txn
+                    .prepare_cached(#upsert_statement)
+                    .and_then(|mut stmt| stmt.query_row(params![#(#field_self_name),*], #read_returned))
+                {
+                    Ok(returned) => returned,
+                    Err(rusqlite::Error::QueryReturnedNoRows) => return Err(#not_updated),
+                    Err(err) => return Err(crate::database::EntityError::from_sqlite(#name_string, err)),
+                };
+                #(self.#returned_idents = #returned_idents;)*
+            ),
+        )
+    };
+
+    // With `#[entity(hooks)]` the writes call the `EntityHooks` of the struct.
+    let Hooks {
+        validate,
+        before_insert,
+        after_insert,
+        before_insert_many,
+        after_insert_many,
+        before_update,
+        after_update,
+        before_upsert,
+        after_upsert,
+        hooks_impl,
+    } = Hooks::new(entity);
+
+    // With `#[entity(soft_delete)]` deleting sets `deleted_at` instead.
+    let deletes = if entity.soft_delete {
+        tracking::soft_delete(entity)
+    } else {
+        quote!(
+            #[doc = #delete_docstring]
+            pub fn delete(txn: &WriteTxn, id: &#primary_key_type) -> std::result::Result<(), crate::database::EntityError> {
+                let deleted = txn
+                    .prepare_cached(#delete_statement)
+                    .and_then(|mut stmt| stmt.execute([id]))
+                    .map_err(|err| crate::database::EntityError::from_sqlite(#name_string, err))?;
+                match deleted {
+                    0 => Err(crate::database::EntityError::not_found(#name_string, id)),
+                    _ => Ok(()),
                 }
-            ));
+            }
+
+            #[doc = #delete_many_docstring]
+            pub fn delete_many(txn: &WriteTxn, ids: &[#primary_key_type]) -> std::result::Result<usize, crate::database::EntityError> {
+                let mut deleted = 0;
+                for chunk in ids.chunks(crate::database::MAX_PARAMETERS) {
+                    deleted += txn
+                        .prepare_cached(&format!(
+                            #delete_many_statement,
+                            "?,".repeat(chunk.len()).trim_end_matches(",")
+                        ))
+                        .and_then(|mut stmt| stmt.execute(params_from_iter(chunk)))
+                        .map_err(|err| crate::database::EntityError::from_sqlite(#name_string, err))?;
+                }
+                Ok(deleted)
+            }
+        )
+    };
+    let deleted_at = match entity.deleted_at() {
+        Some(deleted_at) => {
+            let column = &deleted_at.column;
+            quote!(Some(#column))
         }
-    }
+        None => quote!(None),
+    };
+    let field_outputs = entity.columns().map(|field| field.output(quote!(entity)));
 
-    TokenStream::from(
-        syn::Error::new(
-            input.ident.span(),
-            "Only structs with named fields can derive `ToSql`",
+    // The `{Struct}Column` constants for each stored field
+    let column_struct = format_ident!("{name}Column");
+    let column_constants = entity.columns().map(|field| {
+        let constant = format_ident!("{}", field.ident.to_string().to_case(Case::Pascal));
+        let column = &field.column;
+        let ty = &field.ty;
+        let query_column = field.query_column();
+        let docstring = format!("The `{column}` column of `{name}`.");
+        quote!(
+            #[doc = #docstring]
+            pub const #constant: crate::database::QueryColumn<#name, #ty> = #query_column;
         )
-        .to_compile_error(),
-    )
+    });
+    let vis = &entity.vis;
+    let column_struct_docstring =
+        format!("The columns of `{name}`, to filter and order its queries.");
+    let query_docstring = format!("Starts a query on the `{name}` records.");
+
+    let relations = entity
+        .relations
+        .iter()
+        .map(|relation| relation.expand(entity, &columns));
+
+    TokenStream::from(quote!(
+        impl #name {
+            #[doc = #schema_docstring]
+            pub const SCHEMA: crate::database::EntitySchema = crate::database::EntitySchema {
+                entity: #name_string,
+                table: #table,
+                columns: &[#(#field_columns),*],
+            };
+
+            #[doc = #new_docstring]
+            pub fn new(#(#field_name_type),*) -> Self {
+                #new_now
+                Self { #(#field_new),* }
+            }
+
+            #[doc = #insert_docstring]
+            pub fn insert(#write_self, txn: &WriteTxn) -> std::result::Result<&Self, crate::database::EntityError> {
+                #validate
+                #stamp_insert
+                #before_insert
+                txn.prepare_cached(#insert_statement)
+                    .and_then(|mut stmt| stmt.execute(params![#(#field_self_name),*]))
+                    .map_err(|err| crate::database::EntityError::from_sqlite(#name_string, err))?;
+                #after_insert
+                Ok(self)
+            }
+
+            #[doc = #insert_many_docstring]
+            pub fn insert_many(txn: &WriteTxn, entities: &[Self]) -> std::result::Result<usize, crate::database::EntityError> {
+                #before_insert_many
+                let mut inserted = 0;
+                for chunk in entities.chunks(crate::database::MAX_PARAMETERS / #column_count) {
+                    inserted += txn
+                        .prepare_cached(&format!(
+                            #insert_many_statement,
+                            vec![#insert_many_row; chunk.len()].join(",")
+                        ))
+                        .and_then(|mut stmt| {
+                            let values = chunk
+                                .iter()
+                                .map(|entity| -> rusqlite::Result<[rusqlite::types::ToSqlOutput<'_>; #column_count]> {
+                                    Ok([#(#field_outputs),*])
+                                })
+                                .collect::<rusqlite::Result<Vec<_>>>()?;
+                            stmt.execute(params_from_iter(values.iter().flatten()))
+                        })
+                        .map_err(|err| crate::database::EntityError::from_sqlite(#name_string, err))?;
+                }
+                #after_insert_many
+                Ok(inserted)
+            }
+
+            #[doc = #update_docstring]
+            pub fn update(#update_self, txn: &WriteTxn) -> std::result::Result<&Self, crate::database::EntityError> {
+                #validate
+                #stamp_update
+                #before_update
+                #update_execute
+                #after_update
+                Ok(self)
+            }
+
+            #[doc = #upsert_docstring]
+            pub fn upsert(#update_self, txn: &WriteTxn) -> std::result::Result<&Self, crate::database::EntityError> {
+                #validate
+                #stamp_insert
+                #before_upsert
+                #upsert_execute
+                #after_upsert
+                Ok(self)
+            }
+
+            #deletes
+
+            #[doc = #exists_docstring]
+            pub fn exists(txn: &Connection, id: &#primary_key_type) -> std::result::Result<bool, crate::database::EntityError> {
+                let mut stmt = txn.prepare_cached(#exists_statement)?;
+                Ok(stmt.query_row([id], |row| row.get(0))?)
+            }
+
+            #[doc = #retrieve_docstring]
+            pub fn retrieve(txn: &Connection, id: &#primary_key_type) -> std::result::Result<Option<Self>, crate::database::EntityError> {
+                Ok(Self::retrieve_many(txn, std::slice::from_ref(id))?.pop())
+            }
+
+            #[doc = #retrieve_many_docstring]
+            pub fn retrieve_many(txn: &Connection, ids: &[#primary_key_type]) -> std::result::Result<Vec<Self>, crate::database::EntityError> {
+                let mut retrieved = Vec::new();
+                for chunk in ids.chunks(crate::database::MAX_PARAMETERS) {
+                    let mut stmt = txn.prepare_cached(&format!(
+                        #retrieve_many_statement,
+                        "?,".repeat(chunk.len()).trim_end_matches(",")
+                    ))?;
+                    let mapped = stmt.query_map(params_from_iter(chunk), |row| row.try_into())?;
+                    retrieved.extend(mapped.collect::<rusqlite::Result<Vec<_>>>()?);
+                }
+                Ok(retrieved)
+            }
+
+            #[doc = #query_docstring]
+            pub fn query() -> crate::database::Query<Self> {
+                crate::database::Query::new()
+            }
+
+            #[doc = #retrieve_all_docstring]
+            pub fn retrieve_all(txn: &Connection) -> std::result::Result<Vec<Self>, crate::database::EntityError> {
+                let mut stmt = txn.prepare_cached(#retrieve_all_statement)?;
+                let mapped = stmt.query_map([], |row| row.try_into())?;
+                Ok(mapped.collect::<rusqlite::Result<Vec<_>>>()?)
+            }
+
+            #[doc = #stream_all_docstring]
+            pub fn stream_all<E: From<crate::database::EntityError>>(
+                txn: &Connection,
+                mut f: impl FnMut(Self) -> std::result::Result<(), E>,
+            ) -> std::result::Result<(), E> {
+                let error = |err: rusqlite::Error| E::from(crate::database::EntityError::from(err));
+                let mut stmt = txn.prepare_cached(#retrieve_all_statement).map_err(error)?;
+                let mut rows = stmt.query([]).map_err(error)?;
+                while let Some(row) = rows.next().map_err(error)? {
+                    f(row.try_into().map_err(error)?)?;
+                }
+                Ok(())
+            }
+        }
+
+        impl TryFrom<&rusqlite::Row<'_>> for #name {
+            type Error = rusqlite::Error;
+
+            /// Reads a row selected with the columns in field order.
+            fn try_from(row: &rusqlite::Row<'_>) -> std::result::Result<Self, Self::Error> {
+                Ok(Self { #(#field_gets),* })
+            }
+        }
+
+        impl crate::database::Entity for #name {
+            type Key = #primary_key_type;
+
+            const SCHEMA: crate::database::EntitySchema = #name::SCHEMA;
+
+            const PRIMARY_KEY: &'static str = #primary_key_column;
+
+            const DELETED_AT: Option<&'static str> = #deleted_at;
+
+            fn key(&self) -> &Self::Key {
+                &self.#primary_key_ident
+            }
+
+            fn from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Self> {
+                row.try_into()
+            }
+        }
+
+        #[doc = #column_struct_docstring]
+        #vis struct #column_struct;
+
+        #[allow(non_upper_case_globals)]
+        impl #column_struct {
+            #(#column_constants)*
+        }
+
+        #hooks_impl
+
+        #(#relations)*
+    ))
 }
@@ -0,0 +1,202 @@
+use convert_case::{Case, Casing};
+use proc_macro2::TokenStream;
+use quote::{format_ident, quote};
+use syn::{LitStr, Path};
+
+use super::convert::option_inner;
+use super::{Entity, EntityField};
+
+/// A `#[entity(belongs_to = Parent, fk = "...", has_many = "...")]` foreign key.
+pub(crate) struct Relation {
+    parent: Path,
+    pub(crate) fk: Option<LitStr>,
+    pub(crate) has_many: Option<String>,
+}
+
+impl Relation {
+    pub(crate) fn new(parent: Path) -> Self {
+        Self {
+            parent,
+            fk: None,
+            has_many: None,
+        }
+    }
+
+    /// The snake case name of the parent, e.g. `user` for `User`.
+    fn parent_snake(&self) -> String {
+        let parent = &self.parent.segments.last().unwrap().ident;
+        parent.to_string().to_case(Case::Snake)
+    }
+
+    /// The field holding the foreign key, `fk` or otherwise `{parent}_id`.
+    pub(crate) fn fk_field<'e>(&self, entity: &'e Entity) -> syn::Result<&'e EntityField> {
+        let (name, span) = match &self.fk {
+            Some(fk) => (fk.value(), fk.span()),
+            None => (
+                format!("{}_id", self.parent_snake()),
+                self.parent.segments.last().unwrap().ident.span(),
+            ),
+        };
+        entity
+            .columns()
+            .find(|field| field.ident == name)
+            .ok_or_else(|| {
+                syn::Error::new(
+                    span,
+                    format!("no stored field `{name}` for the foreign key"),
+                )
+            })
+    }
+
+    /// The `Reference` of the foreign key column to the primary key of the parent.
+    pub(crate) fn reference(&self) -> TokenStream {
+        let parent = &self.parent;
+        quote!(Some(crate::database::Reference {
+            table: #parent::SCHEMA.table,
+            column: <#parent as crate::database::Entity>::PRIMARY_KEY,
+        }))
+    }
+
+    /// The accessors for the parent on the entity and for the children on the parent.
+    pub(crate) fn expand(&self, entity: &Entity, columns: &str) -> TokenStream {
+        let child = &entity.name;
+        let child_name = child.to_string();
+        let parent = &self.parent;
+        let parent_name = quote!(#parent).to_string();
+        let table = &entity.table;
+
+        let fk = self.fk_field(entity).unwrap();
+        let fk_ident = &fk.ident;
+        let fk_column = &fk.column;
+        let nullable = option_inner(&fk.ty).is_some();
+
+        let accessor = format_ident!("{}", self.parent_snake());
+        let accessor_for = format_ident!("{}_for", self.parent_snake());
+        let has_many = format_ident!(
+            "{}",
+            self.has_many
+                .clone()
+                .unwrap_or_else(|| format!("{}s", child_name.to_case(Case::Snake)))
+        );
+        let has_many_for = format_ident!("{has_many}_for");
+        let retrieve_by = format_ident!("retrieve_by_{fk_ident}");
+
+        let key = quote!(<#parent as crate::database::Entity>::Key);
+
+        // The parent accessor returns `None` for a nullable foreign key that is not set, and an
+        // error if the key is set but the parent does not exist.
+        let accessor_body = if nullable {
+            quote!(match &self.#fk_ident {
+                Some(key) => #parent::retrieve(txn, key)?
+                    .ok_or_else(|| crate::database::EntityError::not_found(#parent_name, key))
+                    .map(Some),
+                None => Ok(None),
+            })
+        } else {
+            quote!(#parent::retrieve(txn, &self.#fk_ident)?.ok_or_else(|| {
+                crate::database::EntityError::not_found(#parent_name, &self.#fk_ident)
+            }))
+        };
+        let accessor_type = if nullable {
+            quote!(Option<#parent>)
+        } else {
+            quote!(#parent)
+        };
+
+        // The keys of the children's parents, skipping unset nullable keys.
+        let child_keys = if nullable {
+            quote!(children.iter().filter_map(|child| child.#fk_ident.clone()))
+        } else {
+            quote!(children.iter().map(|child| child.#fk_ident.clone()))
+        };
+        let group = if nullable {
+            quote!(if let Some(key) = child.#fk_ident.clone() {
+                grouped.entry(key).or_default().push(child);
+            })
+        } else {
+            quote!(grouped.entry(child.#fk_ident.clone()).or_default().push(child);)
+        };
+
+        let accessor_docstring =
+            format!("Retrieves the `{parent_name}` this `{child_name}` belongs to.");
+        let accessor_for_docstring = format!(
+            "Retrieves the `{parent_name}` of each of `children` in batched queries, keyed by \
+             primary key."
+        );
+        let retrieve_by_docstring =
+            format!("Retrieves the `{child_name}` records whose `{fk_ident}` is any of `keys`.");
+        let and_live = entity
+            .live()
+            .map(|live| format!(" AND {live}"))
+            .unwrap_or_default();
+        let retrieve_by_statement =
+            format!("SELECT {columns} FROM {table} WHERE {fk_column} IN ({{}}){and_live};");
+        let has_many_docstring =
+            format!("Retrieves the `{child_name}` records that belong to this `{parent_name}`.");
+        let has_many_for_docstring = format!(
+            "Retrieves the `{child_name}` records of each of `parents` in batched queries, keyed \
+             by the primary key of the `{parent_name}`."
+        );
+
+        quote!(
+            impl #child {
+                #[doc = #accessor_docstring]
+                pub fn #accessor(&self, txn: &Connection) -> std::result::Result<#accessor_type, crate::database::EntityError> {
+                    #accessor_body
+                }
+
+                #[doc = #accessor_for_docstring]
+                pub fn #accessor_for(
+                    txn: &Connection,
+                    children: &[Self],
+                ) -> std::result::Result<std::collections::HashMap<#key, #parent>, crate::database::EntityError> {
+                    let keys = #child_keys
+                        .collect::<std::collections::HashSet<_>>()
+                        .into_iter()
+                        .collect::<Vec<_>>();
+                    Ok(#parent::retrieve_many(txn, &keys)?
+                        .into_iter()
+                        .map(|parent| (crate::database::Entity::key(&parent).clone(), parent))
+                        .collect())
+                }
+
+                #[doc = #retrieve_by_docstring]
+                pub fn #retrieve_by(txn: &Connection, keys: &[#key]) -> std::result::Result<Vec<Self>, crate::database::EntityError> {
+                    let mut retrieved = Vec::new();
+                    for chunk in keys.chunks(crate::database::MAX_PARAMETERS) {
+                        let mut stmt = txn.prepare_cached(&format!(
+                            #retrieve_by_statement,
+                            "?,".repeat(chunk.len()).trim_end_matches(",")
+                        ))?;
+                        let mapped = stmt.query_map(params_from_iter(chunk), |row| row.try_into())?;
+                        retrieved.extend(mapped.collect::<rusqlite::Result<Vec<_>>>()?);
+                    }
+                    Ok(retrieved)
+                }
+            }
+
+            impl #parent {
+                #[doc = #has_many_docstring]
+                pub fn #has_many(&self, txn: &Connection) -> std::result::Result<Vec<#child>, crate::database::EntityError> {
+                    #child::#retrieve_by(txn, std::slice::from_ref(crate::database::Entity::key(self)))
+                }
+
+                #[doc = #has_many_for_docstring]
+                pub fn #has_many_for(
+                    txn: &Connection,
+                    parents: &[Self],
+                ) -> std::result::Result<std::collections::HashMap<#key, Vec<#child>>, crate::database::EntityError> {
+                    let keys = parents
+                        .iter()
+                        .map(|parent| crate::database::Entity::key(parent).clone())
+                        .collect::<Vec<_>>();
+                    let mut grouped = std::collections::HashMap::<#key, Vec<#child>>::new();
+                    for child in #child::#retrieve_by(txn, &keys)? {
+                        #group
+                    }
+                    Ok(grouped)
+                }
+            }
+        )
+    }
+}
@@ -0,0 +1,190 @@
+use proc_macro2::{Span, TokenStream};
+use quote::quote;
+
+use super::{Entity, EntityField};
+
+impl Entity {
+    /// The stored field named `name`, which `check_tracking` has checked exists.
+    fn tracking_field(&self, name: &str) -> &EntityField {
+        self.columns().find(|field| field.ident == name).unwrap()
+    }
+
+    /// Fail unless the fields required by `#[entity(timestamps)]` and `#[entity(soft_delete)]`
+    /// are stored.
+    pub(crate) fn check_tracking(&self) -> syn::Result<()> {
+        let mut required = Vec::new();
+        if self.timestamps {
+            required.extend([("created_at", "timestamps"), ("updated_at", "timestamps")]);
+        }
+        if self.soft_delete {
+            required.push(("deleted_at", "soft_delete"));
+        }
+        for (name, option) in required {
+            if !self.columns().any(|field| field.ident == name) {
+                return Err(syn::Error::new(
+                    Span::call_site(),
+                    format!("`#[entity({option})]` needs a stored field named `{name}`"),
+                ));
+            }
+        }
+        if self.fields.iter().filter(|field| field.version).count() > 1 {
+            return Err(syn::Error::new(
+                Span::call_site(),
+                "only one field can be `#[entity(version)]`",
+            ));
+        }
+        Ok(())
+    }
+
+    /// The `created_at` and `updated_at` fields of `#[entity(timestamps)]`.
+    pub(crate) fn timestamps(&self) -> Option<(&EntityField, &EntityField)> {
+        self.timestamps.then(|| {
+            (
+                self.tracking_field("created_at"),
+                self.tracking_field("updated_at"),
+            )
+        })
+    }
+
+    /// The `deleted_at` field of `#[entity(soft_delete)]`.
+    pub(crate) fn deleted_at(&self) -> Option<&EntityField> {
+        self.soft_delete.then(|| self.tracking_field("deleted_at"))
+    }
+
+    /// The `#[entity(version)]` field.
+    pub(crate) fn version(&self) -> Option<&EntityField> {
+        self.fields.iter().find(|field| field.version)
+    }
+
+    /// The condition on the rows that have not been soft deleted, if the entity soft deletes.
+    pub(crate) fn live(&self) -> Option<String> {
+        self.deleted_at()
+            .map(|field| format!("{} IS NULL", field.column))
+    }
+
+    /// Whether the field is set by the generated methods rather than by `new`.
+    pub(crate) fn is_tracking(&self, field: &EntityField) -> bool {
+        (self.timestamps && (field.ident == "created_at" || field.ident == "updated_at"))
+            || (self.soft_delete && field.ident == "deleted_at")
+            || field.version
+    }
+}
+
+/// The `delete`, `delete_many`, `restore` and `purge` methods of `#[entity(soft_delete)]`, which
+/// set and clear `deleted_at` instead of deleting the row.
+pub(crate) fn soft_delete(entity: &Entity) -> TokenStream {
+    let name = &entity.name;
+    let name_string = name.to_string();
+    let table = &entity.table;
+    let primary_key = entity.primary_key();
+    let primary_key_column = &primary_key.column;
+    let primary_key_type = &primary_key.ty;
+    let deleted_at = &entity.deleted_at().unwrap().column;
+
+    // Deleting and restoring also counts as an update.
+    let (mut set_deleted, mut set_restored) = match entity.timestamps() {
+        Some((_, updated_at)) => (
+            format!("{deleted_at} = ?1, {} = ?1", updated_at.column),
+            format!("{deleted_at} = NULL, {} = ?1", updated_at.column),
+        ),
+        None => (format!("{deleted_at} = ?1"), format!("{deleted_at} = NULL")),
+    };
+    if let Some(version) = entity.version() {
+        let increment = format!(", {0} = {0} + 1", version.column);
+        set_deleted.push_str(&increment);
+        set_restored.push_str(&increment);
+    }
+    let (restore_key, restore_params) = if entity.timestamps {
+        ("?2", quote!(params![chrono::Utc::now(), id]))
+    } else {
+        ("?1", quote!(params![id]))
+    };
+
+    let delete_docstring = format!(
+        "Soft deletes a `{name}` by its identifier, setting `{deleted_at}`, failing with \
+         `EntityError::NotFound` if it does not exist or is already deleted."
+    );
+    let delete_statement = format!(
+        "UPDATE {table} SET {set_deleted} \
+         WHERE {primary_key_column} = ?2 AND {deleted_at} IS NULL;"
+    );
+    let delete_many_docstring = format!(
+        "Soft deletes `{name}` records by their identifiers, returning the number deleted."
+    );
+    let delete_many_statement = format!(
+        "UPDATE {table} SET {set_deleted} \
+         WHERE {primary_key_column} IN ({{}}) AND {deleted_at} IS NULL;"
+    );
+    let restore_docstring = format!(
+        "Restores a soft deleted `{name}`, failing with `EntityError::NotFound` if it does not \
+         exist or is not deleted."
+    );
+    let restore_statement = format!(
+        "UPDATE {table} SET {set_restored} \
+         WHERE {primary_key_column} = {restore_key} AND {deleted_at} IS NOT NULL;"
+    );
+    let purge_docstring = format!(
+        "Deletes a `{name}` from the database whether or not it is soft deleted, failing with \
+         `EntityError::NotFound` if it does not exist."
+    );
+    let purge_statement = format!("DELETE FROM {table} WHERE {primary_key_column} = ?;");
+
+    quote!(
+        #[doc = #delete_docstring]
+        pub fn delete(txn: &WriteTxn, id: &#primary_key_type) -> std::result::Result<(), crate::database::EntityError> {
+            let deleted = txn
+                .prepare_cached(#delete_statement)
+                .and_then(|mut stmt| stmt.execute(params![chrono::Utc::now(), id]))
+                .map_err(|err| crate::database::EntityError::from_sqlite(#name_string, err))?;
+            match deleted {
+                0 => Err(crate::database::EntityError::not_found(#name_string, id)),
+                _ => Ok(()),
+            }
+        }
+
+        #[doc = #delete_many_docstring]
+        pub fn delete_many(txn: &WriteTxn, ids: &[#primary_key_type]) -> std::result::Result<usize, crate::database::EntityError> {
+            let now = chrono::Utc::now();
+            let mut deleted = 0;
+            for chunk in ids.chunks(crate::database::MAX_PARAMETERS - 1) {
+                deleted += txn
+                    .prepare_cached(&format!(
+                        #delete_many_statement,
+                        "?,".repeat(chunk.len()).trim_end_matches(",")
+                    ))
+                    .and_then(|mut stmt| {
+                        stmt.execute(params_from_iter(
+                            std::iter::once(&now as &dyn rusqlite::ToSql)
+                                .chain(chunk.iter().map(|id| id as &dyn rusqlite::ToSql)),
+                        ))
+                    })
+                    .map_err(|err| crate::database::EntityError::from_sqlite(#name_string, err))?;
+            }
+            Ok(deleted)
+        }
+
+        #[doc = #restore_docstring]
+        pub fn restore(txn: &WriteTxn, id: &#primary_key_type) -> std::result::Result<(), crate::database::EntityError> {
+            let restored = txn
+                .prepare_cached(#restore_statement)
+                .and_then(|mut stmt| stmt.execute(#restore_params))
+                .map_err(|err| crate::database::EntityError::from_sqlite(#name_string, err))?;
+            match restored {
+                0 => Err(crate::database::EntityError::not_found(#name_string, id)),
+                _ => Ok(()),
+            }
+        }
+
+        #[doc = #purge_docstring]
+        pub fn purge(txn: &WriteTxn, id: &#primary_key_type) -> std::result::Result<(), crate::database::EntityError> {
+            let purged = txn
+                .prepare_cached(#purge_statement)
+                .and_then(|mut stmt| stmt.execute([id]))
+                .map_err(|err| crate::database::EntityError::from_sqlite(#name_string, err))?;
+            match purged {
+                0 => Err(crate::database::EntityError::not_found(#name_string, id)),
+                _ => Ok(()),
+            }
+        }
+    )
+}
@@ -2,14 +2,19 @@ duckdb << EOF
 
 COPY (
     SELECT
-		MD5(CONCAT('user',generate_series))::UUID AS id
+		MD5(CONCAT('user',generate_series))::UUID AS id,
+		'2024-01-01T00:00:00Z' AS created_at,
+		'2024-01-01T00:00:00Z' AS updated_at,
+		1 AS version
 	FROM GENERATE_SERIES(0,10,1)
 ) TO 'users.json' (FORMAT JSON, ARRAY true);
 
 COPY (
     SELECT
     	MD5(CONCAT('identity',generate_series))::UUID AS id,
-    	MD5(CONCAT('user',generate_series))::UUID AS user_id
+    	MD5(CONCAT('user',generate_series))::UUID AS user_id,
+    	'2024-01-01T00:00:00Z' AS created_at,
+    	'2024-01-01T00:00:00Z' AS updated_at
     FROM GENERATE_SERIES(0,10,1)
 ) TO 'identitys_users.json' (FORMAT JSON, ARRAY true);
 
@@ -0,0 +1,3 @@
+DROP TABLE identitys_users;
+
+DROP TABLE users;
@@ -0,0 +1,9 @@
+ALTER TABLE identitys_users DROP COLUMN updated_at;
+
+ALTER TABLE identitys_users DROP COLUMN created_at;
+
+ALTER TABLE users DROP COLUMN deleted_at;
+
+ALTER TABLE users DROP COLUMN updated_at;
+
+ALTER TABLE users DROP COLUMN created_at;
@@ -0,0 +1,17 @@
+ALTER TABLE users ADD COLUMN created_at TEXT NOT NULL DEFAULT '1970-01-01 00:00:00+00:00';
+
+ALTER TABLE users ADD COLUMN updated_at TEXT NOT NULL DEFAULT '1970-01-01 00:00:00+00:00';
+
+ALTER TABLE users ADD COLUMN deleted_at TEXT;
+
+ALTER TABLE identitys_users ADD COLUMN created_at TEXT NOT NULL DEFAULT '1970-01-01 00:00:00+00:00';
+
+ALTER TABLE identitys_users ADD COLUMN updated_at TEXT NOT NULL DEFAULT '1970-01-01 00:00:00+00:00';
+
+UPDATE users SET
+    created_at = strftime('%Y-%m-%d %H:%M:%f+00:00', 'now'),
+    updated_at = strftime('%Y-%m-%d %H:%M:%f+00:00', 'now');
+
+UPDATE identitys_users SET
+    created_at = strftime('%Y-%m-%d %H:%M:%f+00:00', 'now'),
+    updated_at = strftime('%Y-%m-%d %H:%M:%f+00:00', 'now');
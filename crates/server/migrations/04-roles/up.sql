@@ -0,0 +1,35 @@
+CREATE TABLE roles (
+    id BLOB PRIMARY KEY NOT NULL,
+    name TEXT NOT NULL,
+    permissions TEXT NOT NULL CHECK (json_valid(permissions)),
+    created_at TEXT NOT NULL,
+    updated_at TEXT NOT NULL
+) WITHOUT ROWID,
+STRICT;
+
+CREATE UNIQUE INDEX roles_name_idx ON roles (name);
+
+CREATE TABLE role_grants (
+    id BLOB PRIMARY KEY NOT NULL,
+    user_id BLOB NOT NULL,
+    role_id BLOB NOT NULL,
+    created_at TEXT NOT NULL,
+    updated_at TEXT NOT NULL,
+    FOREIGN KEY (user_id) REFERENCES users(id),
+    FOREIGN KEY (role_id) REFERENCES roles(id)
+) WITHOUT ROWID,
+STRICT;
+
+-- A role is granted to a user at most once. This also indexes the grants of a user.
+CREATE UNIQUE INDEX role_grants_user_id_role_id_idx ON role_grants (user_id, role_id);
+
+CREATE INDEX role_grants_role_id_idx ON role_grants (role_id);
+
+-- Every permission, for the identities whose Kratos `metadata_admin` lists the `admin` role.
+INSERT INTO roles (id, name, permissions, created_at, updated_at) VALUES (
+    X'0192a4e1f0a87c3e9b5d6f2a41c8e7d3',
+    'admin',
+    '["*"]',
+    strftime('%Y-%m-%d %H:%M:%f+00:00', 'now'),
+    strftime('%Y-%m-%d %H:%M:%f+00:00', 'now')
+);
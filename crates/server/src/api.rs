@@ -1,6 +1,47 @@
-use dropshot::{HttpError, HttpResponseOk, RequestContext};
+use dropshot::{
+    HttpError, HttpResponseDeleted, HttpResponseHeaders, HttpResponseOk,
+    HttpResponseUpdatedNoContent, Path, RequestContext, TypedBody,
+};
+use schemars::JsonSchema;
+use serde::Deserialize;
+use uuid::Uuid;
 
-use crate::entity::user::User;
+use crate::{
+    auth::AuthenticatedUser,
+    entity::{role::Role, user::User},
+    etag::ETagHeader,
+    kratos::SessionCookieConfig,
+};
+
+/// The path of a role.
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct RolePath {
+    /// The name of the role.
+    pub role: String,
+}
+
+/// The path of a user.
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct UserPath {
+    /// The id of the user.
+    pub user_id: Uuid,
+}
+
+/// The path of a role granted to a user.
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct UserRolePath {
+    /// The id of the user.
+    pub user_id: Uuid,
+    /// The name of the role.
+    pub role: String,
+}
+
+/// The permissions of a role.
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct RolePermissions {
+    /// The permissions the role grants, each `resource:action`, `resource:*` or `*`.
+    pub permissions: Vec<String>,
+}
 
 /// The Dropshot API trait.
 #[dropshot::api_description]
@@ -14,16 +55,97 @@ pub(crate) trait ServerApi {
     #[endpoint { method = GET, path = "/v1/user" }]
     async fn get_user(
         rqctx: RequestContext<Self::Context>,
-    ) -> Result<HttpResponseOk<User>, HttpError>;
+        user: AuthenticatedUser,
+    ) -> Result<HttpResponseHeaders<HttpResponseOk<User>, ETagHeader>, HttpError>;
+
+    /// Delete the current user.
+    ///
+    /// With an `If-Match` header it is only deleted if it has not been updated since that `ETag`.
+    #[endpoint { method = DELETE, path = "/v1/user" }]
+    async fn delete_user(
+        rqctx: RequestContext<Self::Context>,
+        user: AuthenticatedUser,
+    ) -> Result<HttpResponseDeleted, HttpError>;
+
+    /// List the roles.
+    ///
+    /// Requires the `roles:read` permission.
+    #[endpoint { method = GET, path = "/v1/roles" }]
+    async fn list_roles(
+        rqctx: RequestContext<Self::Context>,
+        user: AuthenticatedUser,
+    ) -> Result<HttpResponseOk<Vec<Role>>, HttpError>;
+
+    /// Create a role or replace its permissions.
+    ///
+    /// Requires the `roles:write` permission.
+    #[endpoint { method = PUT, path = "/v1/roles/{role}" }]
+    async fn put_role(
+        rqctx: RequestContext<Self::Context>,
+        user: AuthenticatedUser,
+        path: Path<RolePath>,
+        body: TypedBody<RolePermissions>,
+    ) -> Result<HttpResponseOk<Role>, HttpError>;
+
+    /// List the roles granted to a user.
+    ///
+    /// Requires the `grants:read` permission.
+    #[endpoint { method = GET, path = "/v1/users/{user_id}/roles" }]
+    async fn list_user_roles(
+        rqctx: RequestContext<Self::Context>,
+        user: AuthenticatedUser,
+        path: Path<UserPath>,
+    ) -> Result<HttpResponseOk<Vec<Role>>, HttpError>;
+
+    /// Grant a role to a user.
+    ///
+    /// Requires the `grants:write` permission. Granting a role the user has already succeeds.
+    #[endpoint { method = PUT, path = "/v1/users/{user_id}/roles/{role}" }]
+    async fn grant_role(
+        rqctx: RequestContext<Self::Context>,
+        user: AuthenticatedUser,
+        path: Path<UserRolePath>,
+    ) -> Result<HttpResponseUpdatedNoContent, HttpError>;
+
+    /// Revoke a role from a user.
+    ///
+    /// Requires the `grants:write` permission. Revoking a role the user does not have succeeds.
+    #[endpoint { method = DELETE, path = "/v1/users/{user_id}/roles/{role}" }]
+    async fn revoke_role(
+        rqctx: RequestContext<Self::Context>,
+        user: AuthenticatedUser,
+        path: Path<UserRolePath>,
+    ) -> Result<HttpResponseDeleted, HttpError>;
 }
 
 // A simple function to generate an OpenAPI spec for the trait, without having
 // a real implementation available.
 //
 // If the interface and implementation (see below) are in different crates, then
-// this function would live in the interface crate.
+// this function would live in the interface crate. The spec does not depend on the
+// configuration, so the session cookie has its default name.
 pub(crate) fn generate_openapi_spec() -> String {
     let description = server_api_mod::stub_api_description().unwrap();
     let spec = description.openapi("Server", "1.0.0");
-    serde_json::to_string_pretty(&spec.json().unwrap()).unwrap()
+    let mut json = spec.json().unwrap();
+    crate::auth::add_security(&mut json, &SessionCookieConfig::default().name);
+    serde_json::to_string_pretty(&json).unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    // `api/v1.json` is checked in for the clients generated from it, and must be rewritten
+    // with `server openapi` after changing the API.
+    #[test]
+    fn openapi_spec_is_current() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("api/v1.json");
+        let current = std::fs::read_to_string(path).expect("read api/v1.json");
+        assert!(
+            current == super::generate_openapi_spec(),
+            "api/v1.json is out of date, run \
+             `cargo run -p server -- openapi > crates/server/api/v1.json` to rewrite it"
+        );
+    }
 }
@@ -0,0 +1,219 @@
+use std::any::Any;
+
+use async_trait::async_trait;
+use dropshot::{
+    ApiEndpointBodyContentType, ExtensionMode, ExtractorMetadata, HttpError, RequestContext,
+    ServerContext, SharedExtractor,
+};
+use serde_json::{json, Value};
+
+use crate::{context::Context, entity::user::User};
+
+/// The user a request is authenticated as, taken by handlers as a parameter.
+///
+/// The request must carry an `X-Session-Token` header, an `Authorization: Bearer` session token
+/// or tokenizer JWT, or the Kratos session cookie, as described by the security schemes of the
+/// OpenAPI document.
+///
+/// # Failure
+///
+/// The request fails with a 401 "Unauthorized" if it is not authenticated, or a 403 "Forbidden"
+/// if it is authenticated by the cookie but may have been forged by another site.
+#[derive(Debug)]
+pub struct AuthenticatedUser(User);
+
+impl AuthenticatedUser {
+    pub fn into_inner(self) -> User {
+        self.0
+    }
+}
+
+#[async_trait]
+impl SharedExtractor for AuthenticatedUser {
+    async fn from_request<C: ServerContext>(rqctx: &RequestContext<C>) -> Result<Self, HttpError> {
+        let context = (rqctx.context() as &dyn Any)
+            .downcast_ref::<Context>()
+            .ok_or_else(|| {
+                HttpError::for_internal_error(
+                    "AuthenticatedUser needs the server Context".to_string(),
+                )
+            })?;
+        User::authenticate(context, &rqctx.request)
+            .await
+            .map(AuthenticatedUser)
+    }
+
+    fn metadata(_body_content_type: ApiEndpointBodyContentType) -> ExtractorMetadata {
+        // Dropshot has no way to describe authentication here, see `add_security`.
+        ExtractorMetadata {
+            extension_mode: ExtensionMode::None,
+            parameters: vec![],
+        }
+    }
+}
+
+/// The permissions the API checks, granted to users by their roles.
+pub mod permission {
+    /// List roles and their permissions.
+    pub const ROLES_READ: &str = "roles:read";
+    /// Create roles and change their permissions.
+    pub const ROLES_WRITE: &str = "roles:write";
+    /// List the roles granted to a user.
+    pub const GRANTS_READ: &str = "grants:read";
+    /// Grant roles to and revoke roles from a user.
+    pub const GRANTS_WRITE: &str = "grants:write";
+}
+
+/// Checks that the user a request is authenticated as has `permission` through one of their
+/// roles. Handlers take the [`AuthenticatedUser`] as a parameter, so that the OpenAPI document
+/// says they require authentication.
+///
+/// # Failure
+///
+/// Will return a 403 "Forbidden" with the error code `MissingPermission` if the user does not
+/// have `permission`.
+pub async fn require_permission(
+    rqctx: &RequestContext<Context>,
+    user: AuthenticatedUser,
+    permission: &str,
+) -> Result<User, HttpError> {
+    let user = user.into_inner();
+    let user_move = user.clone();
+    let permissions = rqctx
+        .context()
+        .database()
+        .read_txn(move |txn| Ok(user_move.permissions(txn)?))
+        .await?;
+    match permissions.allows(permission) {
+        true => Ok(user),
+        false => Err(HttpError::for_client_error(
+            Some("MissingPermission".to_string()),
+            http::StatusCode::FORBIDDEN,
+            format!("missing permission {permission}"),
+        )),
+    }
+}
+
+/// The operations, by `operationId`, that do not authenticate the request, such as probes.
+///
+/// These are the endpoints that do not take an [`AuthenticatedUser`], which the tests check.
+const UNAUTHENTICATED_OPERATIONS: &[&str] = &[];
+
+/// Adds the ways an [`AuthenticatedUser`] can authenticate to the OpenAPI document `spec`, as
+/// security schemes any one of which each operation requires, with the session cookie named
+/// `cookie_name`. The name is configurable, see `session_cookie.name`.
+///
+/// The `security` is set per operation, so an endpoint that does not take an
+/// `AuthenticatedUser` only needs to be listed in `UNAUTHENTICATED_OPERATIONS`.
+pub(crate) fn add_security(spec: &mut Value, cookie_name: &str) {
+    spec["components"]["securitySchemes"] = json!({
+        "sessionToken": {
+            "type": "apiKey",
+            "in": "header",
+            "name": "X-Session-Token",
+            "description": "A Kratos session token, as returned by the native login flow."
+        },
+        "bearer": {
+            "type": "http",
+            "scheme": "bearer",
+            "description": "A Kratos session token, or a JWT from the Kratos session tokenizer."
+        },
+        "sessionCookie": {
+            "type": "apiKey",
+            "in": "cookie",
+            "name": cookie_name,
+            "description": "The session cookie of the Kratos browser flows, named by the \
+                `session_cookie.name` of the server configuration. Requests other than GET, HEAD \
+                and OPTIONS must come from an allowed Origin."
+        }
+    });
+    let operations = spec["paths"]
+        .as_object_mut()
+        .into_iter()
+        .flat_map(|paths| paths.values_mut())
+        .filter_map(Value::as_object_mut)
+        .flat_map(|path| path.values_mut());
+    for operation in operations {
+        let authenticated = operation["operationId"]
+            .as_str()
+            .is_some_and(|id| !UNAUTHENTICATED_OPERATIONS.contains(&id));
+        if authenticated {
+            operation["security"] = json!([
+                { "sessionToken": [] },
+                { "bearer": [] },
+                { "sessionCookie": [] }
+            ]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeSet;
+
+    use serde_json::Value;
+
+    use crate::kratos::SessionCookieConfig;
+
+    #[test]
+    fn openapi_spec_declares_security() {
+        let spec: Value = serde_json::from_str(&crate::api::generate_openapi_spec()).unwrap();
+        let schemes = spec["components"]["securitySchemes"].as_object().unwrap();
+        assert!(spec.get("security").is_none());
+
+        let paths = spec["paths"].as_object().unwrap();
+        let operation = &paths["/v1/user"]["get"];
+        let required: Vec<&str> = operation["security"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .flat_map(|requirement| requirement.as_object().unwrap().keys())
+            .map(String::as_str)
+            .collect();
+        assert_eq!(required, ["sessionToken", "bearer", "sessionCookie"]);
+        assert!(required.iter().all(|name| schemes.contains_key(*name)));
+        assert!(paths
+            .values()
+            .flat_map(|path| path.as_object().unwrap().values())
+            .all(|operation| operation["security"] == paths["/v1/user"]["get"]["security"]));
+
+        assert_eq!(schemes["sessionToken"]["name"], "X-Session-Token");
+        assert_eq!(
+            schemes["sessionCookie"]["name"],
+            SessionCookieConfig::default().name
+        );
+    }
+
+    // Dropshot cannot describe authentication, so the endpoints of `ServerApi` that take an
+    // `AuthenticatedUser` are read from its source and compared with the security of the spec.
+    #[test]
+    fn security_matches_the_handlers() {
+        let mut authenticated = BTreeSet::new();
+        let mut unauthenticated = BTreeSet::new();
+        for endpoint in include_str!("api.rs").split("#[endpoint").skip(1) {
+            let (_, signature) = endpoint.split_once("async fn ").unwrap();
+            let (name, parameters) = signature.split_once('(').unwrap();
+            let (parameters, _) = parameters.split_once(") ->").unwrap();
+            match parameters.contains(": AuthenticatedUser,") {
+                true => authenticated.insert(name),
+                false => unauthenticated.insert(name),
+            };
+        }
+        assert!(!authenticated.is_empty());
+        assert_eq!(
+            unauthenticated,
+            super::UNAUTHENTICATED_OPERATIONS.iter().copied().collect()
+        );
+
+        let spec: Value = serde_json::from_str(&crate::api::generate_openapi_spec()).unwrap();
+        let secured: BTreeSet<&str> = spec["paths"]
+            .as_object()
+            .unwrap()
+            .values()
+            .flat_map(|path| path.as_object().unwrap().values())
+            .filter(|operation| operation.get("security").is_some())
+            .map(|operation| operation["operationId"].as_str().unwrap())
+            .collect();
+        assert_eq!(secured, authenticated);
+    }
+}
@@ -0,0 +1,49 @@
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use std::path::PathBuf;
+
+use crate::{
+    database::DatabaseConfig,
+    kratos::{JwtConfig, SessionCacheConfig, SessionCookieConfig},
+};
+
+/// The environment variable naming the JSON file the configuration is read from.
+const CONFIG_ENV: &str = "SERVER_CONFIG";
+
+/// Server configuration.
+///
+/// Every section is optional and falls back to its defaults, so an empty file (or no file at
+/// all) is a valid configuration.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Connection pragmas, pool sizing and queueing for the SQLite database.
+    pub database: DatabaseConfig,
+    /// A backup (as written by `Database::backup_to`) to restore at startup when the database
+    /// file does not exist yet.
+    pub restore_from: Option<PathBuf>,
+    /// Lifetimes and size of the cache of verified Kratos sessions.
+    pub session_cache: SessionCacheConfig,
+    /// Offline verification of `Authorization: Bearer` JWTs from the Kratos session tokenizer.
+    pub jwt: JwtConfig,
+    /// The Kratos session cookie of browsers, and the origins allowed to use it for writes.
+    pub session_cookie: SessionCookieConfig,
+}
+
+impl Config {
+    /// Load the configuration from the JSON file named by `SERVER_CONFIG`, or the defaults if it
+    /// is not set.
+    pub fn load() -> Result<Self> {
+        match std::env::var_os(CONFIG_ENV) {
+            Some(path) => {
+                let contents = std::fs::read_to_string(&path).map_err(|err| {
+                    anyhow!("failed to read config {}: {}", path.to_string_lossy(), err)
+                })?;
+                serde_json::from_str(&contents).map_err(|err| {
+                    anyhow!("failed to parse config {}: {}", path.to_string_lossy(), err)
+                })
+            }
+            None => Ok(Self::default()),
+        }
+    }
+}
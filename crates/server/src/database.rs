@@ -15,21 +15,49 @@
     missing_debug_implementations
 )]
 
-use crossbeam_channel::Sender;
+use anyhow::anyhow;
+use crossbeam_channel::Receiver;
 use include_dir::{include_dir, Dir};
 use lazy_static::lazy_static;
-use rusqlite::OpenFlags;
+use rusqlite::{OpenFlags, TransactionBehavior};
 use rusqlite_migration::Migrations;
+use std::any::Any;
+use std::cell::RefCell;
+use std::fmt::{self, Debug, Display};
+use std::ops::Deref;
+use std::panic::{self, AssertUnwindSafe};
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::thread::JoinHandle;
-use std::{
-    fmt::{self, Debug, Display},
-    thread,
-};
+use std::time::{Duration, Instant};
 use tokio::sync::oneshot;
 
-static MESSAGE_BOUND: usize = 100;
+mod backup;
+mod config;
+mod convert;
+mod entity;
+mod generate;
+mod migration;
+mod query;
+mod queue;
+mod schema;
+mod timeout;
+mod worker;
+
+pub use config::{ConnectionConfig, DatabaseConfig, InitFn};
+pub use convert::{enum_values, EnumText, Json};
+pub use entity::{Entity, EntityError, EntityHooks, FieldError};
+pub(crate) use generate::generate_migration;
+pub use generate::GeneratedMigration;
+pub use migration::{DryRun, MigrationInfo, MigrationStatus, SchemaChange};
+pub use query::{Cursor, Filter, Order, Page, Query, QueryColumn};
+use queue::Queue;
+pub use queue::{DatabaseMetrics, QueueStats};
+pub use schema::{Affinity, Check, Column, ColumnType, EntitySchema, Reference};
+pub use worker::{ConnectionStatus, PoolStatus};
+use worker::{OpenFn, Slot, Worker};
+
 static MIGRATIONS_DIR: Dir = include_dir!("$CARGO_MANIFEST_DIR/migrations");
 
 lazy_static! {
@@ -37,6 +65,10 @@ lazy_static! {
         Migrations::from_directory(&MIGRATIONS_DIR).unwrap();
 }
 
+/// The most parameters a statement can have, SQLite's default `SQLITE_MAX_VARIABLE_NUMBER`.
+/// The generated methods taking lists of entities or keys run one statement per chunk.
+pub const MAX_PARAMETERS: usize = 32766;
+
 const BUG_TEXT: &str = "bug in tokio-rusqlite, please report";
 
 // Helper function to return a comma-separated sequence of `?`.
@@ -61,9 +93,22 @@ pub(crate) enum Error {
     /// The connection to the SQLite has been closed and cannot be queried any more.
     ConnectionClosed,
 
+    /// The queue for the connection was full and no capacity became available in time.
+    Overloaded,
+
+    /// The call did not complete within its timeout and was skipped or interrupted.
+    Timeout,
+
+    /// The called function panicked. The panic is contained to the call and its transaction is
+    /// rolled back.
+    Panicked(String),
+
     /// A `Rusqlite` error occured.
     Rusqlite(rusqlite::Error),
 
+    /// A method generated by the `ToSql` derive failed.
+    Entity(EntityError),
+
     /// An application-specific error occured.
     Other(anyhow::Error),
 }
@@ -72,7 +117,11 @@ impl Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Error::ConnectionClosed => write!(f, "ConnectionClosed"),
+            Error::Overloaded => write!(f, "Overloaded"),
+            Error::Timeout => write!(f, "Timeout"),
+            Error::Panicked(message) => write!(f, "Panicked(\"{message}\")"),
             Error::Rusqlite(e) => write!(f, "Rusqlite(\"{e}\")"),
+            Error::Entity(e) => write!(f, "Entity(\"{e}\")"),
             Error::Other(ref e) => write!(f, "Other(\"{e}\")"),
         }
     }
@@ -81,8 +130,11 @@ impl Display for Error {
 impl std::error::Error for Error {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
-            Error::ConnectionClosed => None,
+            Error::ConnectionClosed | Error::Overloaded | Error::Timeout | Error::Panicked(_) => {
+                None
+            }
             Error::Rusqlite(e) => Some(e),
+            Error::Entity(e) => Some(e),
             Error::Other(ref e) => Some(&**e),
         }
     }
@@ -94,9 +146,19 @@ impl From<rusqlite::Error> for Error {
     }
 }
 
+impl From<EntityError> for Error {
+    fn from(value: EntityError) -> Self {
+        Error::Entity(value)
+    }
+}
+
 impl From<anyhow::Error> for Error {
     fn from(value: anyhow::Error) -> Self {
-        Error::Other(value)
+        // Keep entity errors typed when they pass through `anyhow`, so they map to a status.
+        match value.downcast::<EntityError>() {
+            Ok(err) => Error::Entity(err),
+            Err(value) => Error::Other(value),
+        }
     }
 }
 
@@ -109,69 +171,181 @@ impl From<rusqlite_migration::Error> for Error {
 /// The result returned on method calls in this crate.
 pub(crate) type Result<T> = std::result::Result<T, Error>;
 
+/// Call `function`, converting a panic into [`Error::Panicked`] so that it is reported to the
+/// caller instead of unwinding through the connection thread.
+fn catch_panic<R>(function: impl FnOnce() -> Result<R>) -> Result<R> {
+    panic::catch_unwind(AssertUnwindSafe(function))
+        .unwrap_or_else(|payload| Err(Error::Panicked(panic_message(payload.as_ref()))))
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    match payload.downcast_ref::<&str>() {
+        Some(message) => message.to_string(),
+        None => match payload.downcast_ref::<String>() {
+            Some(message) => message.clone(),
+            None => "Box<dyn Any>".to_string(),
+        },
+    }
+}
+
+/// Call `function` with the connection, catching a panic and rolling back any transaction it
+/// left open so that the next job starts from a clean connection.
+fn call_unwind_safe<R>(
+    conn: &mut rusqlite::Connection,
+    function: impl FnOnce(&mut rusqlite::Connection) -> Result<R>,
+) -> Result<R> {
+    let value = catch_panic(|| function(conn));
+    if matches!(value, Err(Error::Panicked(_))) && !conn.is_autocommit() {
+        let _ = conn.execute_batch("ROLLBACK;");
+    }
+    value
+}
+
+/// A handle to an open write transaction on the writer connection.
+///
+/// Only available inside [`Database::write_txn`], which opens the transaction as `IMMEDIATE`,
+/// commits it when the closure returns `Ok` and rolls it back when it returns `Err`. Methods that
+/// modify the database take a `&WriteTxn` so they cannot be called with a [`ReadTxn`].
+pub struct WriteTxn<'conn> {
+    conn: &'conn rusqlite::Connection,
+    after_commit: RefCell<Vec<AfterCommitFn>>,
+}
+
+/// A function to call once a write transaction has been committed.
+type AfterCommitFn = Box<dyn FnOnce() + Send + 'static>;
+
+impl<'conn> WriteTxn<'conn> {
+    fn new(conn: &'conn rusqlite::Connection) -> Self {
+        Self {
+            conn,
+            after_commit: RefCell::default(),
+        }
+    }
+
+    /// Call `function` once the transaction has been committed, or never if it is rolled back.
+    ///
+    /// This is for side effects that must only happen if the write does, such as notifying
+    /// another service. The functions are called in order, for a batched write once the whole
+    /// group has been committed, and may run on the writer connection's thread, so they should
+    /// hand any slow work off rather than block it.
+    pub fn after_commit(&self, function: impl FnOnce() + Send + 'static) {
+        self.after_commit.borrow_mut().push(Box::new(function));
+    }
+
+    /// The functions to call once the transaction has been committed.
+    fn into_after_commit(self) -> Vec<AfterCommitFn> {
+        self.after_commit.into_inner()
+    }
+}
+
+impl Debug for WriteTxn<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WriteTxn")
+            .field("conn", &self.conn)
+            .field("after_commit", &self.after_commit.borrow().len())
+            .finish()
+    }
+}
+
+impl Deref for WriteTxn<'_> {
+    type Target = rusqlite::Connection;
+
+    fn deref(&self) -> &Self::Target {
+        self.conn
+    }
+}
+
+/// A handle to an open read transaction on one of the read-only reader connections.
+///
+/// Only available inside [`Database::read_txn`], which opens the transaction as `DEFERRED` so
+/// that all statements observe the same snapshot of the database.
+#[derive(Debug)]
+pub struct ReadTxn<'conn> {
+    conn: &'conn rusqlite::Connection,
+}
+
+impl Deref for ReadTxn<'_> {
+    type Target = rusqlite::Connection;
+
+    fn deref(&self) -> &Self::Target {
+        self.conn
+    }
+}
+
 type CallFn = Box<dyn FnOnce(&mut rusqlite::Connection) + Send + 'static>;
 
-#[allow(dead_code)]
+/// A job queued by [`Database::write_batched`]. It receives the group transaction (or the error
+/// from beginning it) and returns a callback that delivers its result once the outcome of the
+/// group commit is known.
+type BatchFn =
+    Box<dyn FnOnce(rusqlite::Result<&mut rusqlite::Transaction>) -> Completion + Send + 'static>;
+
+type Completion = Box<dyn FnOnce(std::result::Result<(), &rusqlite::Error>) + Send + 'static>;
+
 enum Message {
     Execute(CallFn),
+    Batch(BatchFn),
     Close,
 }
 
+/// The result of a connection thread, reporting any error from closing its connection.
+type ConnectionHandle = JoinHandle<rusqlite::Result<()>>;
+
 /// A handle to call functions in background thread.
 #[derive(Clone)]
 pub struct Database {
-    writer_sender: Sender<Message>,
-    reader_sender: Sender<Message>,
-    writer_handle: Arc<JoinHandle<()>>,
-    reader_handles: Arc<Vec<JoinHandle<()>>>,
+    writer: Queue,
+    reader: Queue,
+    writer_slot: Slot,
+    reader_slots: Arc<Vec<Slot>>,
+    closed: Arc<AtomicBool>,
 }
 
 impl Database {
     /// Open a new connection to a SQLite database.
     ///
-    /// `Connection::open(path)` is equivalent to
+    /// The writer is opened with `Connection::open(path)`, which is equivalent to
     /// `Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_WRITE |
-    /// OpenFlags::SQLITE_OPEN_CREATE)`.
+    /// OpenFlags::SQLITE_OPEN_CREATE)`, and the migrations are applied to it unless
+    /// `config.migrate` is unset. Then
+    /// `config.readers` read-only connections are opened. Each connection has the pragmas of
+    /// `config.writer` or `config.reader` applied to it.
     ///
     /// # Failure
     ///
-    /// Will return `Err` if `path` cannot be converted to a C-compatible
+    /// Will return `Err` if `config` is invalid, if `path` cannot be converted to a C-compatible
     /// string or if the underlying SQLite open call fails.
-    pub(crate) async fn open<P: AsRef<Path>>(path: P, readers: usize) -> Result<Self> {
+    pub(crate) async fn open<P: AsRef<Path>>(path: P, config: DatabaseConfig) -> Result<Self> {
+        config.validate().map_err(Error::Other)?;
         let path = path.as_ref().to_owned();
         let path_clone = path.clone();
+        let writer_config = config.writer.clone();
+        let reader_config = config.reader.clone();
+        let migrate = config.migrate;
 
         start(
-            move || {
-                let mut writer: rusqlite::Connection = rusqlite::Connection::open(path)?;
-                writer.set_prepared_statement_cache_capacity(1024);
-                writer.execute_batch(
-                    "
-                    PRAGMA journal_mode=WAL;
-                    PRAGMA synchronous=NORMAL;
-                    PRAGMA temp_store=MEMORY;
-                    PRAGMA cache_size=-67108864;
-                    PRAGMA foreign_keys=true;
-                    PRAGMA busy_timeout=5000;
-                    ",
-                )?;
+            Arc::new(move || {
+                let mut writer: rusqlite::Connection = rusqlite::Connection::open(&path)?;
+                writer_config.apply(&writer)?;
 
-                MIGRATIONS
-                    .to_latest(&mut writer)
-                    .map_err(|err| rusqlite::Error::UserFunctionError(Box::new(err)))?;
+                if migrate {
+                    MIGRATIONS
+                        .to_latest(&mut writer)
+                        .map_err(|err| rusqlite::Error::UserFunctionError(Box::new(err)))?;
+                }
 
                 Ok(writer)
-            },
+            }),
             Arc::new(move || {
                 let reader = rusqlite::Connection::open_with_flags(
                     path_clone.clone(),
                     OpenFlags::SQLITE_OPEN_READ_ONLY,
                 )?;
-                reader.set_prepared_statement_cache_capacity(1024);
+                reader_config.apply(&reader)?;
 
                 Ok(reader)
             }),
-            readers,
+            &config,
         )
         .await
         .map_err(Error::Rusqlite)
@@ -183,9 +357,9 @@ impl Database {
     ///
     /// Will return `Err` if the underlying SQLite open call fails.
     #[allow(dead_code)]
-    pub(crate) async fn open_in_memory(readers: usize) -> Result<Self> {
+    pub(crate) async fn open_in_memory(config: DatabaseConfig) -> Result<Self> {
         let name = format!("file:{}?mode=memory&cache=shared", uuid::Uuid::new_v4());
-        Self::open(name, readers).await
+        Self::open(name, config).await
     }
 
     /// Call a function in background thread and get the result
@@ -202,12 +376,14 @@ impl Database {
     {
         let (sender, receiver) = oneshot::channel::<Result<R>>();
 
-        self.writer_sender
-            .send(Message::Execute(Box::new(move |conn| {
-                let value = function(conn);
-                let _ = sender.send(value);
-            })))
-            .map_err(|_| Error::ConnectionClosed)?;
+        self.writer
+            .send(|ticket| {
+                Message::Execute(Box::new(move |conn| {
+                    let value = ticket.run(|| call_unwind_safe(conn, function));
+                    let _ = sender.send(value);
+                }))
+            })
+            .await?;
 
         receiver.await.map_err(|_| Error::ConnectionClosed)?
     }
@@ -226,148 +402,1727 @@ impl Database {
     {
         let (sender, receiver) = oneshot::channel::<Result<R>>();
 
-        self.reader_sender
-            .send(Message::Execute(Box::new(move |conn| {
-                let value = function(conn);
-                let _ = sender.send(value);
-            })))
-            .map_err(|_| Error::ConnectionClosed)?;
+        self.reader
+            .send(|ticket| {
+                Message::Execute(Box::new(move |conn| {
+                    let value = ticket.run(|| call_unwind_safe(conn, function));
+                    let _ = sender.send(value);
+                }))
+            })
+            .await?;
 
         receiver.await.map_err(|_| Error::ConnectionClosed)?
     }
 
-    /// Close the database connection.
+    /// Call a function with an `IMMEDIATE` transaction on the writer connection and get the
+    /// result asynchronously.
     ///
-    /// This is functionally equivalent to the `Drop` implementation for
-    /// `Connection`. It consumes the `Connection`, but on error returns it
-    /// to the caller for retry purposes.
+    /// The transaction is committed if `function` returns `Ok` and rolled back if it returns
+    /// `Err`.
     ///
-    /// If successful, any following `close` operations performed
-    /// on `Connection` copies will succeed immediately.
+    /// # Failure
     ///
-    /// On the other hand, any calls to [`Connection::call`] will return a [`Error::ConnectionClosed`],
-    /// and any calls to [`Connection::call_unwrap`] will cause a `panic`.
+    /// Will return `Err` if the database connection has been closed, if `function` fails or if
+    /// the transaction cannot be committed.
+    pub(crate) async fn write_txn<F, R>(&self, function: F) -> Result<R>
+    where
+        F: FnOnce(&WriteTxn) -> Result<R> + 'static + Send,
+        R: Send + 'static,
+    {
+        self.write(move |conn| {
+            let txn = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let write_txn = WriteTxn::new(&txn);
+            match function(&write_txn) {
+                Ok(value) => {
+                    let after_commit = write_txn.into_after_commit();
+                    txn.commit()?;
+                    after_commit.into_iter().for_each(|function| function());
+                    Ok(value)
+                }
+                Err(err) => {
+                    // Should the rollback fail too, the error of `function` is still the one
+                    // returned.
+                    let _ = txn.rollback();
+                    Err(err)
+                }
+            }
+        })
+        .await
+    }
+
+    /// Call a function on the writer connection as part of a group commit and get the result
+    /// asynchronously.
+    ///
+    /// Batched writes that are queued within a short window of each other are executed in a
+    /// single `IMMEDIATE` transaction which is committed once, with each function running in its
+    /// own savepoint. If `function` returns `Err` only its own savepoint is rolled back and the
+    /// rest of the group is unaffected. The result is only delivered once the group has been
+    /// committed.
+    ///
+    /// Prefer this over [`Database::write_txn`] for bursts of small, independent writes where a
+    /// transaction (and fsync) per write would dominate.
     ///
     /// # Failure
     ///
-    /// Will return `Err` if the underlying SQLite close call fails.
+    /// Will return `Err` if the database connection has been closed, if `function` fails or if
+    /// the group transaction cannot be begun or committed.
     #[allow(dead_code)]
-    pub(crate) async fn close(self) -> Result<()> {
-        // close readers
-        let reader_sender = self.reader_sender.clone();
-        while self
-            .reader_handles
-            .iter()
-            .any(|reader_handle| !reader_handle.is_finished())
-        {
-            let reader_sender = reader_sender.clone();
-            reader_sender.send(Message::Close).ok();
+    pub(crate) async fn write_batched<F, R>(&self, function: F) -> Result<R>
+    where
+        F: FnOnce(&WriteTxn) -> Result<R> + 'static + Send,
+        R: Send + 'static,
+    {
+        let (sender, receiver) = oneshot::channel::<Result<R>>();
+
+        self.writer
+            .send(|ticket| {
+                Message::Batch(Box::new(move |txn| {
+                    let value = ticket.run(|| {
+                        txn.map_err(Error::from).and_then(|txn| {
+                            // Dropping the savepoint without committing rolls it back.
+                            let savepoint = txn.savepoint()?;
+                            let write_txn = WriteTxn::new(&savepoint);
+                            let value = catch_panic(|| function(&write_txn))?;
+                            let after_commit = write_txn.into_after_commit();
+                            savepoint.commit()?;
+                            Ok((value, after_commit))
+                        })
+                    });
+
+                    Box::new(move |committed| {
+                        let value = match committed {
+                            Ok(()) => value.map(|(value, after_commit)| {
+                                after_commit.into_iter().for_each(|function| function());
+                                value
+                            }),
+                            Err(err) => {
+                                value.and(Err(Error::Other(anyhow!("group commit failed: {err}"))))
+                            }
+                        };
+                        let _ = sender.send(value);
+                    })
+                }))
+            })
+            .await?;
+
+        receiver.await.map_err(|_| Error::ConnectionClosed)?
+    }
+
+    /// Call a function with a `DEFERRED` transaction on a reader connection and get the result
+    /// asynchronously.
+    ///
+    /// # Failure
+    ///
+    /// Will return `Err` if the database connection has been closed or if `function` fails.
+    pub(crate) async fn read_txn<F, R>(&self, function: F) -> Result<R>
+    where
+        F: FnOnce(&ReadTxn) -> Result<R> + 'static + Send,
+        R: Send + 'static,
+    {
+        self.read(move |conn| {
+            let txn = conn.transaction_with_behavior(TransactionBehavior::Deferred)?;
+            match function(&ReadTxn { conn: &txn }) {
+                Ok(value) => {
+                    txn.commit()?;
+                    Ok(value)
+                }
+                Err(err) => {
+                    // Should the rollback fail too, the error of `function` is still the one
+                    // returned.
+                    let _ = txn.rollback();
+                    Err(err)
+                }
+            }
+        })
+        .await
+    }
+
+    /// A snapshot of the queue depth, wait and execution times of the writer and the readers.
+    ///
+    /// A writer queue that is consistently deep, or whose wait times dominate its execution
+    /// times, indicates that the single writer is the bottleneck.
+    #[allow(dead_code)]
+    pub(crate) fn metrics(&self) -> DatabaseMetrics {
+        DatabaseMetrics {
+            writer: self.writer.stats(),
+            reader: self.reader.stats(),
         }
+    }
 
-        // close writer
-        while !self.writer_handle.is_finished() {
-            self.writer_sender.send(Message::Close).ok();
+    /// A snapshot of the health of the writer and reader connection threads.
+    ///
+    /// A panic inside a called function is caught and returned to its caller as
+    /// [`Error::Panicked`], but should a connection thread still die it is respawned with a new
+    /// connection and its `respawns` count is incremented. A connection that could not be
+    /// reopened is reported as not alive.
+    #[allow(dead_code)]
+    pub(crate) fn status(&self) -> PoolStatus {
+        PoolStatus {
+            writer: self.writer_slot.status(),
+            readers: self.reader_slots.iter().map(Slot::status).collect(),
         }
+    }
 
-        Ok(())
+    /// Close the database connections, draining any queued work first.
+    ///
+    /// New calls to [`Database::write`] and [`Database::read`] on any copy of this `Database`
+    /// return [`Error::ConnectionClosed`] once closing has started, but jobs that were already
+    /// queued are still executed. The readers are closed first, then the writer runs
+    /// `PRAGMA wal_checkpoint(TRUNCATE)` so that the WAL is folded back into the database file
+    /// before its connection is closed.
+    ///
+    /// Any following `close` operations performed on copies of this `Database` succeed
+    /// immediately.
+    ///
+    /// # Failure
+    ///
+    /// Will return `Err` if the queued work is not drained within `deadline`, if a connection
+    /// thread panicked, or if the checkpoint or the underlying SQLite close call fails.
+    pub(crate) async fn close(self, deadline: Duration) -> Result<()> {
+        self.closed.store(true, Ordering::Release);
+
+        let reader_handles: Vec<_> = self.reader_slots.iter().filter_map(Slot::take).collect();
+        let writer_handle = self.writer_slot.take();
+        let reader_sender = self.reader.sender().clone();
+        let writer_sender = self.writer.sender().clone();
+
+        let drain = tokio::task::spawn_blocking(move || {
+            // Every reader takes exactly one `Close`, which is queued behind any pending reads.
+            for _ in &reader_handles {
+                reader_sender.send(Message::Close).ok();
+            }
+            let mut result = Ok(());
+            for reader_handle in reader_handles {
+                result = result.and(join(reader_handle));
+            }
+
+            if let Some(writer_handle) = writer_handle {
+                writer_sender.send(Message::Close).ok();
+                result = result.and(join(writer_handle));
+            }
+
+            result
+        });
+
+        match tokio::time::timeout(deadline, drain).await {
+            Ok(result) => result.expect(BUG_TEXT),
+            Err(_) => Err(Error::Other(anyhow!(
+                "timed out after {deadline:?} waiting for queued database work to drain"
+            ))),
+        }
     }
 }
 
+/// Wait for a connection thread to exit and return the result of closing its connection.
+fn join(handle: ConnectionHandle) -> Result<()> {
+    handle
+        .join()
+        .map_err(|_| Error::Other(anyhow!("database connection thread panicked")))?
+        .map_err(Error::Rusqlite)
+}
+
 impl Debug for Database {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("Connection").finish()
     }
 }
 
-async fn start<F, G>(
-    open_writer: F,
-    open_reader: Arc<G>,
-    readers: usize,
-) -> rusqlite::Result<Database>
-where
-    F: FnOnce() -> rusqlite::Result<rusqlite::Connection> + Send + 'static,
-    G: Fn() -> rusqlite::Result<rusqlite::Connection> + Send + Sync + 'static,
-{
-    let (writer_sender, writer_receiver) = crossbeam_channel::bounded::<Message>(MESSAGE_BOUND);
-    let (writer_result_sender, writer_result_receiver) = oneshot::channel();
-
-    let writer_handle = thread::spawn(move || {
-        let mut conn = match open_writer() {
-            Ok(c) => c,
-            Err(e) => {
-                let _ = writer_result_sender.send(Err(e));
-                return;
+/// The group commit settings of the writer, taken from [`DatabaseConfig`].
+#[derive(Clone, Copy, Debug)]
+struct Batching {
+    window: Duration,
+    limit: usize,
+}
+
+/// Execute messages on `conn` until a `Message::Close` is received or every sender has been
+/// dropped, then close the connection. If `checkpoint` is set the WAL is checkpointed and
+/// truncated first.
+fn serve(
+    mut conn: rusqlite::Connection,
+    receiver: &Receiver<Message>,
+    checkpoint: bool,
+    batching: Batching,
+) -> rusqlite::Result<()> {
+    let mut next = None;
+    loop {
+        let message = match next.take() {
+            Some(message) => message,
+            None => match receiver.recv() {
+                Ok(message) => message,
+                Err(_) => break,
+            },
+        };
+
+        match message {
+            Message::Execute(f) => f(&mut conn),
+            Message::Batch(f) => next = execute_batch(&mut conn, f, receiver, batching),
+            Message::Close => break,
+        }
+    }
+
+    if checkpoint {
+        conn.query_row("PRAGMA wal_checkpoint(TRUNCATE);", [], |_| Ok(()))?;
+    }
+
+    conn.close().map_err(|(_, err)| err)
+}
+
+/// Execute `first` and any further `Message::Batch` jobs received within the batching window (up
+/// to the batching limit) in a single transaction, commit it once and then deliver every result.
+///
+/// Returns the first non-batch message received while collecting the group, which must be
+/// handled next to preserve ordering.
+fn execute_batch(
+    conn: &mut rusqlite::Connection,
+    first: BatchFn,
+    receiver: &Receiver<Message>,
+    batching: Batching,
+) -> Option<Message> {
+    let deadline = Instant::now() + batching.window;
+
+    let mut txn = match conn.transaction_with_behavior(TransactionBehavior::Immediate) {
+        Ok(txn) => txn,
+        Err(err) => {
+            first(Err(err))(Ok(()));
+            return None;
+        }
+    };
+
+    let mut completions = vec![first(Ok(&mut txn))];
+    let mut next = None;
+    while completions.len() < batching.limit {
+        match receiver.recv_deadline(deadline) {
+            Ok(Message::Batch(f)) => completions.push(f(Ok(&mut txn))),
+            Ok(message) => {
+                next = Some(message);
+                break;
+            }
+            Err(_) => break,
+        }
+    }
+
+    let committed = txn.commit();
+    for completion in completions {
+        completion(committed.as_ref().map(|_| ()));
+    }
+
+    next
+}
+
+async fn start(
+    open_writer: OpenFn,
+    open_reader: OpenFn,
+    config: &DatabaseConfig,
+) -> rusqlite::Result<Database> {
+    let batching = Batching {
+        window: config.batch_window(),
+        limit: config.batch_limit,
+    };
+    let closed = Arc::new(AtomicBool::new(false));
+
+    let (writer_sender, writer_receiver) =
+        crossbeam_channel::bounded::<Message>(config.writer_bound);
+    let writer_slot = Slot::default();
+    Worker {
+        open: open_writer,
+        receiver: writer_receiver,
+        checkpoint: true,
+        batching,
+        slot: writer_slot.clone(),
+        closed: closed.clone(),
+    }
+    .start()
+    .await?;
+
+    let (reader_sender, reader_receiver) =
+        crossbeam_channel::bounded::<Message>(config.reader_bound);
+    let mut reader_slots = Vec::with_capacity(config.readers);
+    for _ in 0..config.readers {
+        let reader_slot = Slot::default();
+        Worker {
+            open: open_reader.clone(),
+            receiver: reader_receiver.clone(),
+            checkpoint: false,
+            batching,
+            slot: reader_slot.clone(),
+            closed: closed.clone(),
+        }
+        .start()
+        .await?;
+        reader_slots.push(reader_slot);
+    }
+
+    Ok(Database {
+        writer: Queue::new(
+            writer_sender,
+            config.writer_bound,
+            config.acquire_timeout_duration(),
+            closed.clone(),
+        ),
+        reader: Queue::new(
+            reader_sender,
+            config.reader_bound,
+            config.acquire_timeout_duration(),
+            closed.clone(),
+        ),
+        writer_slot,
+        reader_slots: Arc::new(reader_slots),
+        closed,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::entity::user::User;
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn write_txn_commits_on_ok() -> anyhow::Result<()> {
+        let database = Database::open_in_memory(DatabaseConfig::default().readers(1)).await?;
+
+        let mut user = User::new(Uuid::new_v4());
+        let user = database
+            .write_txn(move |txn| {
+                user.upsert(txn)?;
+                Ok(user)
+            })
+            .await?;
+
+        let retrieved = database
+            .read_txn(move |txn| Ok(User::retrieve(txn, &user.id)?))
+            .await?;
+        assert_eq!(retrieved, Some(user));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn write_txn_rolls_back_on_err() -> anyhow::Result<()> {
+        let database = Database::open_in_memory(DatabaseConfig::default().readers(1)).await?;
+
+        let user = User::new(Uuid::new_v4());
+        let mut user_move = user.clone();
+        let result = database
+            .write_txn(move |txn| {
+                user_move.upsert(txn)?;
+                Err::<(), _>(Error::Other(anyhow::anyhow!("abort")))
+            })
+            .await;
+        assert!(matches!(result, Err(Error::Other(_))));
+
+        let retrieved = database
+            .read_txn(move |txn| Ok(User::retrieve(txn, &user.id)?))
+            .await?;
+        assert_eq!(retrieved, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn close_drains_queued_writes() -> anyhow::Result<()> {
+        let database = Database::open_in_memory(DatabaseConfig::default().readers(1)).await?;
+
+        let user = User::new(Uuid::new_v4());
+        let mut user_move = user.clone();
+        let pending = tokio::spawn({
+            let database = database.clone();
+            async move {
+                database
+                    .write_txn(move |txn| {
+                        std::thread::sleep(Duration::from_millis(50));
+                        user_move.upsert(txn)?;
+                        Ok(())
+                    })
+                    .await
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        database.clone().close(Duration::from_secs(5)).await?;
+        pending.await??;
+
+        assert!(matches!(
+            database.read(|_| Ok(())).await,
+            Err(Error::ConnectionClosed)
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn write_batched_isolates_failures() -> anyhow::Result<()> {
+        let database = Database::open_in_memory(DatabaseConfig::default().readers(1)).await?;
+
+        let users = (0..3)
+            .map(|_| User::new(Uuid::new_v4()))
+            .collect::<Vec<_>>();
+
+        // Hold the writer so that the batched writes queue up and form a single group.
+        let blocker = tokio::spawn({
+            let database = database.clone();
+            async move {
+                database
+                    .write(|_| {
+                        std::thread::sleep(Duration::from_millis(50));
+                        Ok(())
+                    })
+                    .await
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let writes = users
+            .iter()
+            .cloned()
+            .enumerate()
+            .map(|(i, mut user)| {
+                let database = database.clone();
+                tokio::spawn(async move {
+                    database
+                        .write_batched(move |txn| {
+                            user.upsert(txn)?;
+                            match i {
+                                1 => Err(Error::Other(anyhow::anyhow!("abort"))),
+                                _ => Ok(()),
+                            }
+                        })
+                        .await
+                })
+            })
+            .collect::<Vec<_>>();
+        blocker.await??;
+
+        let mut results = Vec::new();
+        for write in writes {
+            results.push(write.await?);
+        }
+        assert!(results[0].is_ok());
+        assert!(matches!(results[1], Err(Error::Other(_))));
+        assert!(results[2].is_ok());
+
+        let ids = users.iter().map(|user| user.id).collect::<Vec<_>>();
+        let retrieved = database
+            .read_txn(move |txn| Ok(User::retrieve_many(txn, &ids)?))
+            .await?;
+        assert_eq!(retrieved.len(), 2);
+        assert!(!retrieved.contains(&users[1]));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn open_applies_connection_config() -> anyhow::Result<()> {
+        let database = Database::open_in_memory(
+            DatabaseConfig::default().readers(1).reader(
+                ConnectionConfig::reader()
+                    .cache_size(-1024)
+                    .init(|conn| conn.pragma_update(None, "analysis_limit", 7)),
+            ),
+        )
+        .await?;
+
+        let (cache_size, analysis_limit) = database
+            .read(|conn| {
+                Ok((
+                    conn.pragma_query_value(None, "cache_size", |row| row.get::<_, i64>(0))?,
+                    conn.pragma_query_value(None, "analysis_limit", |row| row.get::<_, i64>(0))?,
+                ))
+            })
+            .await?;
+        assert_eq!(cache_size, -1024);
+        assert_eq!(analysis_limit, 7);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn zero_readers_or_bound_is_rejected() {
+        for config in [
+            DatabaseConfig::default().readers(0),
+            DatabaseConfig::default().readers(1).bounds(0, 1),
+            DatabaseConfig::default().readers(1).bounds(1, 0),
+        ] {
+            assert!(matches!(
+                Database::open_in_memory(config).await,
+                Err(Error::Other(_))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn full_queue_fails_fast_when_overloaded() -> anyhow::Result<()> {
+        let database = Database::open_in_memory(
+            DatabaseConfig::default()
+                .readers(1)
+                .bounds(1, 1)
+                .acquire_timeout(Some(Duration::ZERO)),
+        )
+        .await?;
+
+        // The first write occupies the writer and the second fills the single queue slot.
+        let mut queued = Vec::new();
+        for _ in 0..2 {
+            let database = database.clone();
+            queued.push(tokio::spawn(async move {
+                database
+                    .write(|_| {
+                        std::thread::sleep(Duration::from_millis(50));
+                        Ok(())
+                    })
+                    .await
+            }));
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        assert_eq!(database.metrics().writer.depth, 1);
+        assert!(matches!(
+            database.write(|_| Ok(())).await,
+            Err(Error::Overloaded)
+        ));
+
+        for write in queued {
+            write.await??;
+        }
+
+        let metrics = database.metrics().writer;
+        assert_eq!(metrics.depth, 0);
+        assert_eq!(metrics.rejected, 1);
+        assert!(metrics.execute_max >= Duration::from_millis(50));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn read_with_timeout_interrupts_running_job() -> anyhow::Result<()> {
+        let database = Database::open_in_memory(DatabaseConfig::default().readers(1)).await?;
+
+        let started = Instant::now();
+        let result = database
+            .read_with_timeout(Duration::from_millis(50), |txn| {
+                Ok(txn.query_row(
+                    "WITH RECURSIVE c(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM c) SELECT MAX(x) FROM c;",
+                    [],
+                    |row| row.get::<_, i64>(0),
+                )?)
+            })
+            .await;
+        assert!(matches!(result, Err(Error::Timeout)));
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        // The interrupted reader is still usable.
+        database
+            .read_txn(|txn| Ok(User::retrieve_all(txn)?))
+            .await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn write_with_timeout_skips_queued_job() -> anyhow::Result<()> {
+        let database = Database::open_in_memory(DatabaseConfig::default().readers(1)).await?;
+
+        let blocker = tokio::spawn({
+            let database = database.clone();
+            async move {
+                database
+                    .write(|_| {
+                        std::thread::sleep(Duration::from_millis(100));
+                        Ok(())
+                    })
+                    .await
             }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let user = User::new(Uuid::new_v4());
+        let mut user_move = user.clone();
+        let result = database
+            .write_with_timeout(Duration::from_millis(10), move |txn| {
+                user_move.upsert(txn)?;
+                Ok(())
+            })
+            .await;
+        assert!(matches!(result, Err(Error::Timeout)));
+        blocker.await??;
+
+        let retrieved = database
+            .read_txn(move |txn| Ok(User::retrieve(txn, &user.id)?))
+            .await?;
+        assert_eq!(retrieved, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn panicking_write_is_rolled_back() -> anyhow::Result<()> {
+        let database = Database::open_in_memory(DatabaseConfig::default().readers(1)).await?;
+
+        let user = User::new(Uuid::new_v4());
+        let mut user_move = user.clone();
+        let result: Result<()> = database
+            .write_txn(move |txn| {
+                user_move.upsert(txn)?;
+                panic!("boom");
+            })
+            .await;
+        assert!(matches!(result, Err(Error::Panicked(message)) if message == "boom"));
+
+        let retrieved = database
+            .read_txn(move |txn| Ok(User::retrieve(txn, &user.id)?))
+            .await?;
+        assert_eq!(retrieved, None);
+
+        let status = database.status();
+        assert!(status.healthy());
+        assert_eq!(status.writer.respawns, 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dead_reader_is_respawned() -> anyhow::Result<()> {
+        let database = Database::open_in_memory(DatabaseConfig::default().readers(1)).await?;
+
+        // Kill the reader thread by panicking outside of any caught call.
+        let killed = database
+            .reader
+            .sender()
+            .send(Message::Execute(Box::new(|_| panic!("reader died"))));
+        assert!(killed.is_ok());
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while database.status().readers[0].respawns == 0 {
+            assert!(Instant::now() < deadline, "reader was not respawned");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let count = database
+            .read_txn(|txn| {
+                Ok(txn.query_row("SELECT COUNT(*) FROM users", [], |row| row.get::<_, i64>(0))?)
+            })
+            .await?;
+        assert_eq!(count, 0);
+        assert!(database.status().healthy());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn backup_to_and_restore_round_trip() -> anyhow::Result<()> {
+        let source = Database::open_in_memory(DatabaseConfig::default().readers(1)).await?;
+        let mut user = User::new(Uuid::new_v4());
+        let user = source
+            .write_txn(move |txn| {
+                user.upsert(txn)?;
+                Ok(user)
+            })
+            .await?;
+
+        let path = std::env::temp_dir().join(format!("backup-{}.sqlite", Uuid::new_v4()));
+        let (progress_sender, progress_receiver) = std::sync::mpsc::channel();
+        source
+            .backup_to(&path, move |progress| {
+                let _ = progress_sender.send(progress.remaining);
+            })
+            .await?;
+        assert_eq!(progress_receiver.iter().last(), Some(0));
+
+        let target_path = std::env::temp_dir().join(format!("restore-{}.sqlite", Uuid::new_v4()));
+        let restored = Database::restore(&path, &target_path, |_| {}).await;
+        std::fs::remove_file(&path)?;
+        restored?;
+
+        let target = Database::open(&target_path, DatabaseConfig::default().readers(1)).await?;
+        let retrieved = target
+            .read_txn(move |txn| Ok(User::retrieve(txn, &user.id)?))
+            .await;
+        let status = target.migration_status().await;
+        target.close(Duration::from_secs(5)).await?;
+        std::fs::remove_file(&target_path)?;
+        assert_eq!(retrieved?, Some(user));
+        let status = status?;
+        assert_eq!(status.current, status.latest);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn restore_rejects_a_backup_from_a_newer_release() -> anyhow::Result<()> {
+        let path = std::env::temp_dir().join(format!("backup-{}.sqlite", Uuid::new_v4()));
+        rusqlite::Connection::open(&path)?.pragma_update(None, "user_version", 1000)?;
+
+        let target_path = std::env::temp_dir().join(format!("restore-{}.sqlite", Uuid::new_v4()));
+        let restored = Database::restore(&path, &target_path, |_| {}).await;
+        std::fs::remove_file(&path)?;
+        assert!(matches!(restored, Err(Error::Other(_))));
+        // Nothing is left behind, so that the restore is tried again at the next start.
+        assert!(!target_path.exists());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn snapshot_is_a_database_file() -> anyhow::Result<()> {
+        let database = Database::open_in_memory(DatabaseConfig::default().readers(1)).await?;
+
+        let snapshot = database.snapshot().await?;
+        assert!(snapshot.starts_with(b"SQLite format 3\0"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn migrate_to_rolls_back_and_reapplies() -> anyhow::Result<()> {
+        let database = Database::open_in_memory(DatabaseConfig::default().readers(1)).await?;
+
+        let status = database.migration_status().await?;
+        assert_eq!(status.current, status.latest);
+        assert_eq!(status.pending().count(), 0);
+
+        database.migrate_to(0).await?;
+        let status = database.migration_status().await?;
+        assert_eq!(status.current, 0);
+        assert_eq!(status.pending().count(), status.latest);
+        let tables = database
+            .read(|conn| {
+                Ok(conn.query_row(
+                    "SELECT COUNT(*) FROM sqlite_schema WHERE type = 'table'",
+                    [],
+                    |row| row.get::<_, i64>(0),
+                )?)
+            })
+            .await?;
+        assert_eq!(tables, 0);
+
+        database.migrate_to(status.latest).await?;
+        assert_eq!(database.migration_status().await?.current, status.latest);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn migrate_dry_run_reports_schema_diff() -> anyhow::Result<()> {
+        let database =
+            Database::open_in_memory(DatabaseConfig::default().readers(1).migrate(false)).await?;
+        let latest = database.migration_status().await?.latest;
+
+        let dry_run = database.migrate_dry_run(latest).await?;
+        assert_eq!((dry_run.from, dry_run.to), (0, latest));
+        assert!(dry_run.changes.iter().any(|change| matches!(
+            change,
+            SchemaChange::Added { kind, name, .. } if kind == "table" && name == "users"
+        )));
+
+        // The database itself is left untouched.
+        assert_eq!(database.migration_status().await?.current, 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn validate_schema_accepts_entities() -> anyhow::Result<()> {
+        let database = Database::open_in_memory(DatabaseConfig::default().readers(1)).await?;
+
+        assert!(database
+            .validate_schema(crate::entity::SCHEMAS)
+            .await?
+            .is_empty());
+
+        // Columns out of field order still work, so they are only a warning.
+        let mut columns = User::SCHEMA.columns.to_vec();
+        columns.reverse();
+        let reversed: &'static [EntitySchema] = Box::leak(Box::new([EntitySchema {
+            columns: Box::leak(columns.into_boxed_slice()),
+            ..User::SCHEMA
+        }]));
+        let warnings = database.validate_schema(reversed).await?;
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].starts_with("User (users): columns `id, "));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn validate_schema_reports_mismatches() -> anyhow::Result<()> {
+        const MISMATCHED: &[EntitySchema] = &[EntitySchema {
+            entity: "IdentityUser",
+            table: "identitys_users",
+            columns: &[
+                Column {
+                    name: "id",
+                    affinity: Affinity::Text,
+                    nullable: false,
+                    primary_key: true,
+                    unique: false,
+                    index: false,
+                    references: None,
+                    check: None,
+                },
+                Column {
+                    name: "created",
+                    affinity: Affinity::Text,
+                    nullable: false,
+                    primary_key: false,
+                    unique: false,
+                    index: false,
+                    references: None,
+                    check: None,
+                },
+            ],
+        }];
+        let database = Database::open_in_memory(DatabaseConfig::default().readers(1)).await?;
+
+        let message = match database.validate_schema(MISMATCHED).await {
+            Err(Error::Other(err)) => err.to_string(),
+            result => panic!("expected a schema mismatch, got {result:?}"),
         };
+        assert!(
+            message.contains("column `user_id` is `NOT NULL` without a default but has no field")
+        );
+        assert!(message
+            .contains("column `id` is declared `BLOB` (Blob) but its field is stored as Text"));
+        assert!(message.contains("column `created` does not exist"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn generate_migration_matches_entities() -> anyhow::Result<()> {
+        assert_eq!(generate_migration(crate::entity::SCHEMAS)?, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn generate_migration_creates_and_alters_tables() -> anyhow::Result<()> {
+        const fn column(name: &'static str, affinity: Affinity, nullable: bool) -> Column {
+            Column {
+                name,
+                affinity,
+                nullable,
+                primary_key: false,
+                unique: false,
+                index: false,
+                references: None,
+                check: None,
+            }
+        }
+        fn roles() -> Vec<String> {
+            vec!["admin".to_string(), "member".to_string()]
+        }
+        const CHANGED: &[EntitySchema] = &[
+            EntitySchema {
+                entity: "Team",
+                table: "teams",
+                columns: &[
+                    Column {
+                        primary_key: true,
+                        ..column("id", Affinity::Blob, false)
+                    },
+                    Column {
+                        unique: true,
+                        ..column("name", Affinity::Text, false)
+                    },
+                    Column {
+                        index: true,
+                        references: Some(Reference {
+                            table: "users",
+                            column: "id",
+                        }),
+                        ..column("owner_id", Affinity::Blob, false)
+                    },
+                    Column {
+                        check: Some(Check::Json),
+                        ..column("settings", Affinity::Text, false)
+                    },
+                ],
+            },
+            EntitySchema {
+                entity: "User",
+                table: "users",
+                columns: &[
+                    Column {
+                        primary_key: true,
+                        ..column("id", Affinity::Blob, false)
+                    },
+                    Column {
+                        index: true,
+                        ..column("nickname", Affinity::Text, true)
+                    },
+                    Column {
+                        check: Some(Check::OneOf(roles)),
+                        ..column("role", Affinity::Text, true)
+                    },
+                ],
+            },
+        ];
+
+        let migration = generate_migration(CHANGED)?.unwrap();
+        assert_eq!(migration.version, migration::migrations().len() + 1);
+        assert_eq!(
+            migration.up,
+            [
+                "CREATE TABLE teams (\n    id BLOB PRIMARY KEY NOT NULL,\n    name TEXT NOT NULL,\n    \
+                 owner_id BLOB NOT NULL,\n    settings TEXT NOT NULL CHECK (json_valid(settings)),\n    \
+                 FOREIGN KEY (owner_id) REFERENCES users(id)\n) WITHOUT ROWID,\nSTRICT;",
+                "CREATE UNIQUE INDEX teams_name_idx ON teams (name);",
+                "CREATE INDEX teams_owner_id_idx ON teams (owner_id);",
+                "ALTER TABLE users ADD COLUMN nickname TEXT;",
+                "ALTER TABLE users ADD COLUMN role TEXT CHECK (role IN ('admin', 'member'));",
+                "CREATE INDEX users_nickname_idx ON users (nickname);",
+            ]
+        );
+
+        let database = Database::open_in_memory(DatabaseConfig::default().readers(1)).await?;
+        let (up, down) = (migration.up.join("\n"), migration.down.join("\n"));
+        database
+            .write(move |conn| Ok(conn.execute_batch(&up)?))
+            .await?;
+        database.validate_schema(CHANGED).await?;
+        database
+            .write(move |conn| Ok(conn.execute_batch(&down)?))
+            .await?;
+        database.validate_schema(crate::entity::SCHEMAS).await?;
 
-        if let Err(_e) = writer_result_sender.send(Ok(())) {
-            return;
+        // A `NOT NULL` column cannot be added to the rows of an existing table.
+        const NOT_NULL: &[EntitySchema] = &[EntitySchema {
+            entity: "User",
+            table: "users",
+            columns: &[column("nickname", Affinity::Text, false)],
+        }];
+        assert!(generate_migration(NOT_NULL).is_err());
+
+        Ok(())
+    }
+
+    mod derive {
+        use crate::database::{Database, DatabaseConfig, WriteTxn};
+        use anyhow::Result;
+        use entity_macro::ToSql;
+        use rusqlite::{params, params_from_iter, Connection};
+        use schemars::JsonSchema;
+        use serde::{Deserialize, Serialize};
+        use uuid::Uuid;
+
+        /// Stored in `identitys_users`, with renamed and reordered columns.
+        #[derive(Clone, Debug, PartialEq, ToSql)]
+        #[entity(table = "identitys_users")]
+        struct Link {
+            #[entity(column = "user_id")]
+            owner: Uuid,
+            #[entity(primary_key, column = "id")]
+            key: Uuid,
+            #[entity(skip)]
+            cached: Option<String>,
         }
 
-        while let Ok(message) = writer_receiver.recv() {
-            match message {
-                Message::Execute(f) => f(&mut conn),
-                Message::Close => {
-                    let result = conn.close();
+        #[tokio::test]
+        async fn attributes_name_columns_and_skip_fields() -> Result<()> {
+            let database = Database::open_in_memory(DatabaseConfig::default().readers(1)).await?;
+            database.validate_schema(&[Link::SCHEMA]).await?;
 
-                    match result {
-                        Ok(_) => {
-                            break;
+            let mut user = crate::entity::user::User::new(Uuid::new_v4());
+            let link = Link::new(user.id, Uuid::new_v4());
+            let link_move = link.clone();
+            database
+                .write_txn(move |txn| {
+                    user.upsert(txn)?;
+                    link_move.upsert(txn)?;
+                    Ok(())
+                })
+                .await?;
+
+            let retrieved = database
+                .read_txn(move |txn| Ok(Link::retrieve(txn, &link.key)?))
+                .await?;
+            assert_eq!(retrieved, Some(link));
+
+            Ok(())
+        }
+
+        #[tokio::test]
+        async fn relations_navigate_in_batches() -> Result<()> {
+            use crate::entity::{identity_user::IdentityUser, user::User};
+
+            let database = Database::open_in_memory(DatabaseConfig::default().readers(1)).await?;
+
+            let mut users = [User::new(Uuid::new_v4()), User::new(Uuid::new_v4())];
+            let mut identity_users = [
+                IdentityUser::new(Uuid::new_v4(), users[0].id),
+                IdentityUser::new(Uuid::new_v4(), users[0].id),
+                IdentityUser::new(Uuid::new_v4(), users[1].id),
+            ];
+            // Inserting sets the timestamps, so keep the inserted entities to compare with.
+            let (users, identity_users) = database
+                .write_txn(move |txn| {
+                    for user in &mut users {
+                        user.insert(txn)?;
+                    }
+                    for identity_user in &mut identity_users {
+                        identity_user.insert(txn)?;
+                    }
+                    Ok((users, identity_users))
+                })
+                .await?;
+
+            database
+                .read_txn(move |txn| {
+                    assert_eq!(identity_users[2].user(txn)?, users[1]);
+                    assert_eq!(users[0].identity_users(txn)?.len(), 2);
+
+                    let children = User::identity_users_for(txn, &users)?;
+                    assert_eq!(children[&users[0].id].len(), 2);
+                    assert_eq!(children[&users[1].id], vec![identity_users[2].clone()]);
+
+                    let parents = IdentityUser::user_for(txn, &identity_users)?;
+                    assert_eq!(parents.len(), 2);
+                    assert_eq!(parents[&users[0].id], users[0]);
+                    Ok(())
+                })
+                .await?;
+
+            Ok(())
+        }
+
+        #[tokio::test]
+        async fn query_filters_orders_and_paginates() -> Result<()> {
+            use crate::database::{EntityError, MAX_PARAMETERS};
+            use crate::entity::identity_user::{IdentityUser, IdentityUserColumn};
+            use crate::entity::user::User;
+
+            let database = Database::open_in_memory(DatabaseConfig::default().readers(1)).await?;
+
+            let mut users = [User::new(Uuid::new_v4()), User::new(Uuid::new_v4())];
+            let mut identity_users = (0..5)
+                .map(|i| IdentityUser::new(Uuid::new_v4(), users[i % 2].id))
+                .collect::<Vec<_>>();
+            // Inserting sets the timestamps, so keep the inserted entities to compare with.
+            let (users, mut identity_users) = database
+                .write_txn(move |txn| {
+                    for user in &mut users {
+                        user.insert(txn)?;
+                    }
+                    for identity_user in &mut identity_users {
+                        identity_user.insert(txn)?;
+                    }
+                    Ok((users, identity_users))
+                })
+                .await?;
+
+            database
+                .read_txn(move |txn| {
+                    let owned =
+                        || IdentityUser::query().filter(IdentityUserColumn::UserId.eq(users[0].id));
+                    assert_eq!(owned().count(txn)?, 3);
+                    assert_eq!(
+                        owned()
+                            .filter(IdentityUserColumn::Id.ne(identity_users[0].id))
+                            .fetch(txn)?
+                            .len(),
+                        2
+                    );
+
+                    // Pages in descending primary key order return every row once.
+                    identity_users.sort_by_key(|identity_user| std::cmp::Reverse(identity_user.id));
+                    let mut paged = Vec::new();
+                    let mut cursor = None;
+                    loop {
+                        let mut query = IdentityUser::query()
+                            .order_by(IdentityUserColumn::Id.desc())
+                            .limit(2);
+                        if let Some(cursor) = cursor {
+                            query = query.after(cursor);
                         }
-                        Err((_c, _e)) => {
+                        let page = query.page(txn)?;
+                        paged.extend(page.items);
+                        cursor = page.next;
+                        if cursor.is_none() {
                             break;
                         }
                     }
-                }
+                    assert_eq!(paged, identity_users);
+
+                    // Mixed directions fall back to comparing column by column.
+                    let first = IdentityUser::query()
+                        .order_by(IdentityUserColumn::UserId.asc())
+                        .order_by(IdentityUserColumn::Id.desc())
+                        .limit(4)
+                        .page(txn)?;
+                    let rest = IdentityUser::query()
+                        .order_by(IdentityUserColumn::UserId.asc())
+                        .order_by(IdentityUserColumn::Id.desc())
+                        .after(first.next.clone().unwrap())
+                        .fetch(txn)?;
+                    assert_eq!(rest.len(), 1);
+                    assert!(!first.items.contains(&rest[0]));
+
+                    assert!(matches!(
+                        IdentityUser::query()
+                            .after("not a cursor".to_string().into())
+                            .fetch(txn),
+                        Err(EntityError::InvalidCursor { .. })
+                    ));
+                    // A cursor of a query with another ordering, even with as many columns, is
+                    // rejected rather than returning the wrong rows.
+                    assert!(matches!(
+                        IdentityUser::query()
+                            .order_by(IdentityUserColumn::UserId.desc())
+                            .order_by(IdentityUserColumn::Id.desc())
+                            .after(first.next.clone().unwrap())
+                            .fetch(txn),
+                        Err(EntityError::InvalidCursor { .. })
+                    ));
+
+                    // More values than a statement can take fail before reaching SQLite.
+                    let ids = (0..MAX_PARAMETERS)
+                        .map(|_| Uuid::new_v4())
+                        .collect::<Vec<_>>();
+                    assert!(matches!(
+                        IdentityUser::query()
+                            .filter(IdentityUserColumn::Id.is_in(ids))
+                            .fetch(txn),
+                        Err(EntityError::TooManyParameters {
+                            count: MAX_PARAMETERS,
+                            ..
+                        })
+                    ));
+                    Ok(())
+                })
+                .await?;
+
+            Ok(())
+        }
+
+        #[tokio::test]
+        async fn bulk_methods_chunk_past_the_parameter_limit() -> Result<()> {
+            use crate::database::{EntityError, MAX_PARAMETERS};
+            use crate::entity::user::User;
+
+            let database = Database::open_in_memory(DatabaseConfig::default().readers(1)).await?;
+
+            let users = (0..MAX_PARAMETERS + 10)
+                .map(|_| User::new(Uuid::new_v4()))
+                .collect::<Vec<_>>();
+            let ids = users.iter().map(|user| user.id).collect::<Vec<_>>();
+            let users_move = users.clone();
+            database
+                .write_txn(move |txn| {
+                    assert_eq!(User::insert_many(txn, &users_move)?, users_move.len());
+                    assert!(matches!(
+                        User::insert_many(txn, &users_move[..1]),
+                        Err(EntityError::Conflict { .. })
+                    ));
+                    Ok(())
+                })
+                .await?;
+
+            let ids_move = ids.clone();
+            database
+                .read_txn(move |txn| {
+                    assert_eq!(User::retrieve_many(txn, &ids_move)?.len(), ids_move.len());
+
+                    let mut streamed = 0;
+                    User::stream_all(txn, |_| {
+                        streamed += 1;
+                        Ok::<_, EntityError>(())
+                    })?;
+                    assert_eq!(streamed, ids_move.len());
+
+                    // The callback stops the stream at its first error.
+                    let mut seen = 0;
+                    let result = User::stream_all(txn, |_| {
+                        seen += 1;
+                        Err(EntityError::not_found("User", "stop"))
+                    });
+                    assert!(matches!(result, Err(EntityError::NotFound { .. })));
+                    assert_eq!(seen, 1);
+                    Ok(())
+                })
+                .await?;
+
+            database
+                .write_txn(move |txn| {
+                    assert_eq!(User::delete_many(txn, &ids)?, ids.len());
+                    Ok(())
+                })
+                .await?;
+
+            Ok(())
+        }
+
+        #[tokio::test]
+        async fn timestamps_and_soft_delete_track_changes() -> Result<()> {
+            use crate::database::EntityError;
+            use crate::entity::identity_user::IdentityUser;
+            use crate::entity::user::{User, UserColumn};
+
+            let database = Database::open_in_memory(DatabaseConfig::default().readers(1)).await?;
+
+            let mut user = User::new(Uuid::new_v4());
+            let mut identity_user = IdentityUser::new(Uuid::new_v4(), user.id);
+            database
+                .write_txn(move |txn| {
+                    user.insert(txn)?;
+                    identity_user.insert(txn)?;
+                    assert_eq!(user.created_at, user.updated_at);
+                    let created_at = user.created_at;
+
+                    // An update keeps the stored `created_at`.
+                    user.created_at = chrono::DateTime::default();
+                    user.update(txn)?;
+                    assert_eq!(user.created_at, created_at);
+                    assert!(user.updated_at > created_at);
+                    user.upsert(txn)?;
+                    assert_eq!(user.created_at, created_at);
+                    assert_eq!(User::retrieve(txn, &user.id)?, Some(user.clone()));
+
+                    User::delete(txn, &user.id)?;
+                    assert_eq!(User::retrieve(txn, &user.id)?, None);
+                    assert!(!User::exists(txn, &user.id)?);
+                    assert!(User::retrieve_all(txn)?.is_empty());
+                    assert!(matches!(
+                        identity_user.user(txn),
+                        Err(EntityError::NotFound { .. })
+                    ));
+                    assert!(matches!(
+                        user.update(txn),
+                        Err(EntityError::NotFound { .. })
+                    ));
+                    // An upsert does not restore the row either.
+                    let mut upserted = user.clone();
+                    assert!(matches!(
+                        upserted.upsert(txn),
+                        Err(EntityError::NotFound { .. })
+                    ));
+
+                    assert_eq!(User::query().count(txn)?, 0);
+                    let deleted = User::query()
+                        .with_deleted()
+                        .filter(UserColumn::DeletedAt.is_not_null())
+                        .fetch(txn)?;
+                    assert_eq!(deleted.len(), 1);
+                    assert_eq!(Some(deleted[0].updated_at), deleted[0].deleted_at);
+
+                    User::restore(txn, &user.id)?;
+                    assert!(matches!(
+                        User::restore(txn, &user.id),
+                        Err(EntityError::NotFound { .. })
+                    ));
+                    assert_eq!(identity_user.user(txn)?.id, user.id);
+
+                    IdentityUser::delete(txn, &identity_user.id)?;
+                    User::delete(txn, &user.id)?;
+                    User::purge(txn, &user.id)?;
+                    assert_eq!(User::query().with_deleted().count(txn)?, 0);
+
+                    // Imported records keep their timestamps.
+                    let mut imported = User::new(Uuid::new_v4());
+                    imported.created_at = "2024-01-01T00:00:00Z".parse().unwrap();
+                    imported.updated_at = imported.created_at;
+                    User::insert_many(txn, std::slice::from_ref(&imported))?;
+                    assert_eq!(User::retrieve(txn, &imported.id)?, Some(imported));
+                    Ok(())
+                })
+                .await?;
+
+            Ok(())
+        }
+
+        #[tokio::test]
+        async fn version_rejects_stale_writes() -> Result<()> {
+            use crate::database::EntityError;
+            use crate::entity::user::User;
+
+            let database = Database::open_in_memory(DatabaseConfig::default().readers(1)).await?;
+
+            let mut user = User::new(Uuid::new_v4());
+            database
+                .write_txn(move |txn| {
+                    user.insert(txn)?;
+                    assert_eq!(user.version, 1);
+                    let mut stale = user.clone();
+
+                    user.update(txn)?;
+                    assert_eq!(user.version, 2);
+                    user.upsert(txn)?;
+                    assert_eq!(user.version, 3);
+                    assert_eq!(User::retrieve(txn, &user.id)?, Some(user.clone()));
+
+                    // The copy read before the updates can no longer be written.
+                    assert!(matches!(
+                        stale.update(txn),
+                        Err(EntityError::VersionConflict { .. })
+                    ));
+                    assert!(matches!(
+                        stale.upsert(txn),
+                        Err(EntityError::VersionConflict { .. })
+                    ));
+                    assert_eq!(User::retrieve(txn, &user.id)?.unwrap().version, 3);
+
+                    // Soft deleting is an update too.
+                    User::delete(txn, &user.id)?;
+                    User::restore(txn, &user.id)?;
+                    assert_eq!(User::retrieve(txn, &user.id)?.unwrap().version, 5);
+                    assert!(matches!(
+                        user.update(txn),
+                        Err(EntityError::VersionConflict { .. })
+                    ));
+                    Ok(())
+                })
+                .await?;
+
+            Ok(())
+        }
+
+        /// The key of `Setting`, stored as the `Uuid` it wraps.
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, ToSql)]
+        #[entity(transparent)]
+        struct SettingId(Uuid);
+
+        impl std::fmt::Display for SettingId {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                self.0.fmt(f)
             }
         }
-    });
-    writer_result_receiver.await.expect(BUG_TEXT)?;
-
-    let (reader_sender, reader_receiver) = crossbeam_channel::bounded::<Message>(MESSAGE_BOUND);
-    let mut reader_handles = Vec::with_capacity(readers);
-    for _ in 0..readers {
-        let (reader_result_sender, reader_result_receiver) = oneshot::channel();
-        let reader_receiver = reader_receiver.clone();
-        let open_reader = open_reader.clone();
-        reader_handles.push(thread::spawn(move || {
-            let mut conn = match open_reader() {
-                Ok(c) => c,
-                Err(e) => {
-                    let _ = reader_result_sender.send(Err(e));
-                    return;
+
+        #[derive(Clone, Copy, Debug, PartialEq, Deserialize, JsonSchema, Serialize)]
+        #[serde(rename_all = "snake_case")]
+        enum Theme {
+            Light,
+            /// Documented variants are listed differently by the schema.
+            HighContrast,
+        }
+
+        #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+        struct Layout {
+            columns: u8,
+            pinned: Vec<String>,
+        }
+
+        /// Stored in a `settings` table created from the generated migration.
+        #[derive(Clone, Debug, PartialEq, ToSql)]
+        #[entity(table = "settings")]
+        struct Setting {
+            id: SettingId,
+            #[entity(enum_text)]
+            theme: Theme,
+            #[entity(enum_text)]
+            fallback: Option<Theme>,
+            #[entity(json)]
+            layout: Layout,
+            #[entity(json)]
+            tags: Option<Vec<String>>,
+        }
+
+        #[tokio::test]
+        async fn converted_fields_round_trip_and_are_checked() -> Result<()> {
+            use crate::database::{generate_migration, EntitySchema};
+
+            const SCHEMAS: &[EntitySchema] = &[Setting::SCHEMA];
+            let migration = generate_migration(SCHEMAS)?.unwrap();
+            assert!(migration.up[0]
+                .contains("theme TEXT NOT NULL CHECK (theme IN ('light', 'high_contrast'))"));
+            assert!(migration.up[0].contains("fallback TEXT CHECK"));
+            assert!(migration.up[0].contains("layout TEXT NOT NULL CHECK (json_valid(layout))"));
+
+            let database = Database::open_in_memory(DatabaseConfig::default().readers(1)).await?;
+            let up = migration.up.join("\n");
+            database
+                .write(move |conn| Ok(conn.execute_batch(&up)?))
+                .await?;
+            database.validate_schema(SCHEMAS).await?;
+
+            let setting = Setting::new(
+                SettingId(Uuid::new_v4()),
+                Theme::HighContrast,
+                None,
+                Layout {
+                    columns: 2,
+                    pinned: vec!["inbox".to_string()],
+                },
+                Some(vec!["beta".to_string()]),
+            );
+            let others = [
+                Setting::new(
+                    SettingId(Uuid::new_v4()),
+                    Theme::Light,
+                    Some(Theme::HighContrast),
+                    setting.layout.clone(),
+                    None,
+                ),
+                Setting::new(
+                    SettingId(Uuid::new_v4()),
+                    Theme::Light,
+                    None,
+                    setting.layout.clone(),
+                    None,
+                ),
+            ];
+            let setting_move = setting.clone();
+            database
+                .write_txn(move |txn| {
+                    setting_move.insert(txn)?;
+                    Setting::insert_many(txn, &others)?;
+
+                    // The stored values are checked even when written by hand.
+                    assert!(txn
+                        .execute(
+                            "UPDATE settings SET theme = 'dark' WHERE id = ?",
+                            [setting_move.id],
+                        )
+                        .is_err());
+                    assert!(txn
+                        .execute(
+                            "UPDATE settings SET layout = '{' WHERE id = ?",
+                            [setting_move.id],
+                        )
+                        .is_err());
+                    Ok(())
+                })
+                .await?;
+
+            database
+                .read_txn(move |txn| {
+                    assert_eq!(Setting::retrieve(txn, &setting.id)?, Some(setting.clone()));
+                    assert_eq!(
+                        Setting::query()
+                            .filter(SettingColumn::Theme.eq(Theme::Light))
+                            .count(txn)?,
+                        2
+                    );
+                    assert_eq!(
+                        Setting::query()
+                            .filter(SettingColumn::Fallback.is_null())
+                            .filter(SettingColumn::Id.ne(setting.id))
+                            .fetch(txn)?
+                            .len(),
+                        1
+                    );
+                    Ok(())
+                })
+                .await?;
+
+            Ok(())
+        }
+
+        /// Stored in `identitys_users`, with hooks that check the user and count commits.
+        #[derive(Clone, Debug, PartialEq, ToSql)]
+        #[entity(table = "identitys_users", hooks)]
+        struct CheckedLink {
+            id: Uuid,
+            user_id: Uuid,
+        }
+
+        static COMMITTED_LINKS: std::sync::atomic::AtomicUsize =
+            std::sync::atomic::AtomicUsize::new(0);
+
+        impl crate::database::EntityHooks for CheckedLink {
+            fn validate(&self) -> std::result::Result<(), Vec<crate::database::FieldError>> {
+                match self.user_id.is_nil() {
+                    true => Err(vec![crate::database::FieldError::new(
+                        "user_id",
+                        "must not be nil",
+                    )]),
+                    false => Ok(()),
                 }
-            };
+            }
 
-            if let Err(_e) = reader_result_sender.send(Ok(())) {
-                return;
+            fn before_insert(
+                &self,
+                txn: &WriteTxn,
+            ) -> std::result::Result<(), crate::database::EntityError> {
+                match crate::entity::user::User::exists(txn, &self.user_id)? {
+                    true => Ok(()),
+                    false => Err(crate::database::EntityError::invalid(
+                        "CheckedLink",
+                        vec![crate::database::FieldError::new(
+                            "user_id",
+                            "is not an existing user",
+                        )],
+                    )),
+                }
+            }
+
+            fn after_insert(
+                &self,
+                txn: &WriteTxn,
+            ) -> std::result::Result<(), crate::database::EntityError> {
+                txn.after_commit(|| {
+                    COMMITTED_LINKS.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                });
+                Ok(())
             }
+        }
 
-            while let Ok(message) = reader_receiver.recv() {
-                match message {
-                    Message::Execute(f) => f(&mut conn),
-                    Message::Close => {
-                        let result = conn.close();
+        #[tokio::test]
+        async fn hooks_validate_and_run_after_commit() -> Result<()> {
+            use crate::database::{EntityError, Error};
+            use std::sync::atomic::Ordering;
 
-                        match result {
-                            Ok(_) => {
-                                break;
-                            }
-                            Err((_c, _e)) => {
-                                break;
-                            }
+            let database = Database::open_in_memory(DatabaseConfig::default().readers(1)).await?;
+
+            let mut user = crate::entity::user::User::new(Uuid::new_v4());
+            let user_id = user.id;
+            database
+                .write_txn(move |txn| {
+                    let invalid = CheckedLink::new(Uuid::new_v4(), Uuid::nil());
+                    match invalid.insert(txn) {
+                        Err(EntityError::Invalid { errors, .. }) => {
+                            assert_eq!(errors[0].field, "user_id")
                         }
+                        result => panic!("expected a validation error, got {result:?}"),
                     }
+                    let dangling = CheckedLink::new(Uuid::new_v4(), Uuid::new_v4());
+                    assert!(matches!(
+                        dangling.insert(txn),
+                        Err(EntityError::Invalid { .. })
+                    ));
+
+                    user.insert(txn)?;
+                    CheckedLink::new(Uuid::new_v4(), user.id).insert(txn)?;
+                    assert_eq!(COMMITTED_LINKS.load(Ordering::SeqCst), 0);
+                    Ok(())
+                })
+                .await?;
+            assert_eq!(COMMITTED_LINKS.load(Ordering::SeqCst), 1);
+
+            // A rolled back write never calls its after commit functions.
+            let result = database
+                .write_txn(move |txn| {
+                    CheckedLink::new(Uuid::new_v4(), user_id).insert(txn)?;
+                    Err::<(), _>(Error::Other(anyhow::anyhow!("roll back")))
+                })
+                .await;
+            assert!(result.is_err());
+            assert_eq!(COMMITTED_LINKS.load(Ordering::SeqCst), 1);
+
+            Ok(())
+        }
+
+        /// Stored in `users`, with hooks that record the callbacks called.
+        #[derive(Clone, Debug, PartialEq, ToSql)]
+        #[entity(table = "users", timestamps, soft_delete, hooks)]
+        struct RecordedUser {
+            id: Uuid,
+            created_at: chrono::DateTime<chrono::Utc>,
+            updated_at: chrono::DateTime<chrono::Utc>,
+            deleted_at: Option<chrono::DateTime<chrono::Utc>>,
+        }
+
+        impl RecordedUser {
+            /// Records the callback, and whether the write had set the timestamps by then.
+            fn record(&self, callback: &'static str) {
+                let mut callbacks = RECORDED_CALLBACKS.lock().unwrap();
+                callbacks.push(callback);
+                if self.updated_at == chrono::DateTime::UNIX_EPOCH {
+                    callbacks.push("unstamped");
                 }
             }
-        }));
-        reader_result_receiver.await.expect(BUG_TEXT)?;
-    }
+        }
 
-    Ok(Database {
-        writer_sender,
-        reader_sender,
-        writer_handle: Arc::new(writer_handle),
-        reader_handles: Arc::new(reader_handles),
-    })
+        static RECORDED_CALLBACKS: std::sync::Mutex<Vec<&str>> = std::sync::Mutex::new(Vec::new());
+
+        impl crate::database::EntityHooks for RecordedUser {
+            fn before_insert(
+                &self,
+                _txn: &WriteTxn,
+            ) -> std::result::Result<(), crate::database::EntityError> {
+                self.record("before_insert");
+                Ok(())
+            }
+
+            fn before_update(
+                &self,
+                _txn: &WriteTxn,
+            ) -> std::result::Result<(), crate::database::EntityError> {
+                self.record("before_update");
+                Ok(())
+            }
+        }
+
+        #[tokio::test]
+        async fn hooks_of_an_upsert_follow_the_stored_row() -> Result<()> {
+            use crate::database::EntityError;
+
+            let database = Database::open_in_memory(DatabaseConfig::default().readers(1)).await?;
+
+            database
+                .write_txn(move |txn| {
+                    // The hooks see the timestamps of the write, not those of the struct.
+                    let mut user = RecordedUser::new(Uuid::new_v4());
+                    user.updated_at = chrono::DateTime::UNIX_EPOCH;
+                    user.upsert(txn)?;
+                    user.updated_at = chrono::DateTime::UNIX_EPOCH;
+                    user.upsert(txn)?;
+                    user.updated_at = chrono::DateTime::UNIX_EPOCH;
+                    user.update(txn)?;
+                    // Deletes call no hooks, and upserting the soft deleted row fails before
+                    // calling any.
+                    RecordedUser::delete(txn, &user.id)?;
+                    assert!(matches!(
+                        user.upsert(txn),
+                        Err(EntityError::NotFound { .. })
+                    ));
+                    Ok(())
+                })
+                .await?;
+            assert_eq!(
+                *RECORDED_CALLBACKS.lock().unwrap(),
+                ["before_insert", "before_update", "before_update"]
+            );
+
+            Ok(())
+        }
+
+        #[tokio::test]
+        async fn generated_methods_return_typed_errors() -> Result<()> {
+            use crate::database::EntityError;
+            use crate::entity::user::User;
+
+            let database = Database::open_in_memory(DatabaseConfig::default().readers(1)).await?;
+
+            let mut user = User::new(Uuid::new_v4());
+            let missing = Link::new(Uuid::new_v4(), Uuid::new_v4());
+            database
+                .write_txn(move |txn| {
+                    assert!(matches!(
+                        missing.update(txn),
+                        Err(EntityError::NotFound { .. })
+                    ));
+                    assert!(matches!(
+                        missing.insert(txn),
+                        Err(EntityError::ForeignKey { .. })
+                    ));
+
+                    user.insert(txn)?;
+                    assert!(matches!(
+                        user.insert(txn),
+                        Err(EntityError::Conflict { .. })
+                    ));
+                    user.upsert(txn)?;
+                    user.update(txn)?;
+
+                    let link = Link::new(user.id, Uuid::new_v4());
+                    link.insert(txn)?;
+                    // The user is still referenced by the link.
+                    assert!(matches!(
+                        User::purge(txn, &user.id),
+                        Err(EntityError::ForeignKey { .. })
+                    ));
+
+                    assert!(Link::exists(txn, &link.key)?);
+                    assert_eq!(Link::delete_many(txn, &[link.key, Uuid::new_v4()])?, 1);
+                    assert!(!Link::exists(txn, &link.key)?);
+                    User::delete(txn, &user.id)?;
+                    assert!(matches!(
+                        User::delete(txn, &user.id),
+                        Err(EntityError::NotFound { .. })
+                    ));
+                    Ok(())
+                })
+                .await?;
+
+            Ok(())
+        }
+    }
 }
@@ -0,0 +1,181 @@
+use anyhow::anyhow;
+use rusqlite::backup::{Backup, Progress, StepResult};
+use rusqlite::{ErrorCode, OpenFlags, TransactionBehavior};
+use std::path::{Path, PathBuf};
+use std::{os::raw::c_int, thread, time::Duration};
+
+use super::{
+    migration::{migrations, user_version},
+    Database, Error, Result, MIGRATIONS,
+};
+
+/// The number of pages copied per backup step. Progress is reported after every step.
+const PAGES_PER_STEP: c_int = 256;
+
+/// How long to pause when a backup step finds a database busy or locked, and how many
+/// consecutive times that may happen before giving up.
+const BUSY_PAUSE: Duration = Duration::from_millis(10);
+const BUSY_RETRIES: usize = 100;
+
+impl Database {
+    /// Write a consistent copy of the database to `path` using SQLite's online backup API,
+    /// replacing any existing file.
+    ///
+    /// The copy is taken on a reader connection inside a read transaction, so writes continue
+    /// while it runs and the copy reflects the database as of the start of the backup.
+    /// `progress` is called after every step with the number of pages remaining.
+    ///
+    /// # Failure
+    ///
+    /// Will return `Err` if the database connection has been closed, if `path` cannot be opened
+    /// or if the backup fails.
+    #[allow(dead_code)]
+    pub(crate) async fn backup_to<P, F>(&self, path: P, progress: F) -> Result<()>
+    where
+        P: AsRef<Path>,
+        F: FnMut(Progress) + Send + 'static,
+    {
+        let path = path.as_ref().to_owned();
+
+        self.read(move |conn| {
+            let mut destination = rusqlite::Connection::open(path)?;
+            copy_snapshot(conn, &mut destination, progress)?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Take a consistent copy of the database as the bytes of a SQLite database file, for
+    /// example to attach to a support bundle or to seed a test.
+    ///
+    /// # Failure
+    ///
+    /// Will return `Err` under the same conditions as [`Database::backup_to`], or if the
+    /// temporary file the copy is written to cannot be read.
+    #[allow(dead_code)]
+    pub(crate) async fn snapshot(&self) -> Result<Vec<u8>> {
+        let path = std::env::temp_dir().join(format!("snapshot-{}.sqlite", uuid::Uuid::new_v4()));
+
+        let bytes = match self.backup_to(&path, |_| {}).await {
+            Ok(()) => std::fs::read(&path).map_err(|err| Error::Other(err.into())),
+            Err(err) => Err(err),
+        };
+        let _ = std::fs::remove_file(&path);
+
+        bytes
+    }
+
+    /// Restore the backup at `backup` as the database file at `path`, then apply any migrations
+    /// the backup is missing.
+    ///
+    /// This is done before the database is opened, so no connection can observe it part way
+    /// through. The backup is restored to a temporary file next to `path` that is only renamed
+    /// to `path` once it is complete and migrated to the latest version. `progress` is called
+    /// after every step with the number of pages remaining.
+    ///
+    /// # Failure
+    ///
+    /// Will return `Err` if either file cannot be opened, if the restore fails, if the migrations
+    /// cannot be applied or if the backup is from a release with migrations this one does not
+    /// know.
+    pub(crate) async fn restore<P, Q, F>(backup: P, path: Q, progress: F) -> Result<()>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+        F: FnMut(Progress) + Send + 'static,
+    {
+        let backup = backup.as_ref().to_owned();
+        let path = path.as_ref().to_owned();
+        let mut restoring = path.clone().into_os_string();
+        restoring.push(".restoring");
+        let restoring = PathBuf::from(restoring);
+
+        tokio::task::spawn_blocking(move || {
+            let restored = restore(&backup, &restoring, progress);
+            match restored {
+                Ok(()) => {
+                    std::fs::rename(&restoring, &path).map_err(|err| Error::Other(err.into()))
+                }
+                Err(err) => {
+                    let _ = std::fs::remove_file(&restoring);
+                    Err(err)
+                }
+            }
+        })
+        .await
+        .map_err(|err| Error::Other(err.into()))?
+    }
+}
+
+/// Copy the backup at `backup` to a new database at `path` and migrate it to the latest version.
+fn restore(backup: &Path, path: &Path, progress: impl FnMut(Progress)) -> Result<()> {
+    let source = rusqlite::Connection::open_with_flags(backup, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let latest = migrations().len();
+    let version = user_version(&source)?;
+    if version > latest {
+        return Err(Error::Other(anyhow!(
+            "backup is at migration version {version}, beyond the latest version {latest}"
+        )));
+    }
+
+    let mut destination = rusqlite::Connection::open(path)?;
+    copy(&source, &mut destination, progress)?;
+    MIGRATIONS.to_latest(&mut destination)?;
+
+    let version = user_version(&destination)?;
+    if version != latest {
+        return Err(Error::Other(anyhow!(
+            "restored database is at migration version {version}, not {latest}"
+        )));
+    }
+    Ok(())
+}
+
+/// Copy the database of `conn` to `to` from a single read snapshot, calling `progress` after
+/// each step.
+pub(super) fn copy_snapshot(
+    conn: &mut rusqlite::Connection,
+    to: &mut rusqlite::Connection,
+    progress: impl FnMut(Progress),
+) -> rusqlite::Result<()> {
+    let txn = conn.transaction_with_behavior(TransactionBehavior::Deferred)?;
+    // A deferred transaction only takes its snapshot on the first read, so read now to keep
+    // every step of the copy on the same snapshot.
+    txn.query_row("SELECT COUNT(*) FROM sqlite_schema", [], |_| Ok(()))?;
+
+    copy(&txn, to, progress)?;
+    txn.commit()
+}
+
+/// Copy every page of `from` to `to`, calling `progress` after each step.
+fn copy(
+    from: &rusqlite::Connection,
+    to: &mut rusqlite::Connection,
+    mut progress: impl FnMut(Progress),
+) -> rusqlite::Result<()> {
+    let backup = Backup::new(from, to)?;
+
+    let mut busy = 0;
+    loop {
+        let step = backup.step(PAGES_PER_STEP)?;
+        progress(backup.progress());
+
+        match step {
+            StepResult::Done => return Ok(()),
+            StepResult::More => busy = 0,
+            _ => {
+                busy += 1;
+                if busy > BUSY_RETRIES {
+                    return Err(rusqlite::Error::SqliteFailure(
+                        rusqlite::ffi::Error {
+                            code: ErrorCode::DatabaseBusy,
+                            extended_code: rusqlite::ffi::SQLITE_BUSY,
+                        },
+                        Some("backup did not complete: database busy".to_string()),
+                    ));
+                }
+                thread::sleep(BUSY_PAUSE);
+            }
+        }
+    }
+}
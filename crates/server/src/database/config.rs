@@ -0,0 +1,302 @@
+use serde::Deserialize;
+use std::{
+    fmt::{self, Debug},
+    sync::Arc,
+    time::Duration,
+};
+
+/// A hook run against every new connection after its pragmas have been applied, for example
+/// to register application-defined SQL functions.
+pub type InitFn = Arc<dyn Fn(&rusqlite::Connection) -> rusqlite::Result<()> + Send + Sync>;
+
+/// Settings for a [`Database`](super::Database), deserializable from the `database` section of
+/// the server configuration.
+///
+/// Fields missing from the configuration take their value from [`DatabaseConfig::default`].
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct DatabaseConfig {
+    /// The number of read-only connections (and threads) in the reader pool.
+    pub readers: usize,
+    /// The settings applied to the single writer connection.
+    pub writer: ConnectionConfig,
+    /// The settings applied to each reader connection.
+    pub reader: ConnectionConfig,
+    /// The number of jobs that can be queued for the writer before callers wait.
+    pub writer_bound: usize,
+    /// The number of jobs that can be queued for the readers before callers wait.
+    pub reader_bound: usize,
+    /// How long the writer waits for further batched writes to join a group commit.
+    pub batch_window_ms: u64,
+    /// The maximum number of batched writes in a single group commit.
+    pub batch_limit: usize,
+    /// How long a caller waits for space in a full queue before failing with
+    /// [`Error::Overloaded`](super::Error::Overloaded). `None` waits indefinitely and `0` fails
+    /// immediately.
+    pub acquire_timeout_ms: Option<u64>,
+    /// Whether pending migrations are applied to the writer when the database is opened. Turn
+    /// this off to inspect or roll back migrations with `server migrate`.
+    pub migrate: bool,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            readers: num_cpus::get(),
+            writer: ConnectionConfig::writer(),
+            reader: ConnectionConfig::reader(),
+            writer_bound: 100,
+            reader_bound: 100,
+            batch_window_ms: 2,
+            batch_limit: 64,
+            acquire_timeout_ms: None,
+            migrate: true,
+        }
+    }
+}
+
+impl DatabaseConfig {
+    /// Sets the number of reader connections.
+    pub fn readers(mut self, readers: usize) -> Self {
+        self.readers = readers;
+        self
+    }
+
+    /// Sets the settings applied to the writer connection.
+    pub fn writer(mut self, writer: ConnectionConfig) -> Self {
+        self.writer = writer;
+        self
+    }
+
+    /// Sets the settings applied to each reader connection.
+    pub fn reader(mut self, reader: ConnectionConfig) -> Self {
+        self.reader = reader;
+        self
+    }
+
+    /// Sets the writer and reader channel bounds.
+    pub fn bounds(mut self, writer_bound: usize, reader_bound: usize) -> Self {
+        self.writer_bound = writer_bound;
+        self.reader_bound = reader_bound;
+        self
+    }
+
+    /// Sets the group commit window and the maximum number of writes in a group.
+    pub fn batching(mut self, window: Duration, limit: usize) -> Self {
+        self.batch_window_ms = window.as_millis() as u64;
+        self.batch_limit = limit;
+        self
+    }
+
+    /// Sets how long a caller waits for space in a full queue, or `None` to wait indefinitely.
+    pub fn acquire_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.acquire_timeout_ms = timeout.map(|timeout| timeout.as_millis() as u64);
+        self
+    }
+
+    /// Sets whether pending migrations are applied when the database is opened.
+    pub fn migrate(mut self, migrate: bool) -> Self {
+        self.migrate = migrate;
+        self
+    }
+
+    /// Checks that a database can be opened with these settings.
+    ///
+    /// # Failure
+    ///
+    /// Will return `Err` if there are no readers, as every read would fail, or if the writer or
+    /// reader bound is `0`, as no job could ever be queued.
+    pub(crate) fn validate(&self) -> anyhow::Result<()> {
+        if self.readers == 0 {
+            anyhow::bail!("database.readers must be at least 1");
+        }
+        if self.writer_bound == 0 {
+            anyhow::bail!("database.writer_bound must be at least 1");
+        }
+        if self.reader_bound == 0 {
+            anyhow::bail!("database.reader_bound must be at least 1");
+        }
+        Ok(())
+    }
+
+    pub(crate) fn batch_window(&self) -> Duration {
+        Duration::from_millis(self.batch_window_ms)
+    }
+
+    pub(crate) fn acquire_timeout_duration(&self) -> Option<Duration> {
+        self.acquire_timeout_ms.map(Duration::from_millis)
+    }
+}
+
+/// The pragmas and settings applied to a connection when it is opened.
+///
+/// A pragma set to `None` is left at the SQLite default.
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct ConnectionConfig {
+    /// `PRAGMA journal_mode`, e.g. `WAL`. This is persistent so only the writer needs it.
+    pub journal_mode: Option<String>,
+    /// `PRAGMA synchronous`, e.g. `NORMAL`.
+    pub synchronous: Option<String>,
+    /// `PRAGMA temp_store`, e.g. `MEMORY`.
+    pub temp_store: Option<String>,
+    /// `PRAGMA cache_size`. Negative values are a size in KiB, positive values are pages.
+    pub cache_size: Option<i64>,
+    /// `PRAGMA foreign_keys`.
+    pub foreign_keys: Option<bool>,
+    /// `PRAGMA busy_timeout` in milliseconds.
+    pub busy_timeout: Option<u64>,
+    /// `PRAGMA mmap_size` in bytes.
+    pub mmap_size: Option<i64>,
+    /// The capacity of the prepared statement cache used by `prepare_cached`.
+    pub statement_cache_capacity: usize,
+    /// A hook run after the pragmas have been applied. This cannot be set from configuration.
+    #[serde(skip)]
+    pub init: Option<InitFn>,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        Self {
+            journal_mode: None,
+            synchronous: None,
+            temp_store: None,
+            cache_size: None,
+            foreign_keys: None,
+            busy_timeout: None,
+            mmap_size: None,
+            statement_cache_capacity: 1024,
+            init: None,
+        }
+    }
+}
+
+impl ConnectionConfig {
+    /// The default settings for the writer connection.
+    pub fn writer() -> Self {
+        Self {
+            journal_mode: Some("WAL".to_string()),
+            synchronous: Some("NORMAL".to_string()),
+            temp_store: Some("MEMORY".to_string()),
+            cache_size: Some(-67108864),
+            foreign_keys: Some(true),
+            busy_timeout: Some(5000),
+            ..Default::default()
+        }
+    }
+
+    /// The default settings for a reader connection.
+    pub fn reader() -> Self {
+        Self {
+            temp_store: Some("MEMORY".to_string()),
+            cache_size: Some(-67108864),
+            busy_timeout: Some(5000),
+            ..Default::default()
+        }
+    }
+
+    /// Sets `PRAGMA journal_mode`.
+    pub fn journal_mode(mut self, journal_mode: impl Into<String>) -> Self {
+        self.journal_mode = Some(journal_mode.into());
+        self
+    }
+
+    /// Sets `PRAGMA synchronous`.
+    pub fn synchronous(mut self, synchronous: impl Into<String>) -> Self {
+        self.synchronous = Some(synchronous.into());
+        self
+    }
+
+    /// Sets `PRAGMA temp_store`.
+    pub fn temp_store(mut self, temp_store: impl Into<String>) -> Self {
+        self.temp_store = Some(temp_store.into());
+        self
+    }
+
+    /// Sets `PRAGMA cache_size`.
+    pub fn cache_size(mut self, cache_size: i64) -> Self {
+        self.cache_size = Some(cache_size);
+        self
+    }
+
+    /// Sets `PRAGMA foreign_keys`.
+    pub fn foreign_keys(mut self, foreign_keys: bool) -> Self {
+        self.foreign_keys = Some(foreign_keys);
+        self
+    }
+
+    /// Sets `PRAGMA busy_timeout`.
+    pub fn busy_timeout(mut self, busy_timeout: Duration) -> Self {
+        self.busy_timeout = Some(busy_timeout.as_millis() as u64);
+        self
+    }
+
+    /// Sets `PRAGMA mmap_size`.
+    pub fn mmap_size(mut self, mmap_size: i64) -> Self {
+        self.mmap_size = Some(mmap_size);
+        self
+    }
+
+    /// Sets the capacity of the prepared statement cache.
+    pub fn statement_cache_capacity(mut self, capacity: usize) -> Self {
+        self.statement_cache_capacity = capacity;
+        self
+    }
+
+    /// Sets a hook to run against each new connection.
+    pub fn init<F>(mut self, init: F) -> Self
+    where
+        F: Fn(&rusqlite::Connection) -> rusqlite::Result<()> + Send + Sync + 'static,
+    {
+        self.init = Some(Arc::new(init));
+        self
+    }
+
+    /// Applies these settings to a newly opened connection.
+    pub(crate) fn apply(&self, conn: &rusqlite::Connection) -> rusqlite::Result<()> {
+        conn.set_prepared_statement_cache_capacity(self.statement_cache_capacity);
+
+        if let Some(journal_mode) = &self.journal_mode {
+            conn.pragma_update(None, "journal_mode", journal_mode)?;
+        }
+        if let Some(synchronous) = &self.synchronous {
+            conn.pragma_update(None, "synchronous", synchronous)?;
+        }
+        if let Some(temp_store) = &self.temp_store {
+            conn.pragma_update(None, "temp_store", temp_store)?;
+        }
+        if let Some(cache_size) = self.cache_size {
+            conn.pragma_update(None, "cache_size", cache_size)?;
+        }
+        if let Some(foreign_keys) = self.foreign_keys {
+            conn.pragma_update(None, "foreign_keys", foreign_keys)?;
+        }
+        if let Some(busy_timeout) = self.busy_timeout {
+            conn.pragma_update(None, "busy_timeout", busy_timeout)?;
+        }
+        if let Some(mmap_size) = self.mmap_size {
+            conn.pragma_update(None, "mmap_size", mmap_size)?;
+        }
+
+        match &self.init {
+            Some(init) => init(conn),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Debug for ConnectionConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConnectionConfig")
+            .field("journal_mode", &self.journal_mode)
+            .field("synchronous", &self.synchronous)
+            .field("temp_store", &self.temp_store)
+            .field("cache_size", &self.cache_size)
+            .field("foreign_keys", &self.foreign_keys)
+            .field("busy_timeout", &self.busy_timeout)
+            .field("mmap_size", &self.mmap_size)
+            .field("statement_cache_capacity", &self.statement_cache_capacity)
+            .field("init", &self.init.as_ref().map(|_| "Fn"))
+            .finish()
+    }
+}
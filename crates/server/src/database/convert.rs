@@ -0,0 +1,108 @@
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, Null, ToSqlOutput, ValueRef};
+use rusqlite::ToSql;
+use schemars::schema::{Schema, SchemaObject};
+use schemars::JsonSchema;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// The value of a `#[entity(json)]` field, stored as JSON text. A value that serializes to
+/// `null`, such as `None`, is stored as `NULL`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Json<T>(pub T);
+
+impl<T: Serialize> Json<T> {
+    /// The stored form of `value`, which unlike [`ToSql::to_sql`] does not borrow from it.
+    pub fn to_sql_output(value: &T) -> rusqlite::Result<ToSqlOutput<'static>> {
+        match serde_json::to_value(value).map_err(to_sql_failure)? {
+            serde_json::Value::Null => Ok(ToSqlOutput::from(Null)),
+            value => Ok(ToSqlOutput::from(value.to_string())),
+        }
+    }
+}
+
+impl<T: Serialize> ToSql for Json<T> {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Self::to_sql_output(&self.0)
+    }
+}
+
+impl<T: DeserializeOwned> FromSql for Json<T> {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value {
+            ValueRef::Null => serde_json::from_value(serde_json::Value::Null),
+            ValueRef::Text(text) => serde_json::from_slice(text),
+            _ => return Err(FromSqlError::InvalidType),
+        }
+        .map(Json)
+        .map_err(|err| FromSqlError::Other(Box::new(err)))
+    }
+}
+
+/// The value of a `#[entity(enum_text)]` field, an enum of unit variants stored as the text of
+/// its variant as serialized by serde. `None` is stored as `NULL`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EnumText<T>(pub T);
+
+impl<T: Serialize> EnumText<T> {
+    /// The stored form of `value`, which unlike [`ToSql::to_sql`] does not borrow from it.
+    pub fn to_sql_output(value: &T) -> rusqlite::Result<ToSqlOutput<'static>> {
+        match serde_json::to_value(value).map_err(to_sql_failure)? {
+            serde_json::Value::Null => Ok(ToSqlOutput::from(Null)),
+            serde_json::Value::String(variant) => Ok(ToSqlOutput::from(variant)),
+            value => Err(to_sql_failure(format!(
+                "{value} is not a unit variant of an enum"
+            ))),
+        }
+    }
+}
+
+impl<T: Serialize> ToSql for EnumText<T> {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Self::to_sql_output(&self.0)
+    }
+}
+
+impl<T: DeserializeOwned> FromSql for EnumText<T> {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        let value = match value {
+            ValueRef::Null => serde_json::Value::Null,
+            ValueRef::Text(_) => serde_json::Value::String(value.as_str()?.to_string()),
+            _ => return Err(FromSqlError::InvalidType),
+        };
+        serde_json::from_value(value)
+            .map(EnumText)
+            .map_err(|err| FromSqlError::Other(Box::new(err)))
+    }
+}
+
+fn to_sql_failure(err: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> rusqlite::Error {
+    rusqlite::Error::ToSqlConversionFailure(err.into())
+}
+
+/// The variants of the enum `T` as its `JsonSchema` lists them, which is how serde serializes
+/// them and so how `EnumText` stores them.
+pub fn enum_values<T: JsonSchema>() -> Vec<String> {
+    fn collect(schema: &SchemaObject, values: &mut Vec<String>) {
+        let strings = schema
+            .enum_values
+            .iter()
+            .flatten()
+            .chain(schema.const_value.as_ref())
+            .filter_map(|value| value.as_str().map(str::to_string));
+        values.extend(strings);
+        // Variants with doc comments are listed as one schema each.
+        let variants = schema
+            .subschemas
+            .iter()
+            .flat_map(|subschemas| subschemas.one_of.iter().flatten());
+        for variant in variants {
+            if let Schema::Object(variant) = variant {
+                collect(variant, values);
+            }
+        }
+    }
+
+    let mut values = Vec::new();
+    collect(&schemars::schema_for!(T).schema, &mut values);
+    values
+}
@@ -0,0 +1,239 @@
+use rusqlite::ffi;
+use std::fmt::{self, Display};
+
+use super::{EntitySchema, WriteTxn};
+
+/// An error from a method generated by the `ToSql` derive.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum EntityError {
+    /// No row has the given primary key.
+    NotFound { entity: &'static str, key: String },
+
+    /// A row with the same primary key or unique value already exists.
+    Conflict {
+        entity: &'static str,
+        source: rusqlite::Error,
+    },
+
+    /// A foreign key constraint failed, because a referenced row does not exist or because the
+    /// row is still referenced.
+    ForeignKey {
+        entity: &'static str,
+        source: rusqlite::Error,
+    },
+
+    /// The row has been updated since the entity was read, so it is no longer at its version.
+    VersionConflict {
+        entity: &'static str,
+        key: String,
+        version: String,
+    },
+
+    /// The entity failed validation.
+    Invalid {
+        entity: &'static str,
+        errors: Vec<FieldError>,
+    },
+
+    /// A query was given a cursor that it did not produce.
+    InvalidCursor { entity: &'static str },
+
+    /// A query has more values than the parameters a statement can take.
+    TooManyParameters { entity: &'static str, count: usize },
+
+    /// Any other SQLite error.
+    Rusqlite(rusqlite::Error),
+}
+
+impl EntityError {
+    /// The error for a missing `entity` with primary key `key`.
+    pub fn not_found(entity: &'static str, key: impl Display) -> Self {
+        EntityError::NotFound {
+            entity,
+            key: key.to_string(),
+        }
+    }
+
+    /// The error for an `entity` with primary key `key` that is no longer at `version`.
+    pub fn version_conflict(
+        entity: &'static str,
+        key: impl Display,
+        version: impl Display,
+    ) -> Self {
+        EntityError::VersionConflict {
+            entity,
+            key: key.to_string(),
+            version: version.to_string(),
+        }
+    }
+
+    /// The error for an `entity` whose fields failed validation.
+    pub fn invalid(entity: &'static str, errors: Vec<FieldError>) -> Self {
+        EntityError::Invalid { entity, errors }
+    }
+
+    /// Classify an error from a statement on the table of `entity`.
+    pub(crate) fn from_sqlite(entity: &'static str, err: rusqlite::Error) -> Self {
+        match err.sqlite_error().map(|err| err.extended_code) {
+            Some(ffi::SQLITE_CONSTRAINT_PRIMARYKEY | ffi::SQLITE_CONSTRAINT_UNIQUE) => {
+                EntityError::Conflict {
+                    entity,
+                    source: err,
+                }
+            }
+            Some(ffi::SQLITE_CONSTRAINT_FOREIGNKEY) => EntityError::ForeignKey {
+                entity,
+                source: err,
+            },
+            _ => EntityError::Rusqlite(err),
+        }
+    }
+}
+
+impl Display for EntityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EntityError::NotFound { entity, key } => write!(f, "{entity} {key} not found"),
+            EntityError::Conflict { entity, source } => {
+                write!(f, "{entity} already exists: {source}")
+            }
+            EntityError::ForeignKey { entity, source } => {
+                write!(f, "{entity} violates a foreign key: {source}")
+            }
+            EntityError::VersionConflict {
+                entity,
+                key,
+                version,
+            } => write!(f, "{entity} {key} has been updated since version {version}"),
+            EntityError::Invalid { entity, errors } => {
+                write!(f, "invalid {entity}: ")?;
+                for (i, error) in errors.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{error}")?;
+                }
+                Ok(())
+            }
+            EntityError::InvalidCursor { entity } => write!(f, "invalid {entity} cursor"),
+            EntityError::TooManyParameters { entity, count } => {
+                write!(f, "{entity} query has too many values: {count}")
+            }
+            EntityError::Rusqlite(e) => write!(f, "Rusqlite(\"{e}\")"),
+        }
+    }
+}
+
+impl std::error::Error for EntityError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            EntityError::NotFound { .. }
+            | EntityError::VersionConflict { .. }
+            | EntityError::Invalid { .. }
+            | EntityError::InvalidCursor { .. }
+            | EntityError::TooManyParameters { .. } => None,
+            EntityError::Conflict { source, .. } | EntityError::ForeignKey { source, .. } => {
+                Some(source)
+            }
+            EntityError::Rusqlite(e) => Some(e),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for EntityError {
+    fn from(value: rusqlite::Error) -> Self {
+        EntityError::Rusqlite(value)
+    }
+}
+
+/// Implemented by the `ToSql` derive, giving generic access to the table and primary key of an
+/// entity.
+pub trait Entity: Sized {
+    /// The type of the primary key.
+    type Key: Clone + Eq + std::hash::Hash + rusqlite::ToSql;
+
+    /// The table and columns of the entity.
+    const SCHEMA: EntitySchema;
+
+    /// The column of the primary key.
+    const PRIMARY_KEY: &'static str;
+
+    /// The `deleted_at` column of an entity with `#[entity(soft_delete)]`.
+    const DELETED_AT: Option<&'static str>;
+
+    /// The primary key of this entity.
+    fn key(&self) -> &Self::Key;
+
+    /// Reads a row selected with the columns of `SCHEMA` first, in order.
+    fn from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Self>;
+}
+
+/// A field of an entity that failed validation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FieldError {
+    /// The name of the field.
+    pub field: &'static str,
+    /// Why its value is invalid, e.g. `must not be empty`.
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new(field: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            field,
+            message: message.into(),
+        }
+    }
+}
+
+impl Display for FieldError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.field, self.message)
+    }
+}
+
+/// Validation and lifecycle callbacks of an entity.
+///
+/// The methods generated by the `ToSql` derive for a struct with `#[entity(hooks)]` call these,
+/// so that struct implements the trait itself. Every other struct gets an implementation that
+/// does nothing.
+///
+/// A write first calls `validate`, then sets the timestamps of `#[entity(timestamps)]`, calls
+/// `before_insert` or `before_update`, executes the statement and finally calls `after_insert`
+/// or `after_update`, all in the transaction of the write. An `upsert` calls the insert or the
+/// update callbacks depending on whether the row exists, and fails with
+/// `EntityError::NotFound` before calling any if it has been soft deleted. Any error fails the
+/// write, and rolls back the transaction if it is returned from the closure of `write_txn`.
+///
+/// Deletes are not covered: `delete`, `delete_many`, `restore` and `purge` take identifiers
+/// rather than entities, and call none of these.
+pub trait EntityHooks {
+    /// Check the values of the fields, returning every field that is invalid.
+    fn validate(&self) -> Result<(), Vec<FieldError>> {
+        Ok(())
+    }
+
+    /// Called before the entity is inserted, for checks that need the database such as whether
+    /// a referenced row is usable.
+    fn before_insert(&self, _txn: &WriteTxn) -> Result<(), EntityError> {
+        Ok(())
+    }
+
+    /// Called before the entity is updated.
+    fn before_update(&self, _txn: &WriteTxn) -> Result<(), EntityError> {
+        Ok(())
+    }
+
+    /// Called after the entity has been inserted. Side effects outside the database should be
+    /// registered with [`WriteTxn::after_commit`] so that they only happen once the write is
+    /// committed.
+    fn after_insert(&self, _txn: &WriteTxn) -> Result<(), EntityError> {
+        Ok(())
+    }
+
+    /// Called after the entity has been updated.
+    fn after_update(&self, _txn: &WriteTxn) -> Result<(), EntityError> {
+        Ok(())
+    }
+}
@@ -0,0 +1,256 @@
+use anyhow::anyhow;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use super::migration::migrations;
+use super::{Affinity, Column, EntitySchema, Error, Result, MIGRATIONS};
+
+/// A migration generated by [`generate_migration`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GeneratedMigration {
+    /// The version the database is at once the migration has been applied.
+    pub version: usize,
+    /// The statements of `up.sql`.
+    pub up: Vec<String>,
+    /// The statements of `down.sql`, undoing `up` in reverse order.
+    pub down: Vec<String>,
+}
+
+impl GeneratedMigration {
+    /// Write the migration to a new `{version:02}-{name}` directory in `dir`, returning its path.
+    ///
+    /// # Failure
+    ///
+    /// Will return `Err` if the directory already exists or cannot be written.
+    pub fn write(&self, dir: &Path, name: &str) -> std::io::Result<PathBuf> {
+        let dir = dir.join(format!("{:02}-{name}", self.version));
+        std::fs::create_dir(&dir)?;
+        std::fs::write(dir.join("up.sql"), self.up.join("\n\n") + "\n")?;
+        std::fs::write(dir.join("down.sql"), self.down.join("\n\n") + "\n")?;
+        Ok(dir)
+    }
+}
+
+/// Diff `entities` against the schema the migrations produce and generate the migration that
+/// brings the tables in line with them, or `None` if they already are.
+///
+/// Missing tables are created `WITHOUT ROWID` and `STRICT`, ordered so that the tables their
+/// foreign keys refer to come first. Missing columns are added to existing tables, and missing
+/// `#[entity(index)]` and `#[entity(unique)]` indexes are created. Columns are never dropped or
+/// changed, which [`Database::validate_schema`](super::Database::validate_schema) reports
+/// instead.
+///
+/// # Failure
+///
+/// Will return `Err` if the migrations fail, if the foreign keys of the new tables form a cycle,
+/// or if a primary key or a `NOT NULL` column would have to be added to an existing table, which
+/// SQLite cannot do without rebuilding the table.
+pub(crate) fn generate_migration(entities: &[EntitySchema]) -> Result<Option<GeneratedMigration>> {
+    let mut conn = rusqlite::Connection::open_in_memory()?;
+    MIGRATIONS.to_latest(&mut conn)?;
+
+    let tables = conn
+        .prepare("SELECT name FROM sqlite_schema WHERE type = 'table'")?
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<rusqlite::Result<HashSet<_>>>()?;
+
+    let mut up = Vec::new();
+    let mut down = Vec::new();
+
+    // Create the new tables once the new tables they refer to have been created.
+    let mut pending = entities
+        .iter()
+        .filter(|entity| !tables.contains(entity.table))
+        .collect::<Vec<_>>();
+    while !pending.is_empty() {
+        let Some(next) = pending.iter().position(|entity| {
+            entity
+                .columns
+                .iter()
+                .filter_map(|column| column.references)
+                .all(|reference| {
+                    reference.table == entity.table
+                        || !pending.iter().any(|other| other.table == reference.table)
+                })
+        }) else {
+            return Err(Error::Other(anyhow!(
+                "the foreign keys of {} form a cycle",
+                pending
+                    .iter()
+                    .map(|entity| entity.table)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )));
+        };
+        let entity = pending.remove(next);
+
+        up.push(create_table(entity));
+        down.push(format!("DROP TABLE {};", entity.table));
+        // The indexes are dropped with the table.
+        up.extend(
+            entity
+                .columns
+                .iter()
+                .filter(|column| column.unique || column.index)
+                .map(|column| create_index(entity.table, column)),
+        );
+    }
+
+    for entity in entities
+        .iter()
+        .filter(|entity| tables.contains(entity.table))
+    {
+        let existing = conn
+            .prepare("SELECT name FROM pragma_table_info(?)")?
+            .query_map([entity.table], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<HashSet<_>>>()?;
+
+        for column in entity
+            .columns
+            .iter()
+            .filter(|column| !existing.contains(column.name))
+        {
+            if column.primary_key || !column.nullable {
+                return Err(Error::Other(anyhow!(
+                    "cannot add `NOT NULL` column `{}` to {}, make its field an `Option` or write \
+                     the migration by hand",
+                    column.name,
+                    entity.table
+                )));
+            }
+            let mut definition = column_definition(column);
+            if let Some(reference) = column.references {
+                definition.push_str(&format!(
+                    " REFERENCES {}({})",
+                    reference.table, reference.column
+                ));
+            }
+            up.push(format!(
+                "ALTER TABLE {} ADD COLUMN {definition};",
+                entity.table
+            ));
+            down.push(format!(
+                "ALTER TABLE {} DROP COLUMN {};",
+                entity.table, column.name
+            ));
+        }
+
+        let indexes = indexes(&conn, entity.table)?;
+        for column in entity.columns {
+            let indexed = if column.unique {
+                indexes.iter().any(|(unique, columns)| {
+                    *unique && columns.len() == 1 && columns[0] == column.name
+                })
+            } else {
+                !column.index
+                    || indexes.iter().any(|(_, columns)| {
+                        columns.first().map(String::as_str) == Some(column.name)
+                    })
+            };
+            if !indexed {
+                up.push(create_index(entity.table, column));
+                down.push(format!("DROP INDEX {};", index_name(entity.table, column)));
+            }
+        }
+    }
+
+    if up.is_empty() {
+        return Ok(None);
+    }
+
+    down.reverse();
+    Ok(Some(GeneratedMigration {
+        version: migrations().len() + 1,
+        up,
+        down,
+    }))
+}
+
+/// The type of a column of a `STRICT` table storing values with `affinity`.
+fn strict_type(affinity: Affinity) -> &'static str {
+    match affinity {
+        Affinity::Integer => "INTEGER",
+        Affinity::Real => "REAL",
+        Affinity::Text => "TEXT",
+        Affinity::Blob => "BLOB",
+        Affinity::Numeric => "ANY",
+    }
+}
+
+fn column_definition(column: &Column) -> String {
+    let mut definition = format!("{} {}", column.name, strict_type(column.affinity));
+    if column.primary_key {
+        definition.push_str(" PRIMARY KEY");
+    }
+    if column.primary_key || !column.nullable {
+        definition.push_str(" NOT NULL");
+    }
+    if let Some(check) = column.check {
+        definition.push(' ');
+        definition.push_str(&check.constraint(column.name));
+    }
+    definition
+}
+
+fn create_table(entity: &EntitySchema) -> String {
+    let mut definitions = entity
+        .columns
+        .iter()
+        .map(column_definition)
+        .collect::<Vec<_>>();
+    definitions.extend(entity.columns.iter().filter_map(|column| {
+        column.references.map(|reference| {
+            format!(
+                "FOREIGN KEY ({}) REFERENCES {}({})",
+                column.name, reference.table, reference.column
+            )
+        })
+    }));
+    format!(
+        "CREATE TABLE {} (\n    {}\n) WITHOUT ROWID,\nSTRICT;",
+        entity.table,
+        definitions.join(",\n    ")
+    )
+}
+
+fn index_name(table: &str, column: &Column) -> String {
+    format!("{table}_{}_idx", column.name)
+}
+
+fn create_index(table: &str, column: &Column) -> String {
+    format!(
+        "CREATE {}INDEX {} ON {table} ({});",
+        if column.unique { "UNIQUE " } else { "" },
+        index_name(table, column),
+        column.name
+    )
+}
+
+/// Whether each index of `table` is unique and its columns in order.
+fn indexes(conn: &rusqlite::Connection, table: &str) -> rusqlite::Result<Vec<(bool, Vec<String>)>> {
+    let mut statement = conn.prepare(
+        "SELECT list.name, list.\"unique\", info.name \
+         FROM pragma_index_list(?1) AS list, pragma_index_info(list.name) AS info \
+         ORDER BY list.name, info.seqno",
+    )?;
+    let rows = statement.query_map([table], |row| {
+        Ok((
+            row.get::<_, String>(0)?,
+            row.get::<_, bool>(1)?,
+            row.get::<_, String>(2)?,
+        ))
+    })?;
+
+    let mut indexes = Vec::<(String, bool, Vec<String>)>::new();
+    for row in rows {
+        let (name, unique, column) = row?;
+        match indexes.last_mut() {
+            Some((last, _, columns)) if *last == name => columns.push(column),
+            _ => indexes.push((name, unique, vec![column])),
+        }
+    }
+    Ok(indexes
+        .into_iter()
+        .map(|(_, unique, columns)| (unique, columns))
+        .collect())
+}
@@ -0,0 +1,258 @@
+use anyhow::anyhow;
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+
+use super::{backup::copy_snapshot, Database, Error, Result, MIGRATIONS, MIGRATIONS_DIR};
+
+/// A migration directory in `migrations/`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MigrationInfo {
+    /// The version the database is at once this migration has been applied.
+    pub version: usize,
+    /// The name of the migration directory, e.g. `01-core`.
+    pub name: String,
+    /// Whether the migration has a `down.sql` and so can be rolled back.
+    pub reversible: bool,
+    /// Whether the migration has been applied to the database.
+    pub applied: bool,
+}
+
+/// The applied and pending migrations of a database.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MigrationStatus {
+    /// The version of the database, i.e. the number of migrations applied to it. This may be
+    /// greater than `latest` if the database was migrated by a newer release.
+    pub current: usize,
+    /// The version of the latest migration known to this release.
+    pub latest: usize,
+    /// Every migration known to this release, in order.
+    pub migrations: Vec<MigrationInfo>,
+}
+
+impl MigrationStatus {
+    /// The migrations that have not been applied yet.
+    pub fn pending(&self) -> impl Iterator<Item = &MigrationInfo> {
+        self.migrations
+            .iter()
+            .filter(|migration| !migration.applied)
+    }
+}
+
+impl Display for MigrationStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "version {} of {}", self.current, self.latest)?;
+        for migration in &self.migrations {
+            writeln!(
+                f,
+                "{} {}{}",
+                if migration.applied {
+                    "applied"
+                } else {
+                    "pending"
+                },
+                migration.name,
+                if migration.reversible {
+                    ""
+                } else {
+                    " (no down.sql)"
+                },
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// A difference between two versions of the schema, keyed by the type and name of the object
+/// in `sqlite_schema`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SchemaChange {
+    /// The object was created.
+    Added {
+        kind: String,
+        name: String,
+        sql: String,
+    },
+    /// The object was dropped.
+    Removed {
+        kind: String,
+        name: String,
+        sql: String,
+    },
+    /// The definition of the object changed.
+    Changed {
+        kind: String,
+        name: String,
+        before: String,
+        after: String,
+    },
+}
+
+impl Display for SchemaChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SchemaChange::Added { kind, name, sql } => write!(f, "+ {kind} {name}\n{sql}"),
+            SchemaChange::Removed { kind, name, sql } => write!(f, "- {kind} {name}\n{sql}"),
+            SchemaChange::Changed {
+                kind,
+                name,
+                before,
+                after,
+            } => write!(f, "~ {kind} {name}\n{before}\n=>\n{after}"),
+        }
+    }
+}
+
+/// The outcome of [`Database::migrate_dry_run`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DryRun {
+    /// The version of the database before the migrations.
+    pub from: usize,
+    /// The version the copy was migrated to.
+    pub to: usize,
+    /// The changes the migrations made to the schema of the copy.
+    pub changes: Vec<SchemaChange>,
+}
+
+impl Database {
+    /// The migrations known to this release and which of them have been applied.
+    ///
+    /// # Failure
+    ///
+    /// Will return `Err` if the database connection has been closed or the version cannot be
+    /// read.
+    pub(crate) async fn migration_status(&self) -> Result<MigrationStatus> {
+        self.read(|conn| {
+            let current = user_version(conn)?;
+            let migrations = migrations()
+                .into_iter()
+                .map(|mut migration| {
+                    migration.applied = migration.version <= current;
+                    migration
+                })
+                .collect::<Vec<_>>();
+
+            Ok(MigrationStatus {
+                current,
+                latest: migrations.len(),
+                migrations,
+            })
+        })
+        .await
+    }
+
+    /// Migrate the database up or down to `version`, where `0` is an empty database.
+    ///
+    /// Each migration runs in its own transaction on the writer.
+    ///
+    /// # Failure
+    ///
+    /// Will return `Err` if `version` is beyond the latest migration, if migrating down would
+    /// roll back a migration without a `down.sql` (in which case nothing is rolled back) or if a
+    /// migration fails.
+    pub(crate) async fn migrate_to(&self, version: usize) -> Result<()> {
+        self.write(move |conn| {
+            check_reversible(user_version(conn)?, version)?;
+            MIGRATIONS.to_version(conn, version)?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Apply the migrations up or down to `version` on an in-memory copy of the database and
+    /// report how they change its schema, without modifying the database itself.
+    ///
+    /// # Failure
+    ///
+    /// Will return `Err` under the same conditions as [`Database::migrate_to`], reporting the
+    /// migration that would fail.
+    pub(crate) async fn migrate_dry_run(&self, version: usize) -> Result<DryRun> {
+        self.read(move |conn| {
+            let mut copy = rusqlite::Connection::open_in_memory()?;
+            copy_snapshot(conn, &mut copy, |_| {})?;
+
+            let from = user_version(&copy)?;
+            check_reversible(from, version)?;
+
+            let before = schema(&copy)?;
+            MIGRATIONS.to_version(&mut copy, version)?;
+            let after = schema(&copy)?;
+
+            Ok(DryRun {
+                from,
+                to: version,
+                changes: diff(before, after),
+            })
+        })
+        .await
+    }
+}
+
+/// The migrations in `migrations/`, ordered by version.
+pub(super) fn migrations() -> Vec<MigrationInfo> {
+    let mut migrations = MIGRATIONS_DIR
+        .dirs()
+        .filter_map(|dir| {
+            let name = dir.path().file_name()?.to_str()?;
+            let version = name.split_once('-')?.0.parse().ok()?;
+            Some(MigrationInfo {
+                version,
+                name: name.to_string(),
+                reversible: dir.files().any(|file| file.path().ends_with("down.sql")),
+                applied: false,
+            })
+        })
+        .collect::<Vec<_>>();
+    migrations.sort_by_key(|migration| migration.version);
+    migrations
+}
+
+/// Fail if migrating from `current` down to `target` would need a `down.sql` that does not
+/// exist. `rusqlite_migration` refuses to migrate down through a migration without a down step,
+/// and checking first names that migration in the error.
+fn check_reversible(current: usize, target: usize) -> Result<()> {
+    match migrations().into_iter().find(|migration| {
+        migration.version > target && migration.version <= current && !migration.reversible
+    }) {
+        Some(migration) => Err(Error::Other(anyhow!(
+            "cannot migrate down to version {target}: {} has no down.sql",
+            migration.name
+        ))),
+        None => Ok(()),
+    }
+}
+
+pub(super) fn user_version(conn: &rusqlite::Connection) -> rusqlite::Result<usize> {
+    conn.query_row("PRAGMA user_version", [], |row| row.get(0))
+}
+
+/// The definition of every object in `sqlite_schema`, keyed by type and name.
+fn schema(conn: &rusqlite::Connection) -> rusqlite::Result<BTreeMap<(String, String), String>> {
+    let mut statement = conn.prepare(
+        "SELECT type, name, sql FROM sqlite_schema WHERE sql IS NOT NULL ORDER BY type, name",
+    )?;
+    let rows = statement.query_map([], |row| Ok(((row.get(0)?, row.get(1)?), row.get(2)?)))?;
+    rows.collect()
+}
+
+fn diff(
+    mut before: BTreeMap<(String, String), String>,
+    after: BTreeMap<(String, String), String>,
+) -> Vec<SchemaChange> {
+    let mut changes = Vec::new();
+    for ((kind, name), sql) in after {
+        match before.remove(&(kind.clone(), name.clone())) {
+            None => changes.push(SchemaChange::Added { kind, name, sql }),
+            Some(previous) if previous != sql => changes.push(SchemaChange::Changed {
+                kind,
+                name,
+                before: previous,
+                after: sql,
+            }),
+            Some(_) => {}
+        }
+    }
+    for ((kind, name), sql) in before {
+        changes.push(SchemaChange::Removed { kind, name, sql });
+    }
+    changes
+}
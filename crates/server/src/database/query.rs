@@ -0,0 +1,608 @@
+use rusqlite::types::Value;
+use rusqlite::{params_from_iter, Connection, ToSql};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Debug, Display, Write};
+use std::marker::PhantomData;
+
+use super::{Entity, EntityError, MAX_PARAMETERS};
+
+/// A stored field of the entity `E` whose values are of type `T`.
+///
+/// The `ToSql` derive generates one for each stored field as a constant of `{Entity}Column`,
+/// named after the field in Pascal case, e.g. `UserColumn::Id`.
+pub struct QueryColumn<E, T> {
+    name: &'static str,
+    to_sql: fn(T) -> Box<dyn ToSql + Send>,
+    marker: PhantomData<fn() -> E>,
+}
+
+impl<E, T> Clone for QueryColumn<E, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<E, T> Copy for QueryColumn<E, T> {}
+
+impl<E, T> Debug for QueryColumn<E, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("QueryColumn").field(&self.name).finish()
+    }
+}
+
+fn boxed<T: ToSql + Send + 'static>(value: T) -> Box<dyn ToSql + Send> {
+    Box::new(value)
+}
+
+impl<E, T: ToSql + Send + 'static> QueryColumn<E, T> {
+    pub const fn new(name: &'static str) -> Self {
+        Self::with(name, boxed::<T>)
+    }
+}
+
+impl<E, T> QueryColumn<E, T> {
+    /// A column whose values are bound with `to_sql`, for fields such as `#[entity(json)]` that
+    /// are stored converted.
+    pub const fn with(name: &'static str, to_sql: fn(T) -> Box<dyn ToSql + Send>) -> Self {
+        Self {
+            name,
+            to_sql,
+            marker: PhantomData,
+        }
+    }
+
+    /// The name of the column.
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Order by this column, smallest first.
+    pub fn asc(self) -> Order<E> {
+        Order::new(self.name, false)
+    }
+
+    /// Order by this column, largest first.
+    pub fn desc(self) -> Order<E> {
+        Order::new(self.name, true)
+    }
+}
+
+impl<E, T> QueryColumn<E, T> {
+    fn compare(self, operator: &str, value: T) -> Filter<E> {
+        Filter::new(
+            format!("{} {operator} ?", self.name),
+            vec![(self.to_sql)(value)],
+        )
+    }
+
+    /// Rows where the column equals `value`.
+    pub fn eq(self, value: impl Into<T>) -> Filter<E> {
+        self.compare("=", value.into())
+    }
+
+    /// Rows where the column does not equal `value`.
+    pub fn ne(self, value: impl Into<T>) -> Filter<E> {
+        self.compare("<>", value.into())
+    }
+
+    /// Rows where the column is less than `value`.
+    pub fn lt(self, value: impl Into<T>) -> Filter<E> {
+        self.compare("<", value.into())
+    }
+
+    /// Rows where the column is less than or equal to `value`.
+    pub fn le(self, value: impl Into<T>) -> Filter<E> {
+        self.compare("<=", value.into())
+    }
+
+    /// Rows where the column is greater than `value`.
+    pub fn gt(self, value: impl Into<T>) -> Filter<E> {
+        self.compare(">", value.into())
+    }
+
+    /// Rows where the column is greater than or equal to `value`.
+    pub fn ge(self, value: impl Into<T>) -> Filter<E> {
+        self.compare(">=", value.into())
+    }
+
+    /// Rows where the column equals any of `values`.
+    ///
+    /// The query fails with `EntityError::TooManyParameters` if it has more than
+    /// [`MAX_PARAMETERS`] values in all, so larger lists should be looked up in chunks.
+    pub fn is_in(self, values: impl IntoIterator<Item = T>) -> Filter<E> {
+        let params = values.into_iter().map(self.to_sql).collect::<Vec<_>>();
+        Filter::new(
+            format!(
+                "{} IN ({})",
+                self.name,
+                "?,".repeat(params.len()).trim_end_matches(",")
+            ),
+            params,
+        )
+    }
+}
+
+impl<E> QueryColumn<E, String> {
+    /// Rows where the column matches the `LIKE` pattern, in which `%` matches any text and `_`
+    /// any character.
+    pub fn like(self, pattern: impl Into<String>) -> Filter<E> {
+        self.compare("LIKE", pattern.into())
+    }
+}
+
+impl<E, T> QueryColumn<E, Option<T>> {
+    /// Rows where the column is `NULL`.
+    pub fn is_null(self) -> Filter<E> {
+        Filter::new(format!("{} IS NULL", self.name), Vec::new())
+    }
+
+    /// Rows where the column is not `NULL`.
+    pub fn is_not_null(self) -> Filter<E> {
+        Filter::new(format!("{} IS NOT NULL", self.name), Vec::new())
+    }
+}
+
+/// A condition on the rows of the entity `E`, built from its `{Entity}Column` constants.
+pub struct Filter<E> {
+    sql: String,
+    params: Vec<Box<dyn ToSql + Send>>,
+    marker: PhantomData<fn() -> E>,
+}
+
+impl<E> Debug for Filter<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Filter")
+            .field("sql", &self.sql)
+            .field("params", &self.params.len())
+            .finish()
+    }
+}
+
+impl<E> Filter<E> {
+    fn new(sql: String, params: Vec<Box<dyn ToSql + Send>>) -> Self {
+        Self {
+            sql,
+            params,
+            marker: PhantomData,
+        }
+    }
+
+    /// Rows matching both this and `other`.
+    pub fn and(mut self, other: Filter<E>) -> Self {
+        self.sql = format!("({}) AND ({})", self.sql, other.sql);
+        self.params.extend(other.params);
+        self
+    }
+
+    /// Rows matching either this or `other`.
+    pub fn or(mut self, other: Filter<E>) -> Self {
+        self.sql = format!("({}) OR ({})", self.sql, other.sql);
+        self.params.extend(other.params);
+        self
+    }
+
+    /// Rows not matching this.
+    #[allow(clippy::should_implement_trait)]
+    pub fn not(mut self) -> Self {
+        self.sql = format!("NOT ({})", self.sql);
+        self
+    }
+}
+
+/// A column of the entity `E` to order by, from [`QueryColumn::asc`] or [`QueryColumn::desc`].
+pub struct Order<E> {
+    column: &'static str,
+    descending: bool,
+    marker: PhantomData<fn() -> E>,
+}
+
+impl<E> Debug for Order<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Order")
+            .field("column", &self.column)
+            .field("descending", &self.descending)
+            .finish()
+    }
+}
+
+impl<E> Order<E> {
+    fn new(column: &'static str, descending: bool) -> Self {
+        Self {
+            column,
+            descending,
+            marker: PhantomData,
+        }
+    }
+}
+
+/// An opaque position in the results of a [`Query`], holding the ordering of the query and the
+/// ordered values of the last row of a [`Page`].
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(transparent)]
+pub struct Cursor(String);
+
+impl Cursor {
+    fn encode(ordering: &str, values: &[Value]) -> Self {
+        let values = values
+            .iter()
+            .map(|value| match value {
+                Value::Null => serde_json::Value::Null,
+                Value::Integer(i) => serde_json::json!({ "i": i }),
+                Value::Real(f) => serde_json::json!({ "r": f }),
+                Value::Text(s) => serde_json::json!({ "t": s }),
+                Value::Blob(b) => serde_json::json!({ "b": hex(b) }),
+            })
+            .collect::<Vec<_>>();
+        let cursor = serde_json::json!({ "o": ordering, "v": values });
+        Cursor(hex(cursor.to_string().as_bytes()))
+    }
+
+    /// The values of the cursor, or `None` if it was not produced by [`Query::page`] with
+    /// `ordering`, so that a cursor of another query cannot silently return the wrong rows.
+    fn decode(&self, ordering: &str, keys: usize) -> Option<Vec<Value>> {
+        let json = String::from_utf8(unhex(&self.0)?).ok()?;
+        let mut cursor = serde_json::from_str::<serde_json::Value>(&json).ok()?;
+        if cursor["o"] != ordering {
+            return None;
+        }
+        let serde_json::Value::Array(values) = cursor["v"].take() else {
+            return None;
+        };
+        if values.len() != keys {
+            return None;
+        }
+        values
+            .into_iter()
+            .map(|value| match value {
+                serde_json::Value::Null => Some(Value::Null),
+                serde_json::Value::Object(object) if object.len() == 1 => {
+                    match object.into_iter().next()? {
+                        (tag, value) if tag == "i" => value.as_i64().map(Value::Integer),
+                        (tag, value) if tag == "r" => value.as_f64().map(Value::Real),
+                        (tag, value) if tag == "t" => {
+                            value.as_str().map(|s| Value::Text(s.to_string()))
+                        }
+                        (tag, value) if tag == "b" => {
+                            value.as_str().and_then(unhex).map(Value::Blob)
+                        }
+                        _ => None,
+                    }
+                }
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+impl Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl From<String> for Cursor {
+    fn from(value: String) -> Self {
+        Cursor(value)
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut s, byte| {
+        let _ = write!(s, "{byte:02x}");
+        s
+    })
+}
+
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// A page of results and the cursor to continue after it, if there are more.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Page<E> {
+    pub items: Vec<E>,
+    pub next: Option<Cursor>,
+}
+
+/// A query on the table of the entity `E`, created with the generated `{Entity}::query()`.
+///
+/// Every value is passed as a parameter, so the SQL only depends on the shape of the query and
+/// the statement is reused from the connection's cache. Rows are ordered by the `order_by`
+/// columns and then by the primary key, which also makes [`Query::page`] return every row exactly
+/// once. Keyset pagination compares the ordering columns with the values in the cursor, so they
+/// should not be nullable.
+pub struct Query<E> {
+    filters: Vec<Filter<E>>,
+    order: Vec<Order<E>>,
+    limit: Option<usize>,
+    after: Option<Cursor>,
+    with_deleted: bool,
+}
+
+impl<E> Debug for Query<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Query")
+            .field("filters", &self.filters)
+            .field("order", &self.order)
+            .field("limit", &self.limit)
+            .field("after", &self.after)
+            .field("with_deleted", &self.with_deleted)
+            .finish()
+    }
+}
+
+impl<E> Default for Query<E> {
+    fn default() -> Self {
+        Self {
+            filters: Vec::new(),
+            order: Vec::new(),
+            limit: None,
+            after: None,
+            with_deleted: false,
+        }
+    }
+}
+
+impl<E: Entity> Query<E> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only return rows matching `filter`, in addition to any previous filters.
+    pub fn filter(mut self, filter: Filter<E>) -> Self {
+        self.filters.push(filter);
+        self
+    }
+
+    /// Order by `order` after any previous orderings.
+    pub fn order_by(mut self, order: Order<E>) -> Self {
+        self.order.push(order);
+        self
+    }
+
+    /// Return at most `limit` rows.
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Only return rows after `cursor`, the `next` cursor of a [`Page`] of the same query.
+    pub fn after(mut self, cursor: Cursor) -> Self {
+        self.after = Some(cursor);
+        self
+    }
+
+    /// Also return rows that have been soft deleted, which are otherwise skipped for entities with
+    /// `#[entity(soft_delete)]`.
+    pub fn with_deleted(mut self) -> Self {
+        self.with_deleted = true;
+        self
+    }
+
+    /// Retrieves the matching rows.
+    ///
+    /// # Failure
+    ///
+    /// Will return `Err` with `EntityError::InvalidCursor` if the cursor does not belong to a
+    /// query with the same ordering, or with `EntityError::TooManyParameters` if the filters have
+    /// more than [`MAX_PARAMETERS`] values.
+    pub fn fetch(self, txn: &Connection) -> Result<Vec<E>, EntityError> {
+        Ok(self
+            .select(txn, self.limit)?
+            .into_iter()
+            .map(|(entity, _)| entity)
+            .collect())
+    }
+
+    /// Retrieves the first matching row.
+    pub fn first(self, txn: &Connection) -> Result<Option<E>, EntityError> {
+        Ok(self.limit(1).fetch(txn)?.pop())
+    }
+
+    /// Retrieves a page of at most `limit` rows and the cursor of the next page, which is `None`
+    /// once there are no more rows or if there is no limit.
+    ///
+    /// # Failure
+    ///
+    /// Will return `Err` with `EntityError::InvalidCursor` if the cursor does not belong to a
+    /// query with the same ordering, or with `EntityError::TooManyParameters` if the filters have
+    /// more than [`MAX_PARAMETERS`] values.
+    pub fn page(self, txn: &Connection) -> Result<Page<E>, EntityError> {
+        // Select one more row than the limit to know whether there is a next page.
+        let mut rows = self.select(txn, self.limit.map(|limit| limit + 1))?;
+        let next = match self.limit {
+            Some(limit) if rows.len() > limit => {
+                rows.truncate(limit);
+                rows.last()
+                    .map(|(_, values)| Cursor::encode(&self.ordering(), values))
+            }
+            _ => None,
+        };
+
+        Ok(Page {
+            items: rows.into_iter().map(|(entity, _)| entity).collect(),
+            next,
+        })
+    }
+
+    /// Counts the matching rows, ignoring the limit and cursor.
+    ///
+    /// # Failure
+    ///
+    /// Will return `Err` with `EntityError::TooManyParameters` if the filters have more than
+    /// [`MAX_PARAMETERS`] values.
+    pub fn count(self, txn: &Connection) -> Result<usize, EntityError> {
+        let (conditions, params) = self.conditions()?;
+        let sql = format!("SELECT COUNT(*) FROM {}{conditions};", E::SCHEMA.table);
+        let mut stmt = txn.prepare_cached(&sql)?;
+        Ok(stmt.query_row(params_from_iter(params), |row| row.get(0))?)
+    }
+
+    /// The ordering columns and whether each is descending, ending with the primary key.
+    fn keys(&self) -> Vec<(&'static str, bool)> {
+        let mut keys = self
+            .order
+            .iter()
+            .map(|order| (order.column, order.descending))
+            .collect::<Vec<_>>();
+        if !keys.iter().any(|(column, _)| *column == E::PRIMARY_KEY) {
+            keys.push((E::PRIMARY_KEY, false));
+        }
+        keys
+    }
+
+    /// The table and the ordering columns with their directions, which a cursor must match.
+    fn ordering(&self) -> String {
+        let keys = self
+            .keys()
+            .iter()
+            .map(|(column, descending)| {
+                format!("{column} {}", if *descending { "DESC" } else { "ASC" })
+            })
+            .collect::<Vec<_>>();
+        format!("{}: {}", E::SCHEMA.table, keys.join(", "))
+    }
+
+    /// The `WHERE` clause of the filters and its parameters, failing if there are more than
+    /// a statement can take.
+    fn conditions(&self) -> Result<(String, Vec<&dyn ToSql>), EntityError> {
+        let mut conditions = self
+            .filters
+            .iter()
+            .map(|filter| format!("({})", filter.sql))
+            .collect::<Vec<_>>();
+        if let (Some(deleted_at), false) = (E::DELETED_AT, self.with_deleted) {
+            conditions.push(format!("{deleted_at} IS NULL"));
+        }
+        let params = self
+            .filters
+            .iter()
+            .flat_map(|filter| filter.params.iter().map(|param| &**param as &dyn ToSql))
+            .collect::<Vec<_>>();
+        // Leave room for the cursor and the limit.
+        if params.len() + self.keys().len() + 1 > MAX_PARAMETERS {
+            return Err(EntityError::TooManyParameters {
+                entity: E::SCHEMA.entity,
+                count: params.len(),
+            });
+        }
+        if conditions.is_empty() {
+            Ok((String::new(), params))
+        } else {
+            Ok((format!(" WHERE {}", conditions.join(" AND ")), params))
+        }
+    }
+
+    /// Select the matching rows after the cursor, along with the values of their ordering
+    /// columns.
+    fn select(
+        &self,
+        txn: &Connection,
+        limit: Option<usize>,
+    ) -> Result<Vec<(E, Vec<Value>)>, EntityError> {
+        let schema = E::SCHEMA;
+        let keys = self.keys();
+        let after = match &self.after {
+            Some(cursor) => Some(cursor.decode(&self.ordering(), keys.len()).ok_or(
+                EntityError::InvalidCursor {
+                    entity: schema.entity,
+                },
+            )?),
+            None => None,
+        };
+
+        let (mut conditions, mut params) = self.conditions()?;
+        if let Some(values) = &after {
+            conditions.push_str(if conditions.is_empty() {
+                " WHERE "
+            } else {
+                " AND "
+            });
+            conditions.push_str(&after_keys(&keys, values, &mut params));
+        }
+
+        let limit = limit.map(|limit| limit as i64);
+        if let Some(limit) = &limit {
+            params.push(limit);
+        }
+
+        let sql = format!(
+            "SELECT {},{} FROM {}{conditions} ORDER BY {}{};",
+            schema
+                .columns
+                .iter()
+                .map(|column| column.name)
+                .collect::<Vec<_>>()
+                .join(","),
+            keys.iter()
+                .map(|(column, _)| *column)
+                .collect::<Vec<_>>()
+                .join(","),
+            schema.table,
+            keys.iter()
+                .map(|(column, descending)| format!(
+                    "{column} {}",
+                    if *descending { "DESC" } else { "ASC" }
+                ))
+                .collect::<Vec<_>>()
+                .join(", "),
+            if limit.is_some() { " LIMIT ?" } else { "" },
+        );
+
+        let mut stmt = txn.prepare_cached(&sql)?;
+        let width = schema.columns.len();
+        let rows = stmt.query_map(params_from_iter(params), |row| {
+            let values = (width..width + keys.len())
+                .map(|i| row.get(i))
+                .collect::<rusqlite::Result<Vec<Value>>>()?;
+            Ok((E::from_row(row)?, values))
+        })?;
+        Ok(rows.collect::<rusqlite::Result<Vec<_>>>()?)
+    }
+}
+
+/// The condition for rows ordered after `values` by `keys`, pushing its parameters to `params`.
+fn after_keys<'a>(
+    keys: &[(&'static str, bool)],
+    values: &'a [Value],
+    params: &mut Vec<&'a dyn ToSql>,
+) -> String {
+    let columns = keys.iter().map(|(column, _)| *column).collect::<Vec<_>>();
+
+    // With a single direction a row value comparison is enough and can use an index.
+    if keys.iter().all(|(_, descending)| *descending == keys[0].1) {
+        params.extend(values.iter().map(|value| value as &dyn ToSql));
+        return format!(
+            "({}) {} ({})",
+            columns.join(","),
+            if keys[0].1 { "<" } else { ">" },
+            "?,".repeat(values.len()).trim_end_matches(",")
+        );
+    }
+
+    // Otherwise the row is after if it is equal on the first columns and after on the next.
+    let alternatives = keys
+        .iter()
+        .enumerate()
+        .map(|(i, (column, descending))| {
+            let mut terms = columns[..i]
+                .iter()
+                .map(|column| format!("{column} = ?"))
+                .collect::<Vec<_>>();
+            params.extend(values[..=i].iter().map(|value| value as &dyn ToSql));
+            terms.push(format!(
+                "{column} {} ?",
+                if *descending { "<" } else { ">" }
+            ));
+            format!("({})", terms.join(" AND "))
+        })
+        .collect::<Vec<_>>();
+    format!("({})", alternatives.join(" OR "))
+}
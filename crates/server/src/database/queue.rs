@@ -0,0 +1,224 @@
+use crossbeam_channel::Sender;
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use super::{Error, Message, Result};
+
+/// The sending side of the writer or reader channel.
+///
+/// The channel itself is bounded, but a blocking `Sender::send` would stall the Tokio worker
+/// thread, so callers first await a permit from a semaphore sized to the bound. A permit is held
+/// for as long as the job is queued, which guarantees that the `send` never blocks.
+#[derive(Clone)]
+pub(super) struct Queue {
+    sender: Sender<Message>,
+    permits: Arc<Semaphore>,
+    bound: usize,
+    acquire_timeout: Option<Duration>,
+    closed: Arc<AtomicBool>,
+    metrics: Arc<Metrics>,
+}
+
+impl Queue {
+    pub(super) fn new(
+        sender: Sender<Message>,
+        bound: usize,
+        acquire_timeout: Option<Duration>,
+        closed: Arc<AtomicBool>,
+    ) -> Self {
+        Self {
+            sender,
+            permits: Arc::new(Semaphore::new(bound)),
+            bound,
+            acquire_timeout,
+            closed,
+            metrics: Arc::new(Metrics::default()),
+        }
+    }
+
+    /// Wait for capacity in the queue, then queue the job built by `message`.
+    ///
+    /// The job must run its work through the [`Ticket`] it is given so that its place in the
+    /// queue is released and its timings are recorded.
+    ///
+    /// # Failure
+    ///
+    /// Will return [`Error::ConnectionClosed`] if the database has been closed, or
+    /// [`Error::Overloaded`] if no capacity became available within the acquire timeout.
+    pub(super) async fn send(&self, message: impl FnOnce(Ticket) -> Message) -> Result<()> {
+        if self.closed.load(Ordering::Acquire) {
+            return Err(Error::ConnectionClosed);
+        }
+
+        let permit = self.acquire().await?;
+        let ticket = Ticket {
+            permit,
+            queued: Instant::now(),
+            metrics: self.metrics.clone(),
+        };
+
+        self.sender
+            .send(message(ticket))
+            .map_err(|_| Error::ConnectionClosed)
+    }
+
+    async fn acquire(&self) -> Result<OwnedSemaphorePermit> {
+        let _waiting = Waiting::new(&self.metrics.waiting);
+        let permits = self.permits.clone();
+
+        let permit = match self.acquire_timeout {
+            None => permits.acquire_owned().await.ok(),
+            Some(timeout) if timeout.is_zero() => permits.try_acquire_owned().ok(),
+            Some(timeout) => tokio::time::timeout(timeout, permits.acquire_owned())
+                .await
+                .ok()
+                .and_then(|permit| permit.ok()),
+        };
+
+        permit.ok_or_else(|| {
+            self.metrics.rejected.fetch_add(1, Ordering::Relaxed);
+            Error::Overloaded
+        })
+    }
+
+    /// The underlying sender, for control messages that bypass backpressure.
+    pub(super) fn sender(&self) -> &Sender<Message> {
+        &self.sender
+    }
+
+    /// A snapshot of the activity of this queue.
+    pub(super) fn stats(&self) -> QueueStats {
+        let metrics = &self.metrics;
+        QueueStats {
+            depth: self.bound - self.permits.available_permits(),
+            waiting: metrics.waiting.load(Ordering::Relaxed),
+            executed: metrics.executed.load(Ordering::Relaxed),
+            rejected: metrics.rejected.load(Ordering::Relaxed),
+            wait_total: Duration::from_micros(metrics.wait_total.load(Ordering::Relaxed)),
+            wait_max: Duration::from_micros(metrics.wait_max.load(Ordering::Relaxed)),
+            execute_total: Duration::from_micros(metrics.execute_total.load(Ordering::Relaxed)),
+            execute_max: Duration::from_micros(metrics.execute_max.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// A queued job's place in the queue.
+pub(super) struct Ticket {
+    permit: OwnedSemaphorePermit,
+    queued: Instant,
+    metrics: Arc<Metrics>,
+}
+
+impl Ticket {
+    /// Run the job: release its place in the queue and record how long it waited and how long
+    /// `function` took to execute.
+    pub(super) fn run<T>(self, function: impl FnOnce() -> T) -> T {
+        let Ticket {
+            permit,
+            queued,
+            metrics,
+        } = self;
+        drop(permit);
+
+        let started = Instant::now();
+        record(&metrics.wait_total, &metrics.wait_max, started - queued);
+        let value = function();
+        record(
+            &metrics.execute_total,
+            &metrics.execute_max,
+            started.elapsed(),
+        );
+        metrics.executed.fetch_add(1, Ordering::Relaxed);
+
+        value
+    }
+}
+
+fn record(total: &AtomicU64, max: &AtomicU64, duration: Duration) {
+    let micros = duration.as_micros() as u64;
+    total.fetch_add(micros, Ordering::Relaxed);
+    max.fetch_max(micros, Ordering::Relaxed);
+}
+
+/// Counts a caller as waiting for capacity until it is dropped, including when the caller's
+/// future is cancelled.
+struct Waiting<'a>(&'a AtomicUsize);
+
+impl<'a> Waiting<'a> {
+    fn new(waiting: &'a AtomicUsize) -> Self {
+        waiting.fetch_add(1, Ordering::Relaxed);
+        Self(waiting)
+    }
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Default)]
+struct Metrics {
+    waiting: AtomicUsize,
+    executed: AtomicU64,
+    rejected: AtomicU64,
+    wait_total: AtomicU64,
+    wait_max: AtomicU64,
+    execute_total: AtomicU64,
+    execute_max: AtomicU64,
+}
+
+/// A point-in-time snapshot of the activity of the writer or reader queue.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct QueueStats {
+    /// The number of jobs queued and not yet started.
+    pub depth: usize,
+    /// The number of callers waiting for space in the queue.
+    pub waiting: usize,
+    /// The number of jobs executed.
+    pub executed: u64,
+    /// The number of calls rejected with [`Error::Overloaded`].
+    pub rejected: u64,
+    /// The total time executed jobs spent queued.
+    pub wait_total: Duration,
+    /// The longest time a job spent queued.
+    pub wait_max: Duration,
+    /// The total time spent executing jobs.
+    pub execute_total: Duration,
+    /// The longest time spent executing a single job.
+    pub execute_max: Duration,
+}
+
+impl QueueStats {
+    /// The mean time executed jobs spent queued.
+    pub fn wait_mean(&self) -> Duration {
+        mean(self.wait_total, self.executed)
+    }
+
+    /// The mean time spent executing a job.
+    pub fn execute_mean(&self) -> Duration {
+        mean(self.execute_total, self.executed)
+    }
+}
+
+fn mean(total: Duration, count: u64) -> Duration {
+    match count {
+        0 => Duration::ZERO,
+        count => Duration::from_micros((total.as_micros() / count as u128) as u64),
+    }
+}
+
+/// A point-in-time snapshot of the activity of a [`Database`](super::Database).
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DatabaseMetrics {
+    /// The queue in front of the single writer connection.
+    pub writer: QueueStats,
+    /// The queue shared by the reader connections.
+    pub reader: QueueStats,
+}
@@ -0,0 +1,279 @@
+use anyhow::anyhow;
+use uuid::Uuid;
+
+use super::{Database, Error, Result};
+
+/// The SQLite type affinity of a column, or the affinity a field type is stored with.
+///
+/// See <https://www.sqlite.org/datatype3.html#determination_of_column_affinity>.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Affinity {
+    Integer,
+    Real,
+    Text,
+    Blob,
+    Numeric,
+}
+
+impl Affinity {
+    /// The affinity of a column declared with type `declared`.
+    pub fn of(declared: &str) -> Self {
+        let declared = declared.to_ascii_uppercase();
+        if declared.contains("INT") {
+            Affinity::Integer
+        } else if ["CHAR", "CLOB", "TEXT"]
+            .iter()
+            .any(|name| declared.contains(name))
+        {
+            Affinity::Text
+        } else if declared.is_empty() || declared.contains("BLOB") {
+            Affinity::Blob
+        } else if ["REAL", "FLOA", "DOUB"]
+            .iter()
+            .any(|name| declared.contains(name))
+        {
+            Affinity::Real
+        } else {
+            Affinity::Numeric
+        }
+    }
+
+    /// Whether a field stored with affinity `field` reads back unchanged from a column with this
+    /// affinity.
+    fn accepts(self, field: Affinity) -> bool {
+        self == field
+            || (self == Affinity::Numeric && matches!(field, Affinity::Integer | Affinity::Real))
+    }
+}
+
+/// How a field type is stored in SQLite, used to check entities against their tables.
+pub trait ColumnType {
+    /// The affinity the value is stored with.
+    const AFFINITY: Affinity;
+    /// Whether the value can be `NULL`.
+    const NULLABLE: bool = false;
+}
+
+macro_rules! column_type {
+    ($affinity:ident: $($ty:ty),*) => {
+        $(impl ColumnType for $ty {
+            const AFFINITY: Affinity = Affinity::$affinity;
+        })*
+    };
+}
+
+column_type!(Integer: bool, i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+column_type!(Real: f32, f64);
+column_type!(Text: String, chrono::NaiveDate, chrono::NaiveDateTime, chrono::NaiveTime, serde_json::Value);
+column_type!(Blob: Vec<u8>, Uuid);
+
+impl<Tz: chrono::TimeZone> ColumnType for chrono::DateTime<Tz> {
+    const AFFINITY: Affinity = Affinity::Text;
+}
+
+impl<T: ColumnType> ColumnType for Option<T> {
+    const AFFINITY: Affinity = T::AFFINITY;
+    const NULLABLE: bool = true;
+}
+
+/// A field of an entity and the column it is stored in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Column {
+    pub name: &'static str,
+    pub affinity: Affinity,
+    pub nullable: bool,
+    /// Whether the column is the primary key.
+    pub primary_key: bool,
+    /// Whether the column has a unique index, from `#[entity(unique)]`.
+    pub unique: bool,
+    /// Whether the column has an index, from `#[entity(index)]`.
+    pub index: bool,
+    /// The column the values refer to, from `#[entity(belongs_to = ...)]`.
+    pub references: Option<Reference>,
+    /// The constraint on the stored values, from `#[entity(json)]` or `#[entity(enum_text)]`.
+    pub check: Option<Check>,
+}
+
+/// A `CHECK` constraint on the values of a column.
+#[derive(Clone, Copy, Debug)]
+pub enum Check {
+    /// The values are valid JSON.
+    Json,
+    /// The values are one of those returned by the function, see
+    /// [`enum_values`](super::enum_values).
+    OneOf(fn() -> Vec<String>),
+}
+
+impl Check {
+    /// The `CHECK` constraint on the column `name`.
+    pub fn constraint(&self, name: &str) -> String {
+        match self {
+            Check::Json => format!("CHECK (json_valid({name}))"),
+            Check::OneOf(values) => format!(
+                "CHECK ({name} IN ({}))",
+                values()
+                    .iter()
+                    .map(|value| format!("'{}'", value.replace('\'', "''")))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
+    }
+}
+
+// Function pointers cannot be compared reliably, so compare the values they return.
+impl PartialEq for Check {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Check::Json, Check::Json) => true,
+            (Check::OneOf(values), Check::OneOf(other)) => values() == other(),
+            _ => false,
+        }
+    }
+}
+
+impl Eq for Check {}
+
+/// The primary key column of another table that a foreign key column refers to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Reference {
+    pub table: &'static str,
+    pub column: &'static str,
+}
+
+/// The table an entity deriving `ToSql` is stored in and the columns of its stored fields,
+/// available as the entity's `SCHEMA` constant.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EntitySchema {
+    pub entity: &'static str,
+    pub table: &'static str,
+    pub columns: &'static [Column],
+}
+
+impl Database {
+    /// Check that every entity lines up with the columns of its table, as reported by
+    /// `PRAGMA table_info`.
+    ///
+    /// A missing column, a column whose affinity differs from its field's type, a nullable column
+    /// read into a non-`Option` field or a `NOT NULL` column without a default that no field
+    /// writes would otherwise only fail at runtime. The generated queries name their columns, so
+    /// columns out of field order still work and are returned as warnings.
+    ///
+    /// # Failure
+    ///
+    /// Will return `Err` listing every mismatch, or if the database connection has been closed.
+    pub(crate) async fn validate_schema(
+        &self,
+        entities: &'static [EntitySchema],
+    ) -> Result<Vec<String>> {
+        self.read(move |conn| {
+            let mut problems = Vec::new();
+            let mut warnings = Vec::new();
+            for entity in entities {
+                let (entity_problems, entity_warnings) = validate(conn, entity)?;
+                let name = |problem| format!("{} ({}): {problem}", entity.entity, entity.table);
+                problems.extend(entity_problems.into_iter().map(name));
+                warnings.extend(entity_warnings.into_iter().map(name));
+            }
+
+            if problems.is_empty() {
+                Ok(warnings)
+            } else {
+                Err(Error::Other(anyhow!(
+                    "entities do not match the database schema:\n{}",
+                    problems.join("\n")
+                )))
+            }
+        })
+        .await
+    }
+}
+
+/// A column as reported by `PRAGMA table_info`.
+struct TableColumn {
+    name: String,
+    declared: String,
+    not_null: bool,
+    default: bool,
+}
+
+/// The problems and the warnings found comparing `entity` with its table.
+fn validate(
+    conn: &rusqlite::Connection,
+    entity: &EntitySchema,
+) -> rusqlite::Result<(Vec<String>, Vec<String>)> {
+    let mut statement =
+        conn.prepare("SELECT name, type, \"notnull\", pk, dflt_value FROM pragma_table_info(?)")?;
+    let table = statement
+        .query_map([entity.table], |row| {
+            Ok(TableColumn {
+                name: row.get(0)?,
+                declared: row.get(1)?,
+                // Primary key columns cannot be NULL in a `WITHOUT ROWID` table.
+                not_null: row.get::<_, bool>(2)? || row.get::<_, i64>(3)? > 0,
+                default: row.get::<_, Option<String>>(4)?.is_some(),
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    if table.is_empty() {
+        return Ok((vec!["table does not exist".to_string()], Vec::new()));
+    }
+
+    let mut problems = Vec::new();
+    for column in entity.columns {
+        let Some(found) = table.iter().find(|found| found.name == column.name) else {
+            problems.push(format!("column `{}` does not exist", column.name));
+            continue;
+        };
+
+        let affinity = Affinity::of(&found.declared);
+        if !found.declared.eq_ignore_ascii_case("ANY") && !affinity.accepts(column.affinity) {
+            problems.push(format!(
+                "column `{}` is declared `{}` ({affinity:?}) but its field is stored as {:?}",
+                column.name, found.declared, column.affinity
+            ));
+        }
+        if !found.not_null && !column.nullable {
+            problems.push(format!(
+                "column `{}` is nullable but its field is not an `Option`",
+                column.name
+            ));
+        }
+    }
+
+    for found in &table {
+        let written = entity
+            .columns
+            .iter()
+            .any(|column| column.name == found.name);
+        if !written && found.not_null && !found.default {
+            problems.push(format!(
+                "column `{}` is `NOT NULL` without a default but has no field",
+                found.name
+            ));
+        }
+    }
+
+    let mut warnings = Vec::new();
+    let fields = entity
+        .columns
+        .iter()
+        .map(|column| column.name)
+        .filter(|name| table.iter().any(|found| found.name == *name))
+        .collect::<Vec<_>>();
+    let columns = table
+        .iter()
+        .map(|found| found.name.as_str())
+        .filter(|name| fields.contains(name))
+        .collect::<Vec<_>>();
+    if columns != fields {
+        warnings.push(format!(
+            "columns `{}` are not in the field order `{}`",
+            columns.join(", "),
+            fields.join(", ")
+        ));
+    }
+
+    Ok((problems, warnings))
+}
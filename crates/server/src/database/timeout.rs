@@ -0,0 +1,170 @@
+use rusqlite::{InterruptHandle, TransactionBehavior};
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::oneshot;
+
+use super::{
+    catch_panic, queue::Queue, Database, Error, Message, ReadTxn, Result, WriteTxn, BUG_TEXT,
+};
+
+/// The state of a job queued with a timeout, shared between the caller and the connection
+/// thread so that they agree on whether the job was abandoned.
+enum JobState {
+    Queued,
+    Running(InterruptHandle),
+    Finished,
+    Abandoned,
+}
+
+impl Database {
+    /// Call a function with an `IMMEDIATE` transaction on the writer connection, giving up after
+    /// `timeout`.
+    ///
+    /// If the timeout elapses while the job is still queued it is skipped when it reaches the
+    /// writer. If it elapses while the job is running, the running statement is interrupted and
+    /// the transaction rolled back, so a timed out write never commits. Jobs whose caller has
+    /// gone away (for example because the HTTP request was cancelled) are skipped or rolled back
+    /// in the same way.
+    ///
+    /// # Failure
+    ///
+    /// Will return [`Error::Timeout`] if the timeout elapses, or `Err` under the same conditions
+    /// as [`Database::write_txn`].
+    #[allow(dead_code)]
+    pub(crate) async fn write_with_timeout<F, R>(&self, timeout: Duration, function: F) -> Result<R>
+    where
+        F: FnOnce(&WriteTxn) -> Result<R> + 'static + Send,
+        R: Send + 'static,
+    {
+        let (value, after_commit) = call_with_timeout(
+            &self.writer,
+            TransactionBehavior::Immediate,
+            timeout,
+            move |conn| {
+                let write_txn = WriteTxn::new(conn);
+                let value = function(&write_txn)?;
+                Ok((value, write_txn.into_after_commit()))
+            },
+        )
+        .await?;
+        after_commit.into_iter().for_each(|function| function());
+        Ok(value)
+    }
+
+    /// Call a function with a `DEFERRED` transaction on a reader connection, giving up after
+    /// `timeout`.
+    ///
+    /// Like [`Database::write_with_timeout`], a job is skipped if it times out while queued and
+    /// interrupted if it times out while running.
+    ///
+    /// # Failure
+    ///
+    /// Will return [`Error::Timeout`] if the timeout elapses, or `Err` under the same conditions
+    /// as [`Database::read_txn`].
+    #[allow(dead_code)]
+    pub(crate) async fn read_with_timeout<F, R>(&self, timeout: Duration, function: F) -> Result<R>
+    where
+        F: FnOnce(&ReadTxn) -> Result<R> + 'static + Send,
+        R: Send + 'static,
+    {
+        call_with_timeout(
+            &self.reader,
+            TransactionBehavior::Deferred,
+            timeout,
+            move |conn| function(&ReadTxn { conn }),
+        )
+        .await
+    }
+}
+
+async fn call_with_timeout<F, R>(
+    queue: &Queue,
+    behavior: TransactionBehavior,
+    timeout: Duration,
+    function: F,
+) -> Result<R>
+where
+    F: FnOnce(&rusqlite::Connection) -> Result<R> + 'static + Send,
+    R: Send + 'static,
+{
+    let deadline = tokio::time::Instant::now() + timeout;
+    let state = Arc::new(Mutex::new(JobState::Queued));
+    let (sender, mut receiver) = oneshot::channel::<Result<R>>();
+
+    let job_state = state.clone();
+    let send = queue.send(|ticket| {
+        Message::Execute(Box::new(move |conn| {
+            ticket.run(|| {
+                {
+                    let mut state = job_state.lock().expect(BUG_TEXT);
+                    if sender.is_closed() || !matches!(*state, JobState::Queued) {
+                        return;
+                    }
+                    *state = JobState::Running(conn.get_interrupt_handle());
+                }
+
+                let value = conn
+                    .transaction_with_behavior(behavior)
+                    .map_err(Error::from)
+                    .and_then(|txn| {
+                        let value = catch_panic(|| function(&txn));
+
+                        // Decide whether to commit while holding the lock, so that the caller
+                        // either abandons the job before the commit or not at all.
+                        let mut state = job_state.lock().expect(BUG_TEXT);
+                        let abandoned = sender.is_closed() || matches!(*state, JobState::Abandoned);
+                        *state = JobState::Finished;
+
+                        match value {
+                            Ok(value) if !abandoned => {
+                                txn.commit()?;
+                                Ok(value)
+                            }
+                            // Should the rollback fail too, the timeout or the error of
+                            // `function` is still the one returned.
+                            Ok(_) => {
+                                let _ = txn.rollback();
+                                Err(Error::Timeout)
+                            }
+                            Err(err) => {
+                                let _ = txn.rollback();
+                                Err(err)
+                            }
+                        }
+                    });
+                let _ = sender.send(value);
+            })
+        }))
+    });
+
+    match tokio::time::timeout_at(deadline, send).await {
+        Ok(result) => result?,
+        Err(_) => return Err(Error::Timeout),
+    }
+
+    match tokio::time::timeout_at(deadline, &mut receiver).await {
+        Ok(result) => result.map_err(|_| Error::ConnectionClosed)?,
+        Err(_) => {
+            // Interrupt while holding the lock, so the connection is still running this job.
+            let finished = {
+                let mut state = state.lock().expect(BUG_TEXT);
+                match std::mem::replace(&mut *state, JobState::Abandoned) {
+                    JobState::Running(handle) => {
+                        handle.interrupt();
+                        false
+                    }
+                    JobState::Finished => true,
+                    JobState::Queued | JobState::Abandoned => false,
+                }
+            };
+
+            // The job completed while the timeout was being handled, so its result is imminent.
+            if finished {
+                return receiver.await.map_err(|_| Error::ConnectionClosed)?;
+            }
+            Err(Error::Timeout)
+        }
+    }
+}
@@ -0,0 +1,126 @@
+use crossbeam_channel::Receiver;
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+};
+use tokio::sync::oneshot;
+
+use super::{serve, Batching, ConnectionHandle, Message, BUG_TEXT};
+
+/// Opens a new connection for a connection thread, both at startup and when the thread is
+/// respawned.
+pub(super) type OpenFn = Arc<dyn Fn() -> rusqlite::Result<rusqlite::Connection> + Send + Sync>;
+
+/// The current thread of one connection, shared between the [`Database`](super::Database) and
+/// the thread itself so that a replacement thread can take its place.
+#[derive(Clone, Default)]
+pub(super) struct Slot {
+    handle: Arc<Mutex<Option<ConnectionHandle>>>,
+    respawns: Arc<AtomicU64>,
+}
+
+impl Slot {
+    /// Take the handle of the current thread so that it can be joined.
+    pub(super) fn take(&self) -> Option<ConnectionHandle> {
+        self.handle.lock().expect(BUG_TEXT).take()
+    }
+
+    pub(super) fn status(&self) -> ConnectionStatus {
+        let handle = self.handle.lock().expect(BUG_TEXT);
+        ConnectionStatus {
+            alive: handle.as_ref().is_some_and(|handle| !handle.is_finished()),
+            respawns: self.respawns.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Everything needed to (re)start the thread of one connection.
+#[derive(Clone)]
+pub(super) struct Worker {
+    pub(super) open: OpenFn,
+    pub(super) receiver: Receiver<Message>,
+    pub(super) checkpoint: bool,
+    pub(super) batching: Batching,
+    pub(super) slot: Slot,
+    pub(super) closed: Arc<AtomicBool>,
+}
+
+impl Worker {
+    /// Spawn the connection thread and wait until its connection has been opened.
+    pub(super) async fn start(self) -> rusqlite::Result<()> {
+        let (sender, receiver) = oneshot::channel();
+        self.spawn(Some(sender));
+        receiver.await.expect(BUG_TEXT)
+    }
+
+    /// Spawn the connection thread into the slot, replacing any previous thread. The result of
+    /// opening the connection is reported to `opened` if given, otherwise it is the result of
+    /// the thread.
+    fn spawn(self, opened: Option<oneshot::Sender<rusqlite::Result<()>>>) {
+        let slot = self.slot.clone();
+        // Hold the lock while spawning so a replacement spawned by the new thread is stored last.
+        let mut handle = slot.handle.lock().expect(BUG_TEXT);
+        *handle = Some(thread::spawn(move || {
+            let sentinel = Sentinel(self);
+            let worker = &sentinel.0;
+
+            let conn = match ((worker.open)(), opened) {
+                (Ok(conn), None) => conn,
+                (Ok(conn), Some(opened)) => match opened.send(Ok(())) {
+                    Ok(()) => conn,
+                    Err(_) => return Ok(()),
+                },
+                (Err(err), None) => return Err(err),
+                (Err(err), Some(opened)) => {
+                    let _ = opened.send(Err(err));
+                    return Ok(());
+                }
+            };
+
+            serve(conn, &worker.receiver, worker.checkpoint, worker.batching)
+        }));
+    }
+}
+
+/// Owned by a connection thread, respawning it with a new connection if the thread unwinds from
+/// a panic. The jobs that were still queued are then executed by the replacement thread.
+struct Sentinel(Worker);
+
+impl Drop for Sentinel {
+    fn drop(&mut self) {
+        if thread::panicking() && !self.0.closed.load(Ordering::Acquire) {
+            self.0.slot.respawns.fetch_add(1, Ordering::Relaxed);
+            self.0.clone().spawn(None);
+        }
+    }
+}
+
+/// The health of a single connection thread.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ConnectionStatus {
+    /// Whether the thread is running. A thread whose connection could not be reopened after a
+    /// respawn, or that has been closed, is not alive.
+    pub alive: bool,
+    /// The number of times the thread died from a panic and was respawned.
+    pub respawns: u64,
+}
+
+/// A point-in-time snapshot of the health of the connections of a
+/// [`Database`](super::Database).
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PoolStatus {
+    /// The single writer connection.
+    pub writer: ConnectionStatus,
+    /// Each of the reader connections.
+    pub readers: Vec<ConnectionStatus>,
+}
+
+impl PoolStatus {
+    /// Whether the writer and every reader are alive.
+    pub fn healthy(&self) -> bool {
+        self.writer.alive && self.readers.iter().all(|reader| reader.alive)
+    }
+}
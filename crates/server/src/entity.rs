@@ -1,2 +1,58 @@
+use crate::database::EntitySchema;
+
 pub mod identity_user;
+pub mod role;
+pub mod role_grant;
 pub mod user;
+
+/// The schema of every entity, validated against the migrated tables at startup and diffed
+/// against them by `server migrate generate`.
+///
+/// A test checks that every struct deriving `ToSql` in the modules of `entity` is listed here.
+pub const SCHEMAS: &[EntitySchema] = &[
+    user::User::SCHEMA,
+    identity_user::IdentityUser::SCHEMA,
+    role::Role::SCHEMA,
+    role_grant::RoleGrant::SCHEMA,
+];
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::SCHEMAS;
+
+    #[test]
+    fn schemas_list_every_entity() -> anyhow::Result<()> {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("src/entity");
+        let mut derived = Vec::new();
+        for file in std::fs::read_dir(dir)? {
+            let path = file?.path();
+            if path.extension().is_none_or(|extension| extension != "rs") {
+                continue;
+            }
+            // The struct following each `#[derive(..., ToSql)]`.
+            let source = std::fs::read_to_string(&path)?;
+            let mut lines = source.lines().map(str::trim);
+            while let Some(line) = lines.next() {
+                if !(line.starts_with("#[derive(") && line.contains("ToSql")) {
+                    continue;
+                }
+                let name = lines
+                    .find_map(|line| line.strip_prefix("pub struct "))
+                    .and_then(|rest| rest.split([' ', '{', '(']).next())
+                    .unwrap_or_default();
+                derived.push(name.to_string());
+            }
+        }
+        derived.sort();
+
+        let mut listed = SCHEMAS
+            .iter()
+            .map(|schema| schema.entity.to_string())
+            .collect::<Vec<_>>();
+        listed.sort();
+        assert_eq!(derived, listed, "entity::SCHEMAS must list every entity");
+        Ok(())
+    }
+}
@@ -1,46 +1,45 @@
-use anyhow::Result;
+use chrono::{DateTime, Utc};
 use entity_macro::ToSql;
-use rusqlite::{params, params_from_iter, Transaction};
+use rusqlite::{params, params_from_iter, Connection};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
 use super::user::User;
+use crate::database::WriteTxn;
 
 #[cfg(test)]
 use crate::test::TestContext;
 
 /// This struct represents a record in the `identitys_users` table.
 #[derive(Clone, Debug, Default, Deserialize, PartialEq, JsonSchema, Serialize, ToSql)]
+#[entity(table = "identitys_users", belongs_to = User, fk = "user_id", timestamps)]
 pub struct IdentityUser {
     /// The unique identifier for the identity user.
     pub id: Uuid,
     /// The unique identifier for the associated user.
     pub user_id: Uuid,
-}
-
-impl IdentityUser {
-    /// Retrieves the associated `User` for this `IdentityUser`.
-    pub fn retrieve_user(&self, txn: &Transaction) -> Result<User> {
-        Ok(User::retrieve(txn, &self.user_id)?.unwrap())
-    }
+    /// When the identity was linked to the user.
+    pub created_at: DateTime<Utc>,
+    /// When the link was last updated.
+    pub updated_at: DateTime<Utc>,
 }
 
 #[cfg(test)]
 pub mod test {
+    use anyhow::Result;
+
     use crate::entity::user::test::Users;
 
     use super::*;
 
     pub struct IdentitysUsers;
     impl crate::test::Fixture for IdentitysUsers {
-        fn try_fixtures(&self, txn: &Transaction) -> Result<()> {
-            serde_json::from_str::<Vec<IdentityUser>>(&std::fs::read_to_string(
-                std::path::Path::new("fixtures/identitys_users.json"),
-            )?)?
-            .iter()
-            .map(|entity| Ok(entity.upsert(txn)?))
-            .collect::<Result<Vec<_>>>()?;
+        fn try_fixtures(&self, txn: &WriteTxn) -> Result<()> {
+            let identity_users = serde_json::from_str::<Vec<IdentityUser>>(
+                &std::fs::read_to_string(std::path::Path::new("fixtures/identitys_users.json"))?,
+            )?;
+            IdentityUser::insert_many(txn, &identity_users)?;
             Ok(())
         }
     }
@@ -54,23 +53,20 @@ pub mod test {
             Uuid::parse_str("3d517fe6-ebab-7b8c-fcf9-8db6259c8a59").unwrap(),
         );
 
-        let entity_move = entity.clone();
-        context
+        // Upserting sets the timestamps, so compare with the upserted entity.
+        let mut entity_move = entity.clone();
+        let entity = context
             .database()
-            .write(move |connection| {
-                let txn = connection.transaction()?;
-                entity_move.upsert(&txn)?;
-                Ok(txn.commit()?)
+            .write_txn(move |txn| {
+                entity_move.upsert(txn)?;
+                Ok(entity_move)
             })
             .await?;
 
         let entity_move = entity.clone();
         let retrieved_entity = context
             .database()
-            .read(move |connection| {
-                let txn = connection.transaction()?;
-                Ok(IdentityUser::retrieve(&txn, &entity_move.id)?)
-            })
+            .read_txn(move |txn| Ok(IdentityUser::retrieve(txn, &entity_move.id)?))
             .await?
             .unwrap();
 
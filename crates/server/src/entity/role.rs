@@ -0,0 +1,139 @@
+use std::collections::BTreeSet;
+
+use chrono::{DateTime, Utc};
+use entity_macro::ToSql;
+use rusqlite::{params, params_from_iter, Connection};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::database::{EntityError, EntityHooks, FieldError, WriteTxn};
+
+/// This struct represents a record in the `roles` table.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, JsonSchema, Serialize, ToSql)]
+#[entity(table = "roles", timestamps, hooks)]
+pub struct Role {
+    /// Unique identifier for the role.
+    pub id: Uuid,
+    /// The name of the role, as listed in the `roles` of an identity's Kratos `metadata_admin`.
+    #[entity(unique)]
+    pub name: String,
+    /// The permissions the role grants, each `resource:action`, `resource:*` or `*`.
+    #[entity(json)]
+    pub permissions: Vec<String>,
+    /// When the role was created.
+    pub created_at: DateTime<Utc>,
+    /// When the role was last updated.
+    pub updated_at: DateTime<Utc>,
+}
+
+impl EntityHooks for Role {
+    fn validate(&self) -> Result<(), Vec<FieldError>> {
+        let mut errors = Vec::new();
+        if self.name.is_empty() {
+            errors.push(FieldError::new("name", "must not be empty"));
+        }
+        for permission in &self.permissions {
+            if !is_valid_permission(permission) {
+                errors.push(FieldError::new(
+                    "permissions",
+                    format!("has an invalid permission {permission:?}"),
+                ));
+            }
+        }
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(errors),
+        }
+    }
+}
+
+impl Role {
+    /// Retrieves the role named `name`.
+    pub fn retrieve_by_name(txn: &Connection, name: &str) -> Result<Option<Self>, EntityError> {
+        Self::query().filter(RoleColumn::Name.eq(name)).first(txn)
+    }
+}
+
+/// Whether `permission` is `*`, or a resource and an action (or `*`) of lowercase letters and
+/// underscores separated by a colon.
+fn is_valid_permission(permission: &str) -> bool {
+    let is_name =
+        |part: &str| !part.is_empty() && part.chars().all(|c| c.is_ascii_lowercase() || c == '_');
+    match permission.split_once(':') {
+        Some((resource, action)) => is_name(resource) && (action == "*" || is_name(action)),
+        None => permission == "*",
+    }
+}
+
+/// What a user may do, the union of the permissions of their roles.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Permissions(BTreeSet<String>);
+
+impl Permissions {
+    /// Whether `permission`, e.g. `users:read`, is granted, itself or by `users:*` or `*`.
+    pub fn allows(&self, permission: &str) -> bool {
+        let wildcard = permission
+            .split_once(':')
+            .map(|(resource, _)| format!("{resource}:*"));
+        self.0.contains("*")
+            || self.0.contains(permission)
+            || wildcard.is_some_and(|wildcard| self.0.contains(&wildcard))
+    }
+}
+
+impl FromIterator<String> for Permissions {
+    fn from_iter<I: IntoIterator<Item = String>>(iter: I) -> Self {
+        Self(iter.into_iter().collect())
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    fn permissions_allow_wildcards() {
+        let permissions: Permissions = ["users:read", "roles:*"]
+            .map(String::from)
+            .into_iter()
+            .collect();
+        assert!(permissions.allows("users:read"));
+        assert!(!permissions.allows("users:write"));
+        assert!(permissions.allows("roles:read"));
+        assert!(permissions.allows("roles:write"));
+        assert!(!permissions.allows("grants:read"));
+
+        let admin: Permissions = ["*".to_string()].into_iter().collect();
+        assert!(admin.allows("grants:write"));
+        assert!(!Permissions::default().allows("users:read"));
+    }
+
+    #[test]
+    fn validate_permissions() {
+        let mut role = Role::new(
+            Uuid::new_v4(),
+            "editor".to_string(),
+            vec![
+                "users:read".to_string(),
+                "roles:*".to_string(),
+                "*".to_string(),
+            ],
+        );
+        assert_eq!(role.validate(), Ok(()));
+
+        role.name = String::new();
+        role.permissions = vec![
+            "users".to_string(),
+            "Users:read".to_string(),
+            ":*".to_string(),
+        ];
+        let errors = role.validate().unwrap_err();
+        assert_eq!(errors.len(), 4);
+        assert_eq!(errors[0].to_string(), "name must not be empty");
+        assert_eq!(
+            errors[1].to_string(),
+            "permissions has an invalid permission \"users\""
+        );
+    }
+}
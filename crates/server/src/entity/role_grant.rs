@@ -0,0 +1,171 @@
+use chrono::{DateTime, Utc};
+use entity_macro::ToSql;
+use rusqlite::{params, params_from_iter, Connection};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::{
+    role::{Permissions, Role},
+    user::User,
+};
+use crate::database::{EntityError, WriteTxn};
+
+/// This struct represents a record in the `role_grants` table, a role granted to a user.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, JsonSchema, Serialize, ToSql)]
+#[entity(table = "role_grants", belongs_to = User, belongs_to = Role, timestamps)]
+pub struct RoleGrant {
+    /// Unique identifier for the grant.
+    pub id: Uuid,
+    /// The user the role is granted to.
+    #[entity(index)]
+    pub user_id: Uuid,
+    /// The role granted.
+    #[entity(index)]
+    pub role_id: Uuid,
+    /// When the role was granted.
+    pub created_at: DateTime<Utc>,
+    /// When the grant was last updated.
+    pub updated_at: DateTime<Utc>,
+}
+
+impl User {
+    /// The roles granted to this user.
+    pub fn roles(&self, txn: &Connection) -> Result<Vec<Role>, EntityError> {
+        let grants = self.role_grants(txn)?;
+        let mut roles = RoleGrant::role_for(txn, &grants)?
+            .into_values()
+            .collect::<Vec<_>>();
+        roles.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(roles)
+    }
+
+    /// The permissions of this user, those of all of their roles.
+    pub fn permissions(&self, txn: &Connection) -> Result<Permissions, EntityError> {
+        Ok(self
+            .roles(txn)?
+            .into_iter()
+            .flat_map(|role| role.permissions)
+            .collect())
+    }
+
+    /// Grants `role` to this user, unless it has it already.
+    pub fn grant_role(&self, txn: &WriteTxn, role: &Role) -> Result<RoleGrant, EntityError> {
+        if let Some(grant) = self.role_grant(txn, role)? {
+            return Ok(grant);
+        }
+        let mut grant = RoleGrant::new(Uuid::new_v4(), self.id, role.id);
+        grant.insert(txn)?;
+        Ok(grant)
+    }
+
+    /// Revokes `role` from this user, returning whether it had it.
+    pub fn revoke_role(&self, txn: &WriteTxn, role: &Role) -> Result<bool, EntityError> {
+        match self.role_grant(txn, role)? {
+            Some(grant) => RoleGrant::delete(txn, &grant.id).map(|_| true),
+            None => Ok(false),
+        }
+    }
+
+    fn role_grant(&self, txn: &Connection, role: &Role) -> Result<Option<RoleGrant>, EntityError> {
+        RoleGrant::query()
+            .filter(
+                RoleGrantColumn::UserId
+                    .eq(self.id)
+                    .and(RoleGrantColumn::RoleId.eq(role.id)),
+            )
+            .first(txn)
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use anyhow::Result;
+
+    use super::*;
+    use crate::database::{Database, DatabaseConfig};
+
+    #[tokio::test]
+    async fn grants_give_the_permissions_of_their_roles() -> Result<()> {
+        let database = Database::open_in_memory(DatabaseConfig::default().readers(1)).await?;
+
+        let (user, other) = database
+            .write_txn(|txn| {
+                let mut user = User::new(Uuid::new_v4());
+                user.insert(txn)?;
+                let mut other = User::new(Uuid::new_v4());
+                other.insert(txn)?;
+
+                let mut reader = Role::new(
+                    Uuid::new_v4(),
+                    "reader".to_string(),
+                    vec!["users:read".to_string()],
+                );
+                reader.insert(txn)?;
+                let mut granter = Role::new(
+                    Uuid::new_v4(),
+                    "granter".to_string(),
+                    vec!["grants:*".to_string()],
+                );
+                granter.insert(txn)?;
+
+                user.grant_role(txn, &reader)?;
+                user.grant_role(txn, &granter)?;
+                // Granting a role again keeps the one grant, which is all the table allows.
+                user.grant_role(txn, &reader)?;
+                assert!(RoleGrant::new(Uuid::new_v4(), user.id, reader.id)
+                    .insert(txn)
+                    .is_err());
+                other.grant_role(txn, &reader)?;
+                Ok((user, other))
+            })
+            .await?;
+
+        let user_move = user.clone();
+        let (grants, roles, permissions) = database
+            .read_txn(move |txn| {
+                Ok((
+                    user_move.role_grants(txn)?,
+                    user_move.roles(txn)?,
+                    user_move.permissions(txn)?,
+                ))
+            })
+            .await?;
+        assert_eq!(grants.len(), 2);
+        let names = roles
+            .iter()
+            .map(|role| role.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["granter", "reader"]);
+        assert!(permissions.allows("users:read"));
+        assert!(permissions.allows("grants:write"));
+        assert!(!permissions.allows("roles:write"));
+
+        let user_move = user.clone();
+        let revoked = database
+            .write_txn(move |txn| {
+                let granter = Role::retrieve_by_name(txn, "granter")?.unwrap();
+                Ok((
+                    user_move.revoke_role(txn, &granter)?,
+                    user_move.revoke_role(txn, &granter)?,
+                ))
+            })
+            .await?;
+        assert_eq!(revoked, (true, false));
+
+        let permissions = database
+            .read_txn(move |txn| Ok((user.permissions(txn)?, other.permissions(txn)?)))
+            .await?;
+        assert!(!permissions.0.allows("grants:write"));
+        assert!(permissions.0.allows("users:read"));
+        assert!(permissions.1.allows("users:read"));
+
+        // The migrations seed an `admin` role with every permission.
+        let admin = database
+            .read_txn(|txn| Ok(Role::retrieve_by_name(txn, "admin")?))
+            .await?
+            .unwrap();
+        assert_eq!(admin.permissions, ["*"]);
+        Ok(())
+    }
+}
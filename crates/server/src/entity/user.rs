@@ -1,10 +1,12 @@
 use anyhow::Result;
-use rusqlite::{params, params_from_iter, Transaction};
+use chrono::{DateTime, Utc};
+use rusqlite::{params, params_from_iter, Connection};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
 use super::identity_user::IdentityUser;
+use crate::database::WriteTxn;
 use entity_macro::ToSql;
 
 #[cfg(test)]
@@ -12,16 +14,26 @@ use crate::test::TestContext;
 
 /// This struct represents a record in the `users` table.
 #[derive(Clone, Debug, Default, Deserialize, PartialEq, JsonSchema, Serialize, ToSql)]
+#[entity(table = "users", timestamps, soft_delete)]
 pub struct User {
     /// Unique identifier for the user.
     pub id: Uuid,
+    /// When the user was created.
+    pub created_at: DateTime<Utc>,
+    /// When the user was last updated.
+    pub updated_at: DateTime<Utc>,
+    /// When the user was deleted, if it has been.
+    pub deleted_at: Option<DateTime<Utc>>,
+    /// Incremented on every update, and returned as the `ETag` of the user.
+    #[entity(version)]
+    pub version: i64,
 }
 
 impl User {
     /// Creates an  associated `IdentityUser` for this `User`.
-    pub fn create_identity_user(&self, txn: &Transaction, id: &Uuid) -> Result<IdentityUser> {
-        let identity_user = IdentityUser::new(*id, self.id);
-        identity_user.upsert(txn)?;
+    pub fn create_identity_user(&self, txn: &WriteTxn, id: &Uuid) -> Result<IdentityUser> {
+        let mut identity_user = IdentityUser::new(*id, self.id);
+        identity_user.insert(txn)?;
         Ok(identity_user)
     }
 
@@ -38,13 +50,11 @@ pub mod test {
 
     pub struct Users;
     impl crate::test::Fixture for Users {
-        fn try_fixtures(&self, txn: &Transaction) -> Result<()> {
-            serde_json::from_str::<Vec<User>>(&std::fs::read_to_string(std::path::Path::new(
-                "fixtures/users.json",
-            ))?)?
-            .iter()
-            .map(|entity| Ok(entity.upsert(txn)?))
-            .collect::<Result<Vec<_>>>()?;
+        fn try_fixtures(&self, txn: &WriteTxn) -> Result<()> {
+            let users = serde_json::from_str::<Vec<User>>(&std::fs::read_to_string(
+                std::path::Path::new("fixtures/users.json"),
+            )?)?;
+            User::insert_many(txn, &users)?;
             Ok(())
         }
     }
@@ -55,23 +65,20 @@ pub mod test {
 
         let entity = User::default();
 
-        let entity_move = entity.clone();
-        context
+        // Upserting sets the timestamps, so compare with the upserted entity.
+        let mut entity_move = entity.clone();
+        let entity = context
             .database()
-            .write(move |connection| {
-                let txn = connection.transaction()?;
-                entity_move.upsert(&txn)?;
-                Ok(txn.commit()?)
+            .write_txn(move |txn| {
+                entity_move.upsert(txn)?;
+                Ok(entity_move)
             })
             .await?;
 
         let entity_move = entity.clone();
         let retrieved_entity = context
             .database()
-            .read(move |connection| {
-                let txn = connection.transaction()?;
-                Ok(User::retrieve(&txn, &entity_move.id)?)
-            })
+            .read_txn(move |txn| Ok(User::retrieve(txn, &entity_move.id)?))
             .await?
             .unwrap();
         assert_eq!(retrieved_entity, entity);
@@ -1,8 +1,36 @@
 use dropshot::HttpError;
 
-/// Map any database error to a 500 "Internal Server Error"
-impl From<super::database::Error> for HttpError {
-    fn from(val: super::database::Error) -> Self {
-        HttpError::for_internal_error(val.to_string())
+use super::database::{EntityError, Error};
+
+/// Map a database error to a 503 "Service Unavailable" if the database is overloaded or timed
+/// out, an entity error to its status, otherwise to a 500 "Internal Server Error"
+impl From<Error> for HttpError {
+    fn from(val: Error) -> Self {
+        match val {
+            Error::Overloaded | Error::Timeout => HttpError::for_unavail(None, val.to_string()),
+            Error::Entity(err) => err.into(),
+            _ => HttpError::for_internal_error(val.to_string()),
+        }
+    }
+}
+
+/// Map an entity error to a 404 "Not Found" if the row does not exist, a 409 "Conflict" if it
+/// violates a primary key, unique or foreign key constraint or has been updated since it was
+/// read, a 400 "Bad Request" naming the invalid fields if it failed validation or for an invalid
+/// cursor, otherwise to a 500 "Internal Server Error"
+impl From<EntityError> for HttpError {
+    fn from(val: EntityError) -> Self {
+        match val {
+            EntityError::NotFound { .. } => HttpError::for_not_found(None, val.to_string()),
+            EntityError::Conflict { .. }
+            | EntityError::ForeignKey { .. }
+            | EntityError::VersionConflict { .. } => {
+                HttpError::for_client_error(None, http::StatusCode::CONFLICT, val.to_string())
+            }
+            EntityError::Invalid { .. } | EntityError::InvalidCursor { .. } => {
+                HttpError::for_bad_request(None, val.to_string())
+            }
+            _ => HttpError::for_internal_error(val.to_string()),
+        }
     }
 }
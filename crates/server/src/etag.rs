@@ -0,0 +1,81 @@
+use dropshot::{HttpError, RequestContext};
+use http::HeaderMap;
+use schemars::JsonSchema;
+use serde::Serialize;
+
+/// The `ETag` response header of an entity with an `#[entity(version)]`, its version quoted.
+#[derive(Debug, JsonSchema, Serialize)]
+pub struct ETagHeader {
+    /// The version of the entity, to send back in `If-Match` to update it only if it has not
+    /// changed since.
+    #[serde(rename = "ETag")]
+    pub etag: String,
+}
+
+impl ETagHeader {
+    pub fn new(version: i64) -> Self {
+        Self {
+            etag: format!("\"{version}\""),
+        }
+    }
+}
+
+/// The version in the `If-Match` header of the request, or `None` if it is absent or `*`.
+///
+/// A write given the version fails with `EntityError::VersionConflict`, a 409 "Conflict", if the
+/// entity has been updated since.
+///
+/// # Failure
+///
+/// Will return a 400 "Bad Request" if the header is not a single `ETag` returned by the API.
+pub fn if_match<C: dropshot::ServerContext>(
+    rqctx: &RequestContext<C>,
+) -> Result<Option<i64>, HttpError> {
+    parse_if_match(rqctx.request.headers())
+}
+
+fn parse_if_match(headers: &HeaderMap) -> Result<Option<i64>, HttpError> {
+    let Some(value) = headers.get(http::header::IF_MATCH) else {
+        return Ok(None);
+    };
+    match value.to_str().map(str::trim) {
+        Ok("*") => Ok(None),
+        Ok(value) => value
+            .strip_prefix('"')
+            .and_then(|value| value.strip_suffix('"'))
+            .and_then(|version| version.parse().ok())
+            .map(Some)
+            .ok_or_else(|| HttpError::for_bad_request(None, format!("invalid If-Match {value}"))),
+        Err(_) => Err(HttpError::for_bad_request(
+            None,
+            "invalid If-Match".to_string(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use http::HeaderValue;
+
+    fn parse(value: &'static str) -> Result<Option<i64>, HttpError> {
+        let mut headers = HeaderMap::new();
+        headers.insert(http::header::IF_MATCH, HeaderValue::from_static(value));
+        parse_if_match(&headers)
+    }
+
+    #[test]
+    fn parse_if_match_values() {
+        assert_eq!(parse_if_match(&HeaderMap::new()).unwrap(), None);
+        assert_eq!(parse("\"1\"").unwrap(), Some(1));
+        assert_eq!(parse(" \"42\" ").unwrap(), Some(42));
+        assert_eq!(parse("*").unwrap(), None);
+        assert_eq!(ETagHeader::new(7).etag, "\"7\"");
+
+        // The API only returns strong ETags, one at a time.
+        for value in ["W/\"1\"", "\"1\", \"2\"", "1", "\"one\"", ""] {
+            let err = parse(value).unwrap_err();
+            assert_eq!(err.status_code, http::StatusCode::BAD_REQUEST);
+        }
+    }
+}
@@ -1,6 +1,16 @@
-use crate::{api::ServerApi, entity::user::User};
+use crate::{
+    api::{RolePath, RolePermissions, ServerApi, UserPath, UserRolePath},
+    auth::{permission, require_permission, AuthenticatedUser},
+    database::{EntityError, WriteTxn},
+    entity::{identity_user::IdentityUser, role::Role, user::User},
+    etag::{if_match, ETagHeader},
+};
 use anyhow::Result;
-use dropshot::{HttpError, HttpResponseOk, RequestContext};
+use dropshot::{
+    HttpError, HttpResponseDeleted, HttpResponseHeaders, HttpResponseOk,
+    HttpResponseUpdatedNoContent, Path, RequestContext, TypedBody,
+};
+use uuid::Uuid;
 
 pub(crate) enum ServerImpl {}
 
@@ -9,16 +19,193 @@ impl ServerApi for ServerImpl {
 
     #[doc = " Get the value of the counter."]
     async fn get_user(
+        _rqctx: RequestContext<Self::Context>,
+        user: AuthenticatedUser,
+    ) -> Result<HttpResponseHeaders<HttpResponseOk<User>, ETagHeader>, HttpError> {
+        let user = user.into_inner();
+        let etag = ETagHeader::new(user.version);
+        Ok(HttpResponseHeaders::new(HttpResponseOk(user), etag))
+    }
+
+    #[doc = " Delete the current user."]
+    async fn delete_user(
+        rqctx: RequestContext<Self::Context>,
+        user: AuthenticatedUser,
+    ) -> Result<HttpResponseDeleted, HttpError> {
+        let user = user.into_inner();
+        let version = if_match(&rqctx)?;
+        let identity_users = rqctx
+            .context()
+            .database()
+            .write_txn(move |txn| {
+                // Compare with the stored user, which may have changed since it was read.
+                let stored = User::retrieve(txn, &user.id)?
+                    .ok_or_else(|| EntityError::not_found("User", user.id))?;
+                if let Some(version) = version.filter(|version| *version != stored.version) {
+                    return Err(EntityError::version_conflict("User", user.id, version).into());
+                }
+                // The identities are unlinked, so that logging in again creates a new User.
+                let identity_users = user.identity_users(txn)?;
+                IdentityUser::delete_many(
+                    txn,
+                    &identity_users
+                        .iter()
+                        .map(|identity_user| identity_user.id)
+                        .collect::<Vec<_>>(),
+                )?;
+                User::delete(txn, &user.id)?;
+                Ok(identity_users)
+            })
+            .await?;
+
+        // The cached sessions of the identities would otherwise still authenticate them as the
+        // deleted User until they expire.
+        let kratos = rqctx.context().kratos();
+        if let Some(credential) = kratos.session_credential(&rqctx.request) {
+            kratos.session_cache.invalidate(credential);
+        }
+        invalidate_sessions(&rqctx, &identity_users);
+        Ok(HttpResponseDeleted())
+    }
+
+    #[doc = " List the roles."]
+    async fn list_roles(
+        rqctx: RequestContext<Self::Context>,
+        user: AuthenticatedUser,
+    ) -> Result<HttpResponseOk<Vec<Role>>, HttpError> {
+        require_permission(&rqctx, user, permission::ROLES_READ).await?;
+        let roles = rqctx
+            .context()
+            .database()
+            .read_txn(|txn| Ok(Role::retrieve_all(txn)?))
+            .await?;
+        Ok(HttpResponseOk(roles))
+    }
+
+    #[doc = " Create a role or replace its permissions."]
+    async fn put_role(
+        rqctx: RequestContext<Self::Context>,
+        user: AuthenticatedUser,
+        path: Path<RolePath>,
+        body: TypedBody<RolePermissions>,
+    ) -> Result<HttpResponseOk<Role>, HttpError> {
+        require_permission(&rqctx, user, permission::ROLES_WRITE).await?;
+        let name = path.into_inner().role;
+        let permissions = body.into_inner().permissions;
+        let role = rqctx
+            .context()
+            .database()
+            .write_txn(move |txn| {
+                let role = match Role::retrieve_by_name(txn, &name)? {
+                    Some(mut role) => {
+                        role.permissions = permissions;
+                        role.update(txn)?;
+                        role
+                    }
+                    None => {
+                        let mut role = Role::new(Uuid::new_v4(), name, permissions);
+                        role.insert(txn)?;
+                        role
+                    }
+                };
+                Ok(role)
+            })
+            .await?;
+        Ok(HttpResponseOk(role))
+    }
+
+    #[doc = " List the roles granted to a user."]
+    async fn list_user_roles(
         rqctx: RequestContext<Self::Context>,
-    ) -> Result<HttpResponseOk<User>, HttpError> {
-        Ok(HttpResponseOk(User::try_from(&rqctx).await?))
+        user: AuthenticatedUser,
+        path: Path<UserPath>,
+    ) -> Result<HttpResponseOk<Vec<Role>>, HttpError> {
+        require_permission(&rqctx, user, permission::GRANTS_READ).await?;
+        let user_id = path.into_inner().user_id;
+        let roles = rqctx
+            .context()
+            .database()
+            .read_txn(move |txn| {
+                let user = User::retrieve(txn, &user_id)?
+                    .ok_or_else(|| EntityError::not_found("User", user_id))?;
+                Ok(user.roles(txn)?)
+            })
+            .await?;
+        Ok(HttpResponseOk(roles))
+    }
+
+    #[doc = " Grant a role to a user."]
+    async fn grant_role(
+        rqctx: RequestContext<Self::Context>,
+        user: AuthenticatedUser,
+        path: Path<UserRolePath>,
+    ) -> Result<HttpResponseUpdatedNoContent, HttpError> {
+        require_permission(&rqctx, user, permission::GRANTS_WRITE).await?;
+        let path = path.into_inner();
+        let identity_users = rqctx
+            .context()
+            .database()
+            .write_txn(move |txn| {
+                let (user, role) = user_role(txn, &path)?;
+                user.grant_role(txn, &role)?;
+                Ok(user.identity_users(txn)?)
+            })
+            .await?;
+        invalidate_sessions(&rqctx, &identity_users);
+        Ok(HttpResponseUpdatedNoContent())
+    }
+
+    #[doc = " Revoke a role from a user."]
+    async fn revoke_role(
+        rqctx: RequestContext<Self::Context>,
+        user: AuthenticatedUser,
+        path: Path<UserRolePath>,
+    ) -> Result<HttpResponseDeleted, HttpError> {
+        require_permission(&rqctx, user, permission::GRANTS_WRITE).await?;
+        let path = path.into_inner();
+        let identity_users = rqctx
+            .context()
+            .database()
+            .write_txn(move |txn| {
+                let (user, role) = user_role(txn, &path)?;
+                user.revoke_role(txn, &role)?;
+                Ok(user.identity_users(txn)?)
+            })
+            .await?;
+        invalidate_sessions(&rqctx, &identity_users);
+        Ok(HttpResponseDeleted())
+    }
+}
+
+/// Forgets the cached sessions of the identities, so that their next requests are verified with
+/// Kratos again.
+fn invalidate_sessions(
+    rqctx: &RequestContext<crate::context::Context>,
+    identity_users: &[IdentityUser],
+) {
+    for identity_user in identity_users {
+        rqctx
+            .context()
+            .kratos()
+            .session_cache
+            .invalidate_identity(identity_user.id);
     }
 }
 
+/// The user and role named by `path`, or `EntityError::NotFound` if either does not exist.
+fn user_role(txn: &WriteTxn, path: &UserRolePath) -> Result<(User, Role), EntityError> {
+    let user = User::retrieve(txn, &path.user_id)?
+        .ok_or_else(|| EntityError::not_found("User", path.user_id))?;
+    let role = Role::retrieve_by_name(txn, &path.role)?
+        .ok_or_else(|| EntityError::not_found("Role", &path.role))?;
+    Ok((user, role))
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::test::TestContext;
+    use crate::kratos::SessionCredential;
+    use crate::test::{client, TestContext};
     use http::HeaderMap;
     use uuid::Uuid;
 
@@ -28,18 +215,17 @@ mod test {
 
         let user = User::new(Uuid::new_v4());
 
-        let user_move = user.clone();
+        let mut user_move = user.clone();
         let identity = context
             .kratos()
             .create_user("email@email.com", "f9456f3c-0398-452a-92c4-15c6f8f3158f")
             .await?;
         context
             .database()
-            .write(move |connection| {
-                let txn = connection.transaction()?;
-                user_move.upsert(&txn)?;
-                user_move.create_identity_user(&txn, &Uuid::parse_str(&identity.id).unwrap())?;
-                Ok(txn.commit()?)
+            .write_txn(move |txn| {
+                user_move.upsert(txn)?;
+                user_move.create_identity_user(txn, &Uuid::parse_str(&identity.id).unwrap())?;
+                Ok(())
             })
             .await?;
 
@@ -62,4 +248,153 @@ mod test {
 
         Ok(())
     }
+
+    /// Headers authenticating as the identity with a JWT, followed by `headers`.
+    fn authenticated(
+        context: &TestContext,
+        identity_id: Uuid,
+        headers: &[(&'static str, &'static str)],
+    ) -> HeaderMap {
+        let mut header_map = HeaderMap::new();
+        header_map.insert(
+            http::header::AUTHORIZATION,
+            context.kratos().session_jwt(identity_id).parse().unwrap(),
+        );
+        for (name, value) in headers {
+            header_map.insert(*name, value.parse().unwrap());
+        }
+        header_map
+    }
+
+    /// Inserts a User linked to the identity.
+    async fn insert_user(context: &TestContext, identity_id: Uuid) -> Result<User> {
+        Ok(context
+            .database()
+            .write_txn(move |txn| {
+                let mut user = User::new(Uuid::new_v4());
+                user.insert(txn)?;
+                user.create_identity_user(txn, &identity_id)?;
+                Ok(user)
+            })
+            .await?)
+    }
+
+    #[tokio::test]
+    pub async fn delete_user_checks_if_match() -> Result<()> {
+        let context = TestContext::new(vec![]).await?;
+        let identity = context
+            .kratos()
+            .create_user("delete@email.com", "f9456f3c-0398-452a-92c4-15c6f8f3158f")
+            .await?;
+        let identity_id = Uuid::parse_str(&identity.id)?;
+        let user = insert_user(&context, identity_id).await?;
+
+        // A stale version is a conflict and leaves the user in place.
+        let stale = context.client(Some(authenticated(
+            &context,
+            identity_id,
+            &[("If-Match", "\"0\"")],
+        )));
+        let err = stale.delete_user().await.unwrap_err();
+        assert_eq!(err.status(), Some(reqwest::StatusCode::CONFLICT));
+        let client = context.client(Some(authenticated(&context, identity_id, &[])));
+        assert_eq!(client.get_user().await?.into_inner().id, user.id);
+
+        let current = context.client(Some(authenticated(
+            &context,
+            identity_id,
+            &[("If-Match", "\"1\"")],
+        )));
+        let session_cache = &context.kratos().session_cache;
+        session_cache.insert_active(SessionCredential::Token("other device"), identity_id, None);
+        current.delete_user().await?;
+        // Every cached session of the identity is forgotten.
+        assert_eq!(
+            session_cache.get(SessionCredential::Token("other device")),
+            None
+        );
+
+        // The identity is unlinked, so that it gets a new user rather than the deleted one.
+        let recreated = client.get_user().await?.into_inner();
+        assert_ne!(recreated.id, user.id);
+        Ok(())
+    }
+
+    /// The status and error code of a failed call.
+    fn failure<T: std::fmt::Debug>(
+        result: Result<T, client::Error<client::types::Error>>,
+    ) -> (Option<reqwest::StatusCode>, Option<String>) {
+        let err = result.unwrap_err();
+        let error_code = match &err {
+            client::Error::ErrorResponse(response) => response.error_code.clone(),
+            _ => None,
+        };
+        (err.status(), error_code)
+    }
+
+    #[tokio::test]
+    pub async fn roles_require_permissions() -> Result<()> {
+        let context = TestContext::new(vec![]).await?;
+        let (identity_id, admin_identity_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let user = insert_user(&context, identity_id).await?;
+        let admin = insert_user(&context, admin_identity_id).await?;
+        context
+            .database()
+            .write_txn(move |txn| {
+                let role = Role::retrieve_by_name(txn, "admin")?.unwrap();
+                admin.grant_role(txn, &role)?;
+                Ok(())
+            })
+            .await?;
+        let client = context.client(Some(authenticated(&context, identity_id, &[])));
+        let admin = context.client(Some(authenticated(&context, admin_identity_id, &[])));
+
+        let (status, error_code) = failure(client.list_roles().await);
+        assert_eq!(status, Some(reqwest::StatusCode::FORBIDDEN));
+        assert_eq!(error_code.as_deref(), Some("MissingPermission"));
+
+        let invalid = client::types::RolePermissions {
+            permissions: vec!["Roles:Read".to_string()],
+        };
+        let (status, _) = failure(admin.put_role("reader", &invalid).await);
+        assert_eq!(status, Some(reqwest::StatusCode::BAD_REQUEST));
+        let reader = client::types::RolePermissions {
+            permissions: vec!["roles:read".to_string()],
+        };
+        let role = admin.put_role("reader", &reader).await?.into_inner();
+        assert_eq!(role.permissions, ["roles:read"]);
+
+        // Granting and revoking twice is the same as doing it once, and both forget the cached
+        // sessions of the user.
+        let session_cache = &context.kratos().session_cache;
+        let session = SessionCredential::Token("session");
+        session_cache.insert_active(session, identity_id, None);
+        admin.grant_role(&user.id, "reader").await?;
+        assert_eq!(session_cache.get(session), None);
+        admin.grant_role(&user.id, "reader").await?;
+        let roles = admin.list_user_roles(&user.id).await?.into_inner();
+        assert_eq!(roles.len(), 1);
+        assert_eq!(roles[0].name, "reader");
+        assert!(client.list_roles().await.is_ok());
+
+        session_cache.insert_active(session, identity_id, None);
+        admin.revoke_role(&user.id, "reader").await?;
+        assert_eq!(session_cache.get(session), None);
+        admin.revoke_role(&user.id, "reader").await?;
+        assert!(admin.list_user_roles(&user.id).await?.is_empty());
+        let (status, _) = failure(client.list_roles().await);
+        assert_eq!(status, Some(reqwest::StatusCode::FORBIDDEN));
+
+        // An unknown user or role is not found.
+        let unknown = Uuid::new_v4();
+        let (status, _) = failure(admin.list_user_roles(&unknown).await);
+        assert_eq!(status, Some(reqwest::StatusCode::NOT_FOUND));
+        let (status, _) = failure(admin.grant_role(&unknown, "reader").await);
+        assert_eq!(status, Some(reqwest::StatusCode::NOT_FOUND));
+        let (status, _) = failure(admin.grant_role(&user.id, "writer").await);
+        assert_eq!(status, Some(reqwest::StatusCode::NOT_FOUND));
+        let (status, _) = failure(admin.revoke_role(&user.id, "writer").await);
+        assert_eq!(status, Some(reqwest::StatusCode::NOT_FOUND));
+        Ok(())
+    }
 }
@@ -1,18 +1,49 @@
+mod cookie;
+mod jwt;
+mod session_cache;
+
 use std::ops::Not;
 
-use dropshot::{HttpError, RequestContext};
+use chrono::{DateTime, Utc};
+use dropshot::{HttpError, RequestInfo};
 use kratos::apis::{configuration::Configuration, frontend_api::ToSessionError};
 use uuid::Uuid;
 
+pub use cookie::SessionCookieConfig;
+pub use jwt::{JwtConfig, JwtVerifier};
+pub use session_cache::{CachedSession, SessionCache, SessionCacheConfig};
+
 use crate::{
     context,
-    entity::{identity_user::IdentityUser, user::User},
+    database::WriteTxn,
+    entity::{identity_user::IdentityUser, role::Role, user::User},
 };
 
 #[derive(Debug, Clone)]
 pub struct Kratos {
     pub public_configuration: Configuration,
     pub admin_configuration: Configuration,
+    pub session_cache: SessionCache,
+    /// Verifies `Authorization: Bearer` JWTs without asking Kratos, if configured.
+    pub jwt: Option<JwtVerifier>,
+    pub session_cookie: SessionCookieConfig,
+}
+
+/// What a request presents to prove it has a Kratos session.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SessionCredential<'a> {
+    /// A session token from the `X-Session-Token` header or an `Authorization: Bearer` header.
+    Token(&'a str),
+    /// The `name=value` pair of the session cookie of the Kratos browser flows.
+    Cookie(&'a str),
+}
+
+/// What a request presents to authenticate, see [`Kratos::presented`].
+enum Presented<'a> {
+    /// A JWT from the Kratos session tokenizer and the verifier to verify it with.
+    Jwt(&'a str, &'a JwtVerifier),
+    /// A session token or cookie to verify with Kratos.
+    Session(SessionCredential<'a>),
 }
 
 impl Kratos {
@@ -29,33 +60,156 @@ impl Kratos {
         Self {
             public_configuration,
             admin_configuration,
+            session_cache: SessionCache::new(SessionCacheConfig::default()),
+            jwt: None,
+            session_cookie: SessionCookieConfig::default(),
         }
     }
-}
 
-impl User {
-    /// Try to create a User from a request context.
+    /// Sets the settings of the session cache.
+    pub fn session_cache(mut self, config: SessionCacheConfig) -> Self {
+        self.session_cache = SessionCache::new(config);
+        self
+    }
+
+    /// Sets the settings of the browser session cookie.
+    pub fn session_cookie(mut self, config: SessionCookieConfig) -> Self {
+        self.session_cookie = config;
+        self
+    }
+
+    /// Sets the verifier of JWTs issued by the Kratos session tokenizer.
+    pub fn jwt(mut self, verifier: JwtVerifier) -> Self {
+        self.jwt = Some(verifier);
+        self
+    }
+
+    /// The identity of the active session of `credential`.
     ///
-    /// This function attempts to retrieve the user from the request context, falling back to creating a new user if not found.
+    /// The outcome is cached in [`Kratos::session_cache`], so that Kratos is only asked again once
+    /// the cached entry expires or is invalidated.
     ///
-    /// The `X-Session-Token` header is used to authenticate the request and verify the user's session.
-    pub async fn try_from(rqctx: &RequestContext<context::Context>) -> Result<Self, HttpError> {
-        // Check if the `X-Session-Token` header is present and can be decoded to a string.
-        let session_token = match rqctx
-            .request
-            .headers()
-            .get("X-Session-Token")
-            .map(|v| v.to_str())
-        {
-            Some(Ok(session_token)) => session_token,
+    /// # Failure
+    ///
+    /// Will return a 401 "Unauthorized" if the session is not active or has no identity, and a
+    /// 500 "Internal Server Error" if Kratos could not be asked.
+    pub async fn verify_session(
+        &self,
+        credential: SessionCredential<'_>,
+    ) -> Result<Uuid, HttpError> {
+        match self.session_cache.get(credential) {
+            Some(CachedSession::Active(identity_id)) => return Ok(identity_id),
+            Some(CachedSession::Rejected) => {
+                return Err(HttpError::for_status(None, http::StatusCode::UNAUTHORIZED))
+            }
+            None => (),
+        }
+
+        match self.to_session(credential).await {
+            Ok((identity_id, expires_at)) => {
+                self.session_cache
+                    .insert_active(credential, identity_id, expires_at);
+                Ok(identity_id)
+            }
+            // Only rejections are cached, errors reaching Kratos are retried on the next request.
+            Err(err) if err.status_code == http::StatusCode::UNAUTHORIZED => {
+                self.session_cache.insert_rejected(credential);
+                Err(err)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// The session token or cookie `request` is authenticated by, which is what
+    /// [`Kratos::session_cache`] caches, or `None` if it is not authenticated by one.
+    pub fn session_credential<'a>(
+        &'a self,
+        request: &'a RequestInfo,
+    ) -> Option<SessionCredential<'a>> {
+        match self.presented(request) {
+            Ok(Presented::Session(credential)) => Some(credential),
+            _ => None,
+        }
+    }
+
+    /// What `request` presents to authenticate.
+    ///
+    /// # Failure
+    ///
+    /// Will return a 401 "Unauthorized" if it presents nothing, and a 403 "Forbidden" if it
+    /// presents the session cookie from an origin that is not allowed.
+    fn presented<'a>(&'a self, request: &'a RequestInfo) -> Result<Presented<'a>, HttpError> {
+        let headers = request.headers();
+        let bearer = headers
+            .get(http::header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| {
+                v.strip_prefix("Bearer ")
+                    .or_else(|| v.strip_prefix("bearer "))
+            })
+            .map(str::trim);
+
+        if let (Some(token), Some(jwt)) = (bearer, &self.jwt) {
+            if jwt::is_jwt(token) {
+                return Ok(Presented::Jwt(token, jwt));
+            }
+        }
+
+        // Otherwise the `X-Session-Token` header, a session token sent as a bearer token or the
+        // session cookie of a browser must be present and decodable to a string.
+        let credential = match (headers.get("X-Session-Token").map(|v| v.to_str()), bearer) {
+            (Some(Ok(session_token)), _) | (None, Some(session_token)) => {
+                SessionCredential::Token(session_token)
+            }
+            (None, None) => match self.session_cookie.find(headers) {
+                Some(cookie) => {
+                    // Unlike the headers, browsers send the cookie whichever site made the
+                    // request.
+                    self.session_cookie.check_csrf(request.method(), headers)?;
+                    SessionCredential::Cookie(cookie)
+                }
+                None => return Err(HttpError::for_status(None, http::StatusCode::UNAUTHORIZED)),
+            },
             _ => return Err(HttpError::for_status(None, http::StatusCode::UNAUTHORIZED)),
         };
+        Ok(Presented::Session(credential))
+    }
+
+    /// The names of the roles in the `roles` of the identity's `metadata_admin`, which only the
+    /// Kratos admin API returns, e.g. `{"roles": ["admin"]}`.
+    ///
+    /// # Failure
+    ///
+    /// Will return a 500 "Internal Server Error" if the identity could not be retrieved.
+    pub async fn admin_roles(&self, identity_id: Uuid) -> Result<Vec<String>, HttpError> {
+        let identity = kratos::apis::identity_api::get_identity(
+            &self.admin_configuration,
+            &identity_id.to_string(),
+            None,
+        )
+        .await
+        .map_err(|err| HttpError::for_internal_error(err.to_string()))?;
+        Ok(roles_from_metadata(
+            identity.metadata_admin.flatten().as_ref(),
+        ))
+    }
+
+    /// Resolves `credential` with Kratos to the identity of its session and when the session
+    /// expires.
+    async fn to_session(
+        &self,
+        credential: SessionCredential<'_>,
+    ) -> Result<(Uuid, Option<DateTime<Utc>>), HttpError> {
+        let (session_token, cookie) = match credential {
+            SessionCredential::Token(session_token) => (Some(session_token), None),
+            SessionCredential::Cookie(cookie) => (None, Some(cookie)),
+        };
 
-        // Use the Kratos API to resolve the session associated with the `session_token`.
+        // Use the Kratos API to resolve the session associated with the `credential`.
         let session = match kratos::apis::frontend_api::to_session(
-            &rqctx.context().kratos().public_configuration,
-            Some(session_token),
-            None,
+            &self.public_configuration,
+            session_token,
+            cookie,
             None,
         )
         .await
@@ -103,34 +257,104 @@ impl User {
             Err(_) => return Err(HttpError::for_status(None, http::StatusCode::UNAUTHORIZED)),
         };
 
+        // A malformed expiry is treated as no expiry, which is still bounded by the max TTL.
+        let expires_at = session
+            .expires_at
+            .as_deref()
+            .and_then(|expires_at| DateTime::parse_from_rfc3339(expires_at).ok())
+            .map(|expires_at| expires_at.with_timezone(&Utc));
+
+        Ok((identity_id, expires_at))
+    }
+}
+
+/// The strings in the `roles` array of `metadata`, ignoring anything else.
+fn roles_from_metadata(metadata: Option<&serde_json::Value>) -> Vec<String> {
+    metadata
+        .and_then(|metadata| metadata.get("roles"))
+        .and_then(|roles| roles.as_array())
+        .into_iter()
+        .flatten()
+        .filter_map(|role| role.as_str().map(str::to_string))
+        .collect()
+}
+
+/// The User of the identity `identity_id`, created with the roles named `role_names` that exist
+/// if the identity does not have one yet or its User has been deleted.
+///
+/// Concurrent first requests of an identity all miss its IdentityUser when reading, so it is
+/// looked up again here, where writes are serialized, and only the first creates the User.
+fn first_login(txn: &WriteTxn, identity_id: &Uuid, role_names: &[String]) -> anyhow::Result<User> {
+    if let Some(identity_user) = IdentityUser::retrieve(txn, identity_id)? {
+        match User::retrieve(txn, &identity_user.user_id)? {
+            Some(user) => return Ok(user),
+            // A link left behind by a deleted User is replaced.
+            None => IdentityUser::delete(txn, &identity_user.id)?,
+        }
+    }
+
+    let mut user = User::new(Uuid::new_v4());
+    user.insert(txn)?;
+    user.create_identity_user(txn, identity_id)?;
+    for name in role_names {
+        if let Some(role) = Role::retrieve_by_name(txn, name)? {
+            user.grant_role(txn, &role)?;
+        }
+    }
+    Ok(user)
+}
+
+impl User {
+    /// Try to create a User from a request.
+    ///
+    /// This function attempts to retrieve the user the request is authenticated as, falling back to creating a new user if not found.
+    /// Handlers take an [`AuthenticatedUser`](crate::auth::AuthenticatedUser) rather than calling it.
+    ///
+    /// The `X-Session-Token` header is used to authenticate the request and verify the user's session.
+    /// An `Authorization: Bearer` JWT from the Kratos session tokenizer is accepted instead, and
+    /// verified against the JWKS without calling Kratos, even on first login. Browsers are authenticated by the Kratos
+    /// session cookie, and their state-changing requests must come from an allowed origin.
+    pub async fn authenticate(
+        context: &context::Context,
+        request: &RequestInfo,
+    ) -> Result<Self, HttpError> {
+        let kratos = context.kratos();
+        let (identity_id, claimed_roles) = match kratos.presented(request)? {
+            // A JWT from the Kratos session tokenizer is verified locally, and carries the roles.
+            Presented::Jwt(token, jwt) => {
+                let (identity_id, roles) = jwt.verify(token).await?;
+                (identity_id, Some(roles))
+            }
+            // Resolve the session with Kratos, unless it has been verified recently.
+            Presented::Session(credential) => (kratos.verify_session(credential).await?, None),
+        };
+
         // If the IdentityUser already exists then retrieve the User.
-        // Otherwise Upsert a new User. This is safe because the validity of the token has been
+        // Otherwise create a new User. This is safe because the validity of the token has been
         // guaranteed by the Kratos instance.
         let identity_id_move = identity_id.clone();
-        let user = match rqctx
-            .context()
+        let user = match context
             .database()
-            .read(move |connection| {
-                let txn = connection.transaction()?;
-                Ok(IdentityUser::retrieve(&txn, &identity_id_move)?
-                    .map(|identity_user| identity_user.retrieve_user(&txn))
-                    .transpose()?)
+            .read_txn(move |txn| {
+                Ok(match IdentityUser::retrieve(txn, &identity_id_move)? {
+                    Some(identity_user) => User::retrieve(txn, &identity_user.user_id)?,
+                    None => None,
+                })
             })
             .await?
         {
             Some(user) => user,
             None => {
-                rqctx
-                    .context()
+                // On first login the roles listed by Kratos are granted to the new User. Roles
+                // that do not exist are ignored. A JWT lists them in its claims, so that it is
+                // still accepted while Kratos is unavailable.
+                let role_names = match claimed_roles {
+                    Some(roles) => roles,
+                    None => kratos.admin_roles(identity_id).await?,
+                };
+                context
                     .database()
-                    .write(move |connection| {
-                        let txn = connection.transaction()?;
-                        let user = User::new(Uuid::new_v4());
-                        user.upsert(&txn)?;
-                        user.create_identity_user(&txn, &identity_id_move)?;
-                        txn.commit()?;
-                        Ok(user)
-                    })
+                    .write_txn(move |txn| Ok(first_login(txn, &identity_id_move, &role_names)?))
                     .await?
             }
         };
@@ -148,6 +372,110 @@ mod test {
         IdentityWithCredentialsPasswordConfig, SuccessfulNativeLogin, VerifiableIdentityAddress,
     };
     use serde_json::json;
+    use uuid::Uuid;
+
+    use super::{jwt, JwtConfig, JwtVerifier, Kratos};
+    use crate::{
+        context::Context,
+        database::{Database, DatabaseConfig},
+        entity::{identity_user::IdentityUser, user::User},
+    };
+
+    #[test]
+    fn roles_from_metadata() {
+        let metadata = json!({"roles": ["admin", 7, "editor"], "plan": "pro"});
+        assert_eq!(
+            super::roles_from_metadata(Some(&metadata)),
+            ["admin", "editor"]
+        );
+        assert!(super::roles_from_metadata(Some(&json!({"roles": "admin"}))).is_empty());
+        assert!(super::roles_from_metadata(None).is_empty());
+    }
+
+    #[tokio::test]
+    async fn concurrent_first_logins_create_one_user() -> Result<()> {
+        let database = Database::open_in_memory(DatabaseConfig::default().readers(1)).await?;
+        let identity_id = Uuid::new_v4();
+        let first_login = || {
+            database.write_txn(move |txn| {
+                Ok(super::first_login(
+                    txn,
+                    &identity_id,
+                    &["admin".to_string(), "missing".to_string()],
+                )?)
+            })
+        };
+
+        let (first, second) = tokio::join!(first_login(), first_login());
+        let (first, second) = (first?, second?);
+        assert_eq!(first.id, second.id);
+
+        let (identity_user, roles) = database
+            .read_txn(move |txn| {
+                let identity_user = IdentityUser::retrieve(txn, &identity_id)?.unwrap();
+                Ok((identity_user, first.roles(txn)?))
+            })
+            .await?;
+        assert_eq!(identity_user.user_id, second.id);
+        assert_eq!(roles.len(), 1);
+        assert_eq!(roles[0].name, "admin");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn jwt_first_login_does_not_call_kratos() -> Result<()> {
+        // Nothing listens on these ports, so any call to Kratos fails.
+        let kratos = Kratos::new(1, 2).test_jwt();
+        let database = Database::open_in_memory(DatabaseConfig::default().readers(1)).await?;
+        let context = Context::new(database.clone(), kratos);
+
+        let identity_id = Uuid::new_v4();
+        let mut claims = jwt::test::claims(identity_id, chrono::Utc::now().timestamp() + 3600);
+        claims["metadata_admin"] = json!({"roles": ["admin"]});
+        let token = jwt::test::sign(
+            &jwt::test::key_pair(),
+            json!({"alg": "EdDSA", "kid": jwt::test::KID, "typ": "JWT"}),
+            claims,
+        );
+        let request = http::Request::builder()
+            .header(http::header::AUTHORIZATION, format!("Bearer {token}"))
+            .body(())?;
+        let request = dropshot::RequestInfo::new(&request, "127.0.0.1:8080".parse()?);
+
+        let user = User::authenticate(&context, &request).await?;
+        let roles = database.read_txn(move |txn| Ok(user.roles(txn)?)).await?;
+        assert_eq!(roles.len(), 1);
+        assert_eq!(roles[0].name, "admin");
+
+        // A session credential still needs Kratos.
+        let request = http::Request::builder()
+            .header("X-Session-Token", "token")
+            .body(())?;
+        let request = dropshot::RequestInfo::new(&request, "127.0.0.1:8080".parse()?);
+        assert!(User::authenticate(&context, &request).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn first_login_replaces_a_deleted_user() -> Result<()> {
+        let database = Database::open_in_memory(DatabaseConfig::default().readers(1)).await?;
+        let identity_id = Uuid::new_v4();
+
+        let (deleted, user) = database
+            .write_txn(move |txn| {
+                let deleted = super::first_login(txn, &identity_id, &[])?;
+                User::delete(txn, &deleted.id)?;
+                Ok((deleted, super::first_login(txn, &identity_id, &[])?))
+            })
+            .await?;
+        assert_ne!(deleted.id, user.id);
+
+        let identity_user = database
+            .read_txn(move |txn| Ok(IdentityUser::retrieve(txn, &identity_id)?.unwrap()))
+            .await?;
+        assert_eq!(identity_user.user_id, user.id);
+        Ok(())
+    }
 
     impl super::Kratos {
         pub async fn create_user(
@@ -236,5 +564,30 @@ mod test {
             )
             .await?)
         }
+
+        /// Verifies the JWTs of [`Kratos::session_jwt`](super::Kratos::session_jwt), as a
+        /// `jwt` section naming `build/kratos/jwk.eddsa.json` would.
+        pub fn test_jwt(self) -> Self {
+            let verifier = JwtVerifier::new(
+                JwtConfig::default()
+                    .jwks(jwt::test::JWKS)
+                    .issuer(jwt::test::ISSUER),
+            )
+            .expect("load jwks")
+            .expect("verifier");
+            self.jwt(verifier)
+        }
+
+        /// An `Authorization: Bearer` JWT for `identity_id`, signed like those of the Kratos
+        /// session tokenizer, which authenticates without a Kratos session.
+        pub fn session_jwt(&self, identity_id: Uuid) -> String {
+            let exp = chrono::Utc::now().timestamp() + 3600;
+            let token = jwt::test::sign(
+                &jwt::test::key_pair(),
+                json!({"alg": "EdDSA", "kid": jwt::test::KID, "typ": "JWT"}),
+                jwt::test::claims(identity_id, exp),
+            );
+            format!("Bearer {token}")
+        }
     }
 }
@@ -0,0 +1,176 @@
+use dropshot::HttpError;
+use http::{HeaderMap, Method};
+use serde::Deserialize;
+
+/// Settings for authenticating browsers with the Kratos session cookie, deserializable from the
+/// `session_cookie` section of the server configuration.
+///
+/// Fields missing from the configuration take their value from [`SessionCookieConfig::default`].
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct SessionCookieConfig {
+    /// The name of the session cookie set by the Kratos browser flows.
+    pub name: String,
+    /// The origins, e.g. `https://app.example.com`, of the web apps allowed to make
+    /// state-changing requests authenticated by the cookie. Requests from the origin of the API
+    /// itself are always allowed, and none other by default.
+    pub allowed_origins: Vec<String>,
+}
+
+impl Default for SessionCookieConfig {
+    fn default() -> Self {
+        Self {
+            name: "ory_kratos_session".to_string(),
+            allowed_origins: Vec::new(),
+        }
+    }
+}
+
+impl SessionCookieConfig {
+    /// Sets the origins allowed to make state-changing requests.
+    pub fn allowed_origins(mut self, allowed_origins: Vec<String>) -> Self {
+        self.allowed_origins = allowed_origins;
+        self
+    }
+
+    /// The `name=value` pair of the session cookie in the `Cookie` headers, to forward to Kratos.
+    pub(crate) fn find<'a>(&self, headers: &'a HeaderMap) -> Option<&'a str> {
+        headers
+            .get_all(http::header::COOKIE)
+            .iter()
+            .filter_map(|header| header.to_str().ok())
+            .flat_map(|header| header.split(';'))
+            .map(str::trim)
+            .find(|pair| {
+                pair.split_once('=')
+                    .is_some_and(|(name, value)| name == self.name && !value.is_empty())
+            })
+    }
+
+    /// Protects a request authenticated by the cookie, which the browser sends whichever site
+    /// made it, against cross-site request forgery.
+    ///
+    /// Requests that do not change state (`GET`, `HEAD` and `OPTIONS`) are always allowed. Others
+    /// must come from the API's own origin or an allowed origin, as named by their `Origin`
+    /// header or, failing that, their `Referer`.
+    ///
+    /// # Failure
+    ///
+    /// Will return a 403 "Forbidden" if the request may be forged.
+    pub(crate) fn check_csrf(&self, method: &Method, headers: &HeaderMap) -> Result<(), HttpError> {
+        if matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS) {
+            return Ok(());
+        }
+
+        let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+        let Some(origin) = header(http::header::ORIGIN)
+            .filter(|origin| *origin != "null")
+            .or_else(|| header(http::header::REFERER).and_then(referer_origin))
+        else {
+            return Err(forbidden(
+                "missing Origin for a cookie-authenticated request",
+            ));
+        };
+
+        let same_origin = origin
+            .split_once("://")
+            .zip(header(http::header::HOST))
+            .is_some_and(|((_, host), api_host)| host == api_host);
+        let allowed = self
+            .allowed_origins
+            .iter()
+            .any(|allowed| allowed.trim_end_matches('/') == origin);
+        if same_origin || allowed {
+            Ok(())
+        } else {
+            Err(forbidden("cross-site request with the session cookie"))
+        }
+    }
+}
+
+/// The `scheme://host[:port]` of a `Referer` URL.
+fn referer_origin(referer: &str) -> Option<&str> {
+    let (scheme, rest) = referer.split_once("://")?;
+    let end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+    Some(&referer[..scheme.len() + 3 + end])
+}
+
+fn forbidden(message: &str) -> HttpError {
+    HttpError::for_client_error(None, http::StatusCode::FORBIDDEN, message.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use http::HeaderValue;
+
+    fn headers(pairs: &[(http::header::HeaderName, &'static str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.clone(), HeaderValue::from_static(value)))
+            .collect()
+    }
+
+    #[test]
+    fn find_session_cookie() {
+        let config = SessionCookieConfig::default();
+        let cookie = http::header::COOKIE;
+
+        let found = headers(&[(
+            cookie.clone(),
+            "theme=dark; ory_kratos_session=MTY3; lang=en",
+        )]);
+        assert_eq!(config.find(&found), Some("ory_kratos_session=MTY3"));
+
+        let empty = headers(&[(cookie.clone(), "ory_kratos_session=; other=1")]);
+        assert_eq!(config.find(&empty), None);
+        let prefixed = headers(&[(cookie, "xory_kratos_session=MTY3")]);
+        assert_eq!(config.find(&prefixed), None);
+        assert_eq!(config.find(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn check_csrf_of_state_changing_requests() {
+        use http::header::{HOST, ORIGIN, REFERER};
+
+        // The `selfservice.default_browser_return_url` of `build/kratos/kratos.yml`.
+        let config = SessionCookieConfig::default()
+            .allowed_origins(vec!["http://localhost:4455".to_string()]);
+        let host = (HOST, "127.0.0.1:8080");
+        let allowed = |method: Method, pairs: &[(http::header::HeaderName, &'static str)]| {
+            config.check_csrf(&method, &headers(pairs)).is_ok()
+        };
+
+        // Reads are never forged into changing state.
+        assert!(allowed(Method::GET, &[(ORIGIN, "https://evil.example")]));
+        assert!(allowed(Method::GET, &[]));
+
+        assert!(allowed(Method::PUT, &[(ORIGIN, "http://localhost:4455")]));
+        assert!(allowed(
+            Method::DELETE,
+            &[host.clone(), (ORIGIN, "http://127.0.0.1:8080")]
+        ));
+        assert!(allowed(
+            Method::POST,
+            &[(REFERER, "http://localhost:4455/settings?tab=1")]
+        ));
+
+        assert!(!allowed(Method::PUT, &[(ORIGIN, "https://evil.example")]));
+        assert!(!allowed(
+            Method::DELETE,
+            &[host.clone(), (ORIGIN, "http://127.0.0.1:8080.evil.example")]
+        ));
+        assert!(!allowed(Method::PUT, &[(ORIGIN, "null")]));
+        assert!(!allowed(Method::PUT, &[host]));
+        assert!(!allowed(
+            Method::POST,
+            &[(REFERER, "http://localhost:4455.evil.example/")]
+        ));
+
+        // Only the API's own origin is allowed by default.
+        let config = SessionCookieConfig::default();
+        assert!(config
+            .check_csrf(&Method::PUT, &headers(&[(ORIGIN, "http://localhost:4455")]))
+            .is_err());
+    }
+}
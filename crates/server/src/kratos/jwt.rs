@@ -0,0 +1,543 @@
+use std::{
+    path::Path,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use dropshot::HttpError;
+use ring::signature::{UnparsedPublicKey, ED25519};
+use serde::{de::DeserializeOwned, Deserialize};
+use uuid::Uuid;
+
+const POISONED: &str = "jwks lock poisoned";
+
+/// How long after fetching the JWKS a token signed with an unknown key fetches it again, so that
+/// rotated keys are picked up without letting every bad token hit the JWKS endpoint.
+const UNKNOWN_KEY_REFRESH: Duration = Duration::from_secs(10);
+
+/// Settings for verifying the JWTs of the Kratos session tokenizer, deserializable from the `jwt`
+/// section of the server configuration.
+///
+/// Fields missing from the configuration take their value from [`JwtConfig::default`], which
+/// does not accept JWTs at all.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct JwtConfig {
+    /// The JWKS the tokens are signed with, as a file path (optionally prefixed with `file://`)
+    /// read at startup, or an `http://` or `https://` URL fetched on first use and every
+    /// `refresh_ms` after. `None` disables JWT bearer tokens.
+    pub jwks: Option<String>,
+    /// How long keys fetched from a JWKS URL are used before fetching them again.
+    pub refresh_ms: u64,
+    /// The `iss` claim tokens must have, the public URL of Kratos. It must be set along with
+    /// `jwks`.
+    pub issuer: Option<String>,
+    /// A value the `aud` claim of tokens must contain. `None` accepts any audience.
+    pub audience: Option<String>,
+    /// How far the clock of Kratos may be ahead of or behind ours when checking `exp` and `nbf`.
+    pub leeway_ms: u64,
+}
+
+impl Default for JwtConfig {
+    fn default() -> Self {
+        Self {
+            jwks: None,
+            refresh_ms: 300_000,
+            issuer: None,
+            audience: None,
+            leeway_ms: 30_000,
+        }
+    }
+}
+
+impl JwtConfig {
+    /// Sets where the JWKS is loaded from.
+    pub fn jwks(mut self, jwks: impl Into<String>) -> Self {
+        self.jwks = Some(jwks.into());
+        self
+    }
+
+    /// Sets the required `iss` claim.
+    pub fn issuer(mut self, issuer: impl Into<String>) -> Self {
+        self.issuer = Some(issuer.into());
+        self
+    }
+
+    /// Sets the required `aud` claim.
+    pub fn audience(mut self, audience: impl Into<String>) -> Self {
+        self.audience = Some(audience.into());
+        self
+    }
+
+    pub(crate) fn refresh(&self) -> Duration {
+        Duration::from_millis(self.refresh_ms)
+    }
+
+    pub(crate) fn leeway_ms(&self) -> i64 {
+        self.leeway_ms as i64
+    }
+}
+
+/// Verifies `Authorization: Bearer` JWTs issued by the Kratos session tokenizer
+/// (`session.whoami.tokenizer.templates`) offline, against the Ed25519 keys of its JWKS.
+///
+/// Clones share the same keys.
+#[derive(Clone, Debug)]
+pub struct JwtVerifier {
+    config: JwtConfig,
+    url: Option<String>,
+    keys: Arc<RwLock<KeySet>>,
+}
+
+#[derive(Debug, Default)]
+struct KeySet {
+    keys: Arc<Vec<Key>>,
+    fetched_at: Option<Instant>,
+}
+
+#[derive(Debug)]
+struct Key {
+    kid: Option<String>,
+    public_key: Vec<u8>,
+}
+
+impl JwtVerifier {
+    /// A verifier for `config`, or `None` if it does not name a JWKS.
+    ///
+    /// # Failure
+    ///
+    /// Will return `Err` if no issuer is set, or if the JWKS is a file that cannot be read or
+    /// holds no Ed25519 key.
+    pub fn new(config: JwtConfig) -> Result<Option<Self>> {
+        let Some(jwks) = config.jwks.clone() else {
+            return Ok(None);
+        };
+        // Without it any JWT signed with the keys would do, whatever it was issued for.
+        if config.issuer.is_none() {
+            return Err(anyhow!("jwt.issuer must be set along with jwt.jwks"));
+        }
+        let mut verifier = Self {
+            config,
+            url: None,
+            keys: Arc::new(RwLock::new(KeySet::default())),
+        };
+        if jwks.starts_with("http://") || jwks.starts_with("https://") {
+            verifier.url = Some(jwks);
+        } else {
+            let path = Path::new(jwks.strip_prefix("file://").unwrap_or(&jwks));
+            let contents = std::fs::read(path)
+                .map_err(|err| anyhow!("failed to read jwks {}: {}", path.display(), err))?;
+            let keys = parse_jwks(&contents)
+                .map_err(|err| anyhow!("failed to parse jwks {}: {}", path.display(), err))?;
+            verifier.keys.write().expect(POISONED).keys = Arc::new(keys);
+        }
+        Ok(Some(verifier))
+    }
+
+    /// The identity in the `sub` claim of `token`, once its signature, `exp`, `nbf`, `iss` and
+    /// `aud` have been checked, and the roles in the `roles` of its `metadata_admin` claim.
+    ///
+    /// The roles are those of the identity's `metadata_admin` when the claims mapper of the
+    /// tokenizer template copies it into the token, see `build/kratos/claims.jsonnet`, so that a
+    /// first login does not have to ask the Kratos admin API for them.
+    ///
+    /// # Failure
+    ///
+    /// Will return a 401 "Unauthorized" if the token is not valid, and a 500 "Internal Server
+    /// Error" if the JWKS could not be fetched.
+    pub async fn verify(&self, token: &str) -> Result<(Uuid, Vec<String>), HttpError> {
+        let token = Token::decode(token)?;
+        if token.header.alg != "EdDSA" {
+            return Err(unauthorized(format!(
+                "unsupported token algorithm {}",
+                token.header.alg
+            )));
+        }
+
+        let keys = self.keys(token.header.kid.as_deref()).await?;
+        let verified = keys
+            .iter()
+            .filter(|key| token.header.kid.is_none() || key.kid == token.header.kid)
+            .any(|key| {
+                UnparsedPublicKey::new(&ED25519, &key.public_key)
+                    .verify(token.message.as_bytes(), &token.signature)
+                    .is_ok()
+            });
+        if !verified {
+            return Err(unauthorized("invalid token signature".to_string()));
+        }
+
+        let identity_id = self.check(&token.claims, chrono::Utc::now().timestamp_millis())?;
+        let roles = super::roles_from_metadata(token.claims.metadata_admin.as_ref());
+        Ok((identity_id, roles))
+    }
+
+    /// Checks the claims at `now`, in milliseconds since the epoch like the leeway, while the
+    /// claims are in seconds.
+    fn check(&self, claims: &Claims, now: i64) -> Result<Uuid, HttpError> {
+        let leeway = self.config.leeway_ms();
+        if claims.exp.saturating_mul(1000).saturating_add(leeway) <= now {
+            return Err(unauthorized("expired token".to_string()));
+        }
+        if claims
+            .nbf
+            .is_some_and(|nbf| nbf.saturating_mul(1000).saturating_sub(leeway) > now)
+        {
+            return Err(unauthorized("token not yet valid".to_string()));
+        }
+        if let Some(issuer) = &self.config.issuer {
+            if claims.iss.as_ref() != Some(issuer) {
+                return Err(unauthorized("invalid token issuer".to_string()));
+            }
+        }
+        if let Some(audience) = &self.config.audience {
+            let audiences = match &claims.aud {
+                Some(Audience::One(aud)) => std::slice::from_ref(aud),
+                Some(Audience::Many(aud)) => aud.as_slice(),
+                None => &[],
+            };
+            if !audiences.contains(audience) {
+                return Err(unauthorized("invalid token audience".to_string()));
+            }
+        }
+        Uuid::parse_str(&claims.sub).map_err(|_| unauthorized("invalid token subject".to_string()))
+    }
+
+    /// The keys to verify a token signed with `kid` against, fetching them from the JWKS URL if
+    /// they are stale or do not include `kid`.
+    async fn keys(&self, kid: Option<&str>) -> Result<Arc<Vec<Key>>, HttpError> {
+        let (keys, fetched_at) = {
+            let key_set = self.keys.read().expect(POISONED);
+            (key_set.keys.clone(), key_set.fetched_at)
+        };
+        let Some(url) = &self.url else {
+            return Ok(keys);
+        };
+
+        let stale = match fetched_at {
+            None => true,
+            Some(fetched_at) => {
+                let known =
+                    kid.is_none_or(|kid| keys.iter().any(|key| key.kid.as_deref() == Some(kid)));
+                fetched_at.elapsed() >= self.config.refresh()
+                    || (!known && fetched_at.elapsed() >= UNKNOWN_KEY_REFRESH)
+            }
+        };
+        if !stale {
+            return Ok(keys);
+        }
+
+        match fetch_jwks(url).await {
+            Ok(fetched) => {
+                let fetched = Arc::new(fetched);
+                let mut key_set = self.keys.write().expect(POISONED);
+                key_set.keys = fetched.clone();
+                key_set.fetched_at = Some(Instant::now());
+                Ok(fetched)
+            }
+            // Keep using the keys fetched before until the JWKS is back.
+            Err(_) if fetched_at.is_some() => Ok(keys),
+            Err(err) => Err(HttpError::for_internal_error(format!(
+                "failed to fetch jwks {}: {}",
+                url, err
+            ))),
+        }
+    }
+}
+
+async fn fetch_jwks(url: &str) -> Result<Vec<Key>> {
+    let contents = reqwest::get(url).await?.error_for_status()?.bytes().await?;
+    parse_jwks(&contents)
+}
+
+#[derive(Deserialize)]
+struct Jwks {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Deserialize)]
+struct Jwk {
+    kty: String,
+    crv: Option<String>,
+    kid: Option<String>,
+    x: Option<String>,
+    #[serde(rename = "use")]
+    usage: Option<String>,
+}
+
+/// The Ed25519 signing keys of a JWKS. Other keys, and the private parts of keys, are ignored.
+fn parse_jwks(contents: &[u8]) -> Result<Vec<Key>> {
+    let jwks: Jwks = serde_json::from_slice(contents)?;
+    let keys = jwks
+        .keys
+        .into_iter()
+        .filter(|jwk| jwk.kty == "OKP" && jwk.crv.as_deref() == Some("Ed25519"))
+        .filter(|jwk| jwk.usage.as_deref().unwrap_or("sig") == "sig")
+        .map(|jwk| {
+            let x = jwk.x.ok_or_else(|| anyhow!("Ed25519 key without x"))?;
+            Ok(Key {
+                kid: jwk.kid,
+                public_key: URL_SAFE_NO_PAD.decode(x)?,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if keys.is_empty() {
+        return Err(anyhow!("no Ed25519 signing key"));
+    }
+    Ok(keys)
+}
+
+#[derive(Deserialize)]
+struct Header {
+    alg: String,
+    kid: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    sub: String,
+    exp: i64,
+    nbf: Option<i64>,
+    iss: Option<String>,
+    aud: Option<Audience>,
+    metadata_admin: Option<serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Audience {
+    One(String),
+    Many(Vec<String>),
+}
+
+/// A JWT in compact serialization, decoded but not yet verified.
+struct Token<'a> {
+    header: Header,
+    claims: Claims,
+    /// The signed part of the token, its encoded header and claims.
+    message: &'a str,
+    signature: Vec<u8>,
+}
+
+impl<'a> Token<'a> {
+    fn decode(token: &'a str) -> Result<Self, HttpError> {
+        let invalid = || unauthorized("malformed token".to_string());
+        let (message, signature) = token.rsplit_once('.').ok_or_else(invalid)?;
+        let (header, claims) = message.split_once('.').ok_or_else(invalid)?;
+        Ok(Self {
+            header: decode_json(header).ok_or_else(invalid)?,
+            claims: decode_json(claims).ok_or_else(invalid)?,
+            message,
+            signature: URL_SAFE_NO_PAD.decode(signature).map_err(|_| invalid())?,
+        })
+    }
+}
+
+fn decode_json<T: DeserializeOwned>(part: &str) -> Option<T> {
+    serde_json::from_slice(&URL_SAFE_NO_PAD.decode(part).ok()?).ok()
+}
+
+/// Whether `token` has the shape of a JWT rather than an opaque Kratos session token.
+pub(crate) fn is_jwt(token: &str) -> bool {
+    token.split('.').count() == 3
+}
+
+fn unauthorized(message: String) -> HttpError {
+    HttpError::for_client_error(None, http::StatusCode::UNAUTHORIZED, message)
+}
+
+#[cfg(test)]
+pub(super) mod test {
+    use super::*;
+    use ring::signature::Ed25519KeyPair;
+    use serde_json::{json, Value};
+
+    pub(crate) const KID: &str = "10dd524d-decb-48ff-b84d-7d389c10f2f7";
+    pub(crate) const JWKS: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/../../build/kratos/jwk.eddsa.json"
+    );
+    pub(crate) const ISSUER: &str = "http://localhost:4433/";
+
+    /// The key pair in `build/kratos/jwk.eddsa.json`, which Kratos signs tokens with.
+    pub(crate) fn key_pair() -> Ed25519KeyPair {
+        let jwks: Value =
+            serde_json::from_slice(&std::fs::read(JWKS).expect("read jwks")).expect("jwks");
+        let jwk = &jwks["keys"][0];
+        let decode = |part: &str| URL_SAFE_NO_PAD.decode(jwk[part].as_str().unwrap()).unwrap();
+        Ed25519KeyPair::from_seed_and_public_key(&decode("d"), &decode("x")).expect("key pair")
+    }
+
+    pub(crate) fn sign(key_pair: &Ed25519KeyPair, header: Value, claims: Value) -> String {
+        let message = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(header.to_string()),
+            URL_SAFE_NO_PAD.encode(claims.to_string())
+        );
+        let signature = URL_SAFE_NO_PAD.encode(key_pair.sign(message.as_bytes()));
+        format!("{message}.{signature}")
+    }
+
+    fn verifier() -> JwtVerifier {
+        JwtVerifier::new(
+            JwtConfig::default()
+                .jwks(format!("file://{JWKS}"))
+                .issuer(ISSUER)
+                .audience("server"),
+        )
+        .expect("load jwks")
+        .expect("verifier")
+    }
+
+    pub(crate) fn claims(identity_id: Uuid, exp: i64) -> Value {
+        json!({
+            "sub": identity_id.to_string(),
+            "exp": exp,
+            "iat": exp - 3600,
+            "iss": ISSUER,
+            "aud": ["server"],
+        })
+    }
+
+    #[tokio::test]
+    async fn verifies_tokenizer_jwts() {
+        let verifier = verifier();
+        let key_pair = key_pair();
+        let header = json!({"alg": "EdDSA", "kid": KID, "typ": "JWT"});
+        let now = chrono::Utc::now().timestamp();
+        let identity_id = Uuid::new_v4();
+
+        let token = sign(&key_pair, header.clone(), claims(identity_id, now + 60));
+        assert!(is_jwt(&token));
+        assert_eq!(
+            verifier.verify(&token).await.unwrap(),
+            (identity_id, Vec::new())
+        );
+
+        // The roles are those in the metadata_admin claim of the claims mapper.
+        let mut with_roles = claims(identity_id, now + 60);
+        with_roles["metadata_admin"] = json!({"roles": ["admin"]});
+        let token = sign(&key_pair, header.clone(), with_roles);
+        assert_eq!(
+            verifier.verify(&token).await.unwrap(),
+            (identity_id, vec!["admin".to_string()])
+        );
+
+        // A single audience is a string.
+        let mut single = claims(identity_id, now + 60);
+        single["aud"] = json!("server");
+        let token = sign(&key_pair, header.clone(), single);
+        assert_eq!(verifier.verify(&token).await.unwrap().0, identity_id);
+
+        let rejected = |claims: Value| sign(&key_pair, header.clone(), claims);
+        let mut wrong_issuer = claims(identity_id, now + 60);
+        wrong_issuer["iss"] = json!("http://example.com/");
+        let mut wrong_audience = claims(identity_id, now + 60);
+        wrong_audience["aud"] = json!(["other"]);
+        let mut not_yet_valid = claims(identity_id, now + 3600);
+        not_yet_valid["nbf"] = json!(now + 600);
+        let mut bad_subject = claims(identity_id, now + 60);
+        bad_subject["sub"] = json!("not-a-uuid");
+        for (token, message) in [
+            (rejected(claims(identity_id, now - 60)), "expired token"),
+            (rejected(wrong_issuer), "invalid token issuer"),
+            (rejected(wrong_audience), "invalid token audience"),
+            (rejected(not_yet_valid), "token not yet valid"),
+            (rejected(bad_subject), "invalid token subject"),
+        ] {
+            let err = verifier.verify(&token).await.unwrap_err();
+            assert_eq!(err.status_code, http::StatusCode::UNAUTHORIZED);
+            assert_eq!(err.internal_message, message);
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_forged_jwts() {
+        let verifier = verifier();
+        let now = chrono::Utc::now().timestamp();
+        let claims = claims(Uuid::new_v4(), now + 60);
+        let header = json!({"alg": "EdDSA", "kid": KID});
+
+        let other = Ed25519KeyPair::from_seed_unchecked(&[7; 32]).expect("key pair");
+        let forged = sign(&other, header.clone(), claims.clone());
+        let unknown_kid = sign(
+            &key_pair(),
+            json!({"alg": "EdDSA", "kid": "other"}),
+            claims.clone(),
+        );
+        let none = format!(
+            "{}.{}.",
+            URL_SAFE_NO_PAD.encode(json!({"alg": "none"}).to_string()),
+            URL_SAFE_NO_PAD.encode(claims.to_string())
+        );
+        // The claims of one token with the signature of another.
+        let signed = sign(&key_pair(), header.clone(), claims);
+        let other_claims = sign(&key_pair(), header, self::claims(Uuid::new_v4(), now + 60));
+        let tampered = format!(
+            "{}.{}",
+            other_claims.rsplit_once('.').unwrap().0,
+            signed.rsplit_once('.').unwrap().1
+        );
+
+        for (token, message) in [
+            (forged.as_str(), "invalid token signature"),
+            (unknown_kid.as_str(), "invalid token signature"),
+            (none.as_str(), "unsupported token algorithm none"),
+            (tampered.as_str(), "invalid token signature"),
+            ("opaque-session-token", "malformed token"),
+        ] {
+            let err = verifier.verify(token).await.unwrap_err();
+            assert_eq!(err.status_code, http::StatusCode::UNAUTHORIZED);
+            assert_eq!(err.internal_message, message);
+        }
+    }
+
+    #[test]
+    fn issuer_is_required() {
+        let err = JwtVerifier::new(JwtConfig::default().jwks(JWKS)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "jwt.issuer must be set along with jwt.jwks"
+        );
+        assert!(JwtVerifier::new(JwtConfig::default()).unwrap().is_none());
+    }
+
+    #[test]
+    fn leeway_is_in_milliseconds() {
+        let verifier = JwtVerifier::new(JwtConfig {
+            leeway_ms: 500,
+            ..JwtConfig::default().jwks(JWKS).issuer(ISSUER)
+        })
+        .unwrap()
+        .unwrap();
+        let claims = Claims {
+            sub: Uuid::new_v4().to_string(),
+            exp: 100,
+            nbf: Some(90),
+            iss: Some(ISSUER.to_string()),
+            aud: None,
+            metadata_admin: None,
+        };
+        assert!(verifier.check(&claims, 100_499).is_ok());
+        assert!(verifier.check(&claims, 100_500).is_err());
+        assert!(verifier.check(&claims, 89_500).is_ok());
+        assert!(verifier.check(&claims, 89_499).is_err());
+    }
+
+    #[test]
+    fn parse_jwks_keeps_only_ed25519_signing_keys() {
+        let jwks = json!({"keys": [
+            {"kty": "RSA", "kid": "rsa", "n": "AQAB", "e": "AQAB"},
+            {"kty": "OKP", "crv": "Ed25519", "kid": "enc", "use": "enc", "x": "AAAA"},
+            {"kty": "OKP", "crv": "Ed25519", "kid": "sig", "x": "58zYrqRbmsXkyg2t50S9_RoZQb2cVXnSfxUU4aPhDAk"},
+        ]});
+        let keys = parse_jwks(jwks.to_string().as_bytes()).unwrap();
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys[0].kid.as_deref(), Some("sig"));
+        assert_eq!(keys[0].public_key.len(), 32);
+
+        assert!(parse_jwks(br#"{"keys": []}"#).is_err());
+    }
+}
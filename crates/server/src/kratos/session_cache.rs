@@ -0,0 +1,361 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+use ring::digest;
+use serde::Deserialize;
+use uuid::Uuid;
+
+use super::SessionCredential;
+
+const POISONED: &str = "session cache lock poisoned";
+
+/// Settings for the [`SessionCache`], deserializable from the `session_cache` section of the
+/// server configuration.
+///
+/// Fields missing from the configuration take their value from [`SessionCacheConfig::default`].
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct SessionCacheConfig {
+    /// The longest an active session is trusted without asking Kratos again, even if it expires
+    /// later. A session revoked in Kratos keeps working for at most this long. `0` disables the
+    /// cache.
+    pub max_ttl_ms: u64,
+    /// How long a rejected token is remembered before Kratos is asked again. `0` disables
+    /// negative caching.
+    pub negative_ttl_ms: u64,
+    /// The maximum number of tokens cached. When full, rejected tokens are no longer cached, and
+    /// an active session drops the expired entries first and then the entry closest to expiring.
+    pub capacity: usize,
+}
+
+impl Default for SessionCacheConfig {
+    fn default() -> Self {
+        Self {
+            max_ttl_ms: 30_000,
+            negative_ttl_ms: 5_000,
+            capacity: 10_000,
+        }
+    }
+}
+
+impl SessionCacheConfig {
+    /// Sets how long an active session is trusted at most.
+    pub fn max_ttl_ms(mut self, max_ttl_ms: u64) -> Self {
+        self.max_ttl_ms = max_ttl_ms;
+        self
+    }
+
+    /// Sets how long a rejected token is remembered.
+    pub fn negative_ttl_ms(mut self, negative_ttl_ms: u64) -> Self {
+        self.negative_ttl_ms = negative_ttl_ms;
+        self
+    }
+
+    /// Sets the maximum number of tokens cached.
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    pub(crate) fn max_ttl(&self) -> Duration {
+        Duration::from_millis(self.max_ttl_ms)
+    }
+
+    pub(crate) fn negative_ttl(&self) -> Duration {
+        Duration::from_millis(self.negative_ttl_ms)
+    }
+}
+
+/// What Kratos said about a session token the last time it was asked.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CachedSession {
+    /// The session was active and belongs to the identity.
+    Active(Uuid),
+    /// The token was rejected with a 401 "Unauthorized".
+    Rejected,
+}
+
+/// An in-process cache of verified session tokens and cookies, so that `User::authenticate` does not
+/// ask Kratos on every request.
+///
+/// Credentials are only kept as their SHA-256 digest. Clones share the same entries.
+#[derive(Clone, Debug)]
+pub struct SessionCache {
+    config: SessionCacheConfig,
+    entries: Arc<Mutex<HashMap<TokenHash, Entry>>>,
+}
+
+type TokenHash = [u8; 32];
+
+#[derive(Debug)]
+struct Entry {
+    session: CachedSession,
+    expires: Instant,
+}
+
+impl SessionCache {
+    pub fn new(config: SessionCacheConfig) -> Self {
+        Self {
+            config,
+            entries: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// The cached outcome for `credential`, or `None` if it has not been verified recently.
+    pub fn get(&self, credential: SessionCredential) -> Option<CachedSession> {
+        self.get_at(credential, Instant::now())
+    }
+
+    /// Caches the identity of an active session until the session expires at `expires_at`, but
+    /// for no longer than the max TTL.
+    pub fn insert_active(
+        &self,
+        credential: SessionCredential,
+        identity_id: Uuid,
+        expires_at: Option<DateTime<Utc>>,
+    ) {
+        let ttl = self.active_ttl(expires_at, Utc::now());
+        self.insert_at(
+            credential,
+            CachedSession::Active(identity_id),
+            ttl,
+            Instant::now(),
+        );
+    }
+
+    /// Caches that `credential` was rejected, for the negative TTL.
+    pub fn insert_rejected(&self, credential: SessionCredential) {
+        let ttl = self.config.negative_ttl();
+        self.insert_at(credential, CachedSession::Rejected, ttl, Instant::now());
+    }
+
+    /// Forgets `credential`, so that the next request using it is verified with Kratos.
+    pub fn invalidate(&self, credential: SessionCredential) {
+        self.entries
+            .lock()
+            .expect(POISONED)
+            .remove(&hash(credential));
+    }
+
+    /// Forgets every session of the identity, e.g. after its User has been deleted or its roles
+    /// have changed.
+    pub fn invalidate_identity(&self, identity_id: Uuid) {
+        self.entries
+            .lock()
+            .expect(POISONED)
+            .retain(|_, entry| entry.session != CachedSession::Active(identity_id));
+    }
+
+    fn active_ttl(&self, expires_at: Option<DateTime<Utc>>, now: DateTime<Utc>) -> Duration {
+        let max_ttl = self.config.max_ttl();
+        match expires_at {
+            // A session that has already expired is not cached.
+            Some(expires_at) => (expires_at - now).to_std().unwrap_or_default().min(max_ttl),
+            None => max_ttl,
+        }
+    }
+
+    fn get_at(&self, credential: SessionCredential, now: Instant) -> Option<CachedSession> {
+        let key = hash(credential);
+        let mut entries = self.entries.lock().expect(POISONED);
+        match entries.get(&key) {
+            Some(entry) if entry.expires > now => Some(entry.session),
+            Some(_) => {
+                entries.remove(&key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn insert_at(
+        &self,
+        credential: SessionCredential,
+        session: CachedSession,
+        ttl: Duration,
+        now: Instant,
+    ) {
+        if ttl.is_zero() || self.config.capacity == 0 {
+            return;
+        }
+
+        let key = hash(credential);
+        let mut entries = self.entries.lock().expect(POISONED);
+        if !entries.contains_key(&key) && entries.len() >= self.config.capacity {
+            // Anyone can send rejected tokens, so they do not get to make room by scanning the
+            // entries under the lock. Only sessions Kratos accepted do.
+            if session == CachedSession::Rejected {
+                return;
+            }
+            entries.retain(|_, entry| entry.expires > now);
+            if entries.len() >= self.config.capacity {
+                let closest = entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.expires)
+                    .map(|(key, _)| *key);
+                if let Some(closest) = closest {
+                    entries.remove(&closest);
+                }
+            }
+        }
+        entries.insert(
+            key,
+            Entry {
+                session,
+                expires: now + ttl,
+            },
+        );
+    }
+}
+
+fn hash(credential: SessionCredential) -> TokenHash {
+    // Tokens and cookies are told apart, so that one cannot stand in for the other.
+    let (kind, value) = match credential {
+        SessionCredential::Token(token) => (b't', token),
+        SessionCredential::Cookie(cookie) => (b'c', cookie),
+    };
+    let mut context = digest::Context::new(&digest::SHA256);
+    context.update(&[kind]);
+    context.update(value.as_bytes());
+    let mut key = TokenHash::default();
+    key.copy_from_slice(context.finish().as_ref());
+    key
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use SessionCredential::{Cookie, Token};
+
+    fn cache(capacity: usize) -> SessionCache {
+        SessionCache::new(
+            SessionCacheConfig::default()
+                .max_ttl_ms(60_000)
+                .negative_ttl_ms(5_000)
+                .capacity(capacity),
+        )
+    }
+
+    #[test]
+    fn entries_expire() {
+        let cache = cache(10);
+        let now = Instant::now();
+        let identity_id = Uuid::new_v4();
+
+        cache.insert_at(
+            Token("active"),
+            CachedSession::Active(identity_id),
+            Duration::from_secs(10),
+            now,
+        );
+        cache.insert_at(
+            Token("rejected"),
+            CachedSession::Rejected,
+            Duration::from_secs(5),
+            now,
+        );
+        assert_eq!(
+            cache.get_at(Token("active"), now),
+            Some(CachedSession::Active(identity_id))
+        );
+        assert_eq!(
+            cache.get_at(Token("rejected"), now),
+            Some(CachedSession::Rejected)
+        );
+        assert_eq!(cache.get_at(Token("unknown"), now), None);
+        assert_eq!(cache.get_at(Cookie("active"), now), None);
+
+        let later = now + Duration::from_secs(5);
+        assert_eq!(
+            cache.get_at(Token("active"), later),
+            Some(CachedSession::Active(identity_id))
+        );
+        assert_eq!(cache.get_at(Token("rejected"), later), None);
+        assert_eq!(
+            cache.get_at(Token("active"), now + Duration::from_secs(10)),
+            None
+        );
+    }
+
+    #[test]
+    fn active_ttl_honours_expires_at() {
+        let cache = cache(10);
+        let now = Utc::now();
+
+        assert_eq!(cache.active_ttl(None, now), Duration::from_secs(60));
+        assert_eq!(
+            cache.active_ttl(Some(now + chrono::Duration::seconds(10)), now),
+            Duration::from_secs(10)
+        );
+        assert_eq!(
+            cache.active_ttl(Some(now + chrono::Duration::hours(1)), now),
+            Duration::from_secs(60)
+        );
+        assert_eq!(
+            cache.active_ttl(Some(now - chrono::Duration::seconds(1)), now),
+            Duration::ZERO
+        );
+
+        // An expired session is not cached at all.
+        cache.insert_active(Token("expired"), Uuid::new_v4(), Some(now));
+        assert_eq!(cache.get(Token("expired")), None);
+    }
+
+    #[test]
+    fn capacity_evicts_closest_to_expiring() {
+        let cache = cache(2);
+        let now = Instant::now();
+        let active = CachedSession::Active(Uuid::new_v4());
+
+        cache.insert_at(Token("a"), active, Duration::from_secs(30), now);
+        cache.insert_at(
+            Token("b"),
+            CachedSession::Rejected,
+            Duration::from_secs(10),
+            now,
+        );
+        cache.insert_at(Token("c"), active, Duration::from_secs(20), now);
+        assert!(cache.get_at(Token("a"), now).is_some());
+        assert!(cache.get_at(Token("b"), now).is_none());
+        assert!(cache.get_at(Token("c"), now).is_some());
+
+        // A full cache does not take rejected tokens.
+        cache.insert_at(
+            Token("rejected"),
+            CachedSession::Rejected,
+            Duration::from_secs(60),
+            now,
+        );
+        assert!(cache.get_at(Token("rejected"), now).is_none());
+        assert!(cache.get_at(Token("c"), now).is_some());
+
+        // Expired entries go first.
+        let later = now + Duration::from_secs(25);
+        cache.insert_at(Token("d"), active, Duration::from_secs(1), later);
+        assert!(cache.get_at(Token("a"), later).is_some());
+        assert!(cache.get_at(Token("d"), later).is_some());
+    }
+
+    #[test]
+    fn invalidate() {
+        let cache = cache(10);
+        let identity_id = Uuid::new_v4();
+
+        cache.insert_active(Token("first"), identity_id, None);
+        cache.insert_active(Cookie("second"), identity_id, None);
+        cache.insert_active(Token("other"), Uuid::new_v4(), None);
+
+        cache.invalidate(Token("first"));
+        assert_eq!(cache.get(Token("first")), None);
+        assert!(cache.get(Cookie("second")).is_some());
+
+        cache.invalidate_identity(identity_id);
+        assert_eq!(cache.get(Cookie("second")), None);
+        assert!(cache.get(Token("other")).is_some());
+    }
+}
@@ -1,8 +1,11 @@
 pub mod api;
+pub mod auth;
+pub mod config;
 pub mod context;
 pub mod database;
 pub mod entity;
 pub mod error;
+pub mod etag;
 pub mod imp;
 pub mod kratos;
 
@@ -11,43 +14,169 @@ pub mod kratos;
 pub mod test;
 
 use anyhow::{anyhow, Result};
+use config::Config;
 use context::Context;
-use database::Database;
+use database::{Database, DatabaseConfig};
 use dropshot::{ConfigDropshot, ConfigLogging, ConfigLoggingLevel, HttpServer, HttpServerStarter};
-use kratos::Kratos;
-use std::{net::SocketAddr, path::Path};
+use kratos::{JwtVerifier, Kratos};
+use std::{net::SocketAddr, path::Path, time::Duration};
+use tokio::signal::unix::{signal, SignalKind};
+
+/// How long queued database work is given to drain once the server has stopped.
+const SHUTDOWN_DEADLINE: Duration = Duration::from_secs(30);
+
+/// The database used when no path is given.
+const DEFAULT_DATABASE_PATH: &str = "./db.sqlite";
 
 #[tokio::main]
 async fn main() -> Result<()> {
     // Collect command line arguments passed to the program.
     let args: Vec<String> = std::env::args().collect();
 
-    // Generate the OpenAPI specification and write it to a file.
-    std::fs::write(
-        Path::new(&std::env::var("CARGO_MANIFEST_DIR").unwrap()).join("api/v1.json"),
-        api::generate_openapi_spec(),
-    )
-    .unwrap_or_else(|err| panic!("failed to write openapi spec: {}", err));
+    // `server openapi` prints the OpenAPI specification, which is checked in as `api/v1.json`.
+    // It does not depend on the configuration.
+    if args.get(1).map(String::as_str) == Some("openapi") {
+        print!("{}", api::generate_openapi_spec());
+        return Ok(());
+    }
+
+    // Load the server configuration from the file named by `SERVER_CONFIG`, if any.
+    let config = Config::load()?;
+
+    // `server migrate ...` inspects or changes the migrations instead of starting the server.
+    if args.get(1).map(String::as_str) == Some("migrate") {
+        return migrate(&args[2..], config.database).await;
+    }
 
-    // Create a database with 1 writer and num_cpus readers. The path is provided
+    // Create a database with 1 writer and (by default) num_cpus readers. The path is provided
     // as an argument or defaults to "./db.sqlite". This is done asynchronously.
-    let database = Database::open(
-        args.get(2).unwrap_or(&"./db.sqlite".to_string()),
-        num_cpus::get(),
-    )
-    .await?;
+    let path = args
+        .get(2)
+        .cloned()
+        .unwrap_or(DEFAULT_DATABASE_PATH.to_string());
+
+    // Seed a new database from the configured backup, if any, before it is opened.
+    if let (false, Some(backup)) = (Path::new(&path).exists(), &config.restore_from) {
+        Database::restore(backup, &path, |_| {})
+            .await
+            .map_err(|err| anyhow!("failed to restore {}: {}", backup.display(), err))?;
+    }
+    let database = Database::open(&path, config.database).await?;
+
+    // Refuse to start if the entities do not line up with the migrated tables.
+    for warning in database.validate_schema(entity::SCHEMAS).await? {
+        eprintln!("warning: {warning}");
+    }
 
-    let kratos = Kratos::new(4433, 4434);
+    let mut kratos = Kratos::new(4433, 4434)
+        .session_cache(config.session_cache)
+        .session_cookie(config.session_cookie);
+    if let Some(verifier) = JwtVerifier::new(config.jwt)? {
+        kratos = kratos.jwt(verifier);
+    }
 
     // Create a context using the provided database.
-    let context = Context::new(database, kratos);
+    let context = Context::new(database.clone(), kratos);
 
     // Start the server listening on 127.0.0.1:8080 and inject the created database
     // into the context.
     let server = create_server("127.0.0.1:8080".parse().unwrap(), context)?;
 
-    // Wait for the database to stop and return any error that occurred during this process.
-    server.await.map_err(|err| anyhow!(err))
+    // Run until the server stops by itself or a shutdown signal is received, in which case
+    // the server stops accepting requests and waits for in-flight requests to complete.
+    tokio::select! {
+        result = server.wait_for_shutdown() => result.map_err(|err| anyhow!(err))?,
+        result = shutdown_signal() => {
+            result?;
+            server.close().await.map_err(|err| anyhow!(err))?;
+        }
+    }
+
+    // Drain any queued database work, checkpoint the WAL and close the connections.
+    database
+        .close(SHUTDOWN_DEADLINE)
+        .await
+        .map_err(|err| anyhow!("failed to close database: {}", err))
+}
+
+// Runs `server migrate status [PATH]`, `server migrate to VERSION [PATH]`,
+// `server migrate dry-run VERSION [PATH]` or `server migrate generate NAME`. The database is
+// opened without applying pending migrations so that they can be reported, rehearsed or rolled
+// back.
+async fn migrate(args: &[String], config: DatabaseConfig) -> Result<()> {
+    if let [command, name] = args {
+        if command == "generate" {
+            return generate_migration(name);
+        }
+    }
+
+    let (command, version, path) = match args {
+        [command, rest @ ..] if command == "status" => (command.as_str(), None, rest.first()),
+        [command, version, rest @ ..] if command == "to" || command == "dry-run" => (
+            command.as_str(),
+            Some(version.parse::<usize>()?),
+            rest.first(),
+        ),
+        _ => {
+            return Err(anyhow!(
+                "usage: server migrate (status | to VERSION | dry-run VERSION) [PATH] \
+                 | generate NAME"
+            ))
+        }
+    };
+
+    let path = path.map_or(DEFAULT_DATABASE_PATH, String::as_str);
+    let database = Database::open(path, config.migrate(false)).await?;
+
+    match (command, version) {
+        ("to", Some(version)) => {
+            database.migrate_to(version).await?;
+            print!("{}", database.migration_status().await?);
+        }
+        ("dry-run", Some(version)) => {
+            let dry_run = database.migrate_dry_run(version).await?;
+            println!("version {} => {}", dry_run.from, dry_run.to);
+            for change in dry_run.changes {
+                println!("{change}");
+            }
+        }
+        _ => print!("{}", database.migration_status().await?),
+    }
+
+    database
+        .close(SHUTDOWN_DEADLINE)
+        .await
+        .map_err(|err| anyhow!("failed to close database: {}", err))
+}
+
+// Writes the migration that brings the tables in line with the entities to the next numbered
+// directory in `migrations/`, which is compiled in on the next build.
+fn generate_migration(name: &str) -> Result<()> {
+    match database::generate_migration(entity::SCHEMAS)? {
+        Some(migration) => {
+            let dir = migration.write(
+                &Path::new(env!("CARGO_MANIFEST_DIR")).join("migrations"),
+                name,
+            )?;
+            println!("wrote {}", dir.display());
+            for statement in migration.up {
+                println!("{statement}");
+            }
+        }
+        None => println!("the entities match the migrations, nothing to generate"),
+    }
+    Ok(())
+}
+
+// Resolves when the process receives SIGINT or SIGTERM (as sent by Litestream or the container
+// runtime when stopping the container).
+async fn shutdown_signal() -> Result<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result?,
+        _ = terminate.recv() => {}
+    }
+    Ok(())
 }
 
 // Function to create an HttpServer instance with a given bind address and database.
@@ -1,7 +1,11 @@
-use crate::{context::Context, create_server, database::Database, kratos::Kratos};
+use crate::{
+    context::Context,
+    create_server,
+    database::{Database, DatabaseConfig, WriteTxn},
+    kratos::Kratos,
+};
 use anyhow::{anyhow, Result};
 use dropshot::HttpServer;
-use rusqlite::Transaction;
 use std::{
     net::{SocketAddr, TcpListener},
     path::Path,
@@ -24,9 +28,9 @@ pub mod client {
 /// Fixture trait allows tests to configure database state before a test begins.
 ///
 /// Each fixture must implement this trait and provide:
-/// - `try_fixtures`: A function that takes an active database transaction and applies any necessary setup changes. If there's an error, it should return a ResultErr with the specific issue.
+/// - `try_fixtures`: A function that takes an active write transaction and applies any necessary setup changes. If there's an error, it should return a ResultErr with the specific issue.
 pub trait Fixture: Send + Sync + 'static {
-    fn try_fixtures(&self, transaction: &Transaction<'_>) -> Result<()>;
+    fn try_fixtures(&self, txn: &WriteTxn<'_>) -> Result<()>;
 }
 
 /// Finds the first available pair of TCP ports in the range 8000 to 8999.
@@ -78,17 +82,17 @@ impl TestContext {
     /// This function creates an in-memory database, applies fixtures, starts a server, and spawns it as a task.
     pub async fn new(fixtures: Vec<Box<dyn Fixture>>) -> Result<Self> {
         // Open an in-memory database instance for this test
-        let database = Database::open_in_memory(1).await?;
+        let database = Database::open_in_memory(DatabaseConfig::default().readers(1)).await?;
+        database.validate_schema(crate::entity::SCHEMAS).await?;
 
-        // Write transaction to apply fixtures and commit changes
+        // Write transaction to apply fixtures, committed once all fixtures succeed
         database
-            .write(move |connection| {
-                let tx = connection.transaction()?;
+            .write_txn(move |txn| {
                 for fixture in fixtures {
                     // Run each fixture's setup on the transaction
-                    fixture.try_fixtures(&tx)?;
+                    fixture.try_fixtures(txn)?;
                 }
-                Ok(tx.commit()?)
+                Ok(())
             })
             .await?;
 
@@ -141,7 +145,7 @@ impl TestContext {
         .await
         .map_err(|_| anyhow!("duration waiting for kratos healthcheck elapsed"))?;
 
-        let kratos = Kratos::new(public_port, admin_port);
+        let kratos = Kratos::new(public_port, admin_port).test_jwt();
 
         let context = Context::new(database.clone(), kratos.clone());
 